@@ -21,6 +21,10 @@ pub struct User {
     #[sqlx(default)]
     #[serde(skip)]
     pub password_hash: Option<String>,
+    /// the user signs in with a TOTP code in addition to the password
+    #[sqlx(default)]
+    #[serde(default)]
+    pub mfa_enabled: bool,
     pub created_at: DateTime<Utc>,
 }
 
@@ -29,9 +33,20 @@ pub struct Workspace {
     pub id: i64,
     pub name: String,
     pub owner_id: i64,
+    pub allowed_domain: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "workspace_role", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum WorkspaceRole {
+    Owner,
+    Admin,
+    Member,
+    Guest,
+}
+
 #[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
 pub struct ChatUser {
     pub id: i64,
@@ -53,6 +68,15 @@ pub enum ChatType {
     PublicChannel,
 }
 
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "chat_role", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum ChatRole {
+    Owner,
+    Admin,
+    Member,
+}
+
 #[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all(serialize = "camelCase"))]
 pub struct Chat {
@@ -64,6 +88,22 @@ pub struct Chat {
     pub members: Vec<i64>,
     #[serde(alias = "createdAt")]
     pub created_at: DateTime<Utc>,
+    #[serde(alias = "archivedAt", default)]
+    pub archived_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ChatMember {
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "userId")]
+    pub user_id: i64,
+    pub role: ChatRole,
+    #[serde(alias = "joinedAt")]
+    pub joined_at: DateTime<Utc>,
+    #[serde(alias = "lastReadMessageId")]
+    pub last_read_message_id: Option<i64>,
 }
 
 #[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
@@ -78,6 +118,75 @@ pub struct Message {
     pub files: Vec<String>,
     #[serde(alias = "createdAt")]
     pub created_at: DateTime<Utc>,
+    #[serde(alias = "updatedAt", default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(alias = "deletedAt", default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    // tombstone marker, content and files are stripped when deleted
+    #[sqlx(default)]
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(alias = "replyTo", default)]
+    pub reply_to: Option<i64>,
+    #[serde(alias = "threadRootId", default)]
+    pub thread_root_id: Option<i64>,
+    // thread stats, only set on root messages
+    #[sqlx(default)]
+    #[serde(alias = "replyCount", default)]
+    pub reply_count: i64,
+    #[sqlx(default)]
+    #[serde(alias = "lastReplyAt", default)]
+    pub last_reply_at: Option<DateTime<Utc>>,
+    // aggregated per emoji, only filled when listing messages
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    // metadata of the files, only filled when listing messages
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub attachments: Vec<FileInfo>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: i64,
+    // whether the requesting user is among the reactors
+    pub reacted: bool,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct FileInfo {
+    // download url, the same as in Message.files
+    #[sqlx(default)]
+    pub url: String,
+    #[serde(alias = "wsId")]
+    pub ws_id: i64,
+    pub hash: String,
+    pub ext: String,
+    // name of the file when it was first uploaded
+    #[serde(alias = "originalName")]
+    pub original_name: String,
+    pub size: i64,
+    #[serde(alias = "contentType")]
+    pub content_type: String,
+    #[serde(alias = "uploaderId")]
+    pub uploader_id: i64,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct MessageEdit {
+    pub id: i64,
+    #[serde(alias = "messageId")]
+    pub message_id: i64,
+    pub content: String,
+    pub files: Vec<String>,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
 }
 
 impl User {
@@ -89,7 +198,8 @@ impl User {
             fullname: fullname.to_string(),
             email: email.to_string(),
             password_hash: None,
+            mfa_enabled: false,
             created_at: chrono::Utc::now(),
         }
     }
-}
\ No newline at end of file
+}
@@ -44,9 +44,10 @@ where
         };
 
     let req = match state.verify(&token) {
-        Ok(user) => {
+        Ok(claims) => {
             let mut req = Request::from_parts(parts, body);
-            req.extensions_mut().insert(user);
+            req.extensions_mut().insert(claims.user.clone());
+            req.extensions_mut().insert(claims);
             req
         }
         Err(e) => {
@@ -62,7 +63,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{DecodingKey, EncodingKeyPair, User};
+    use crate::{DecodingKey, EncodingKeyPair, JwtAlgorithm, TokenClaims, User};
     use anyhow::Result;
     use axum::{body::Body, middleware::from_fn_with_state, routing::get, Router};
     use std::sync::Arc;
@@ -79,8 +80,8 @@ mod tests {
     impl TokenVerify for AppState {
         type Error = ();
 
-        fn verify(&self, token: &str) -> Result<User, Self::Error> {
-            self.0.dk.verify(token).map_err(|_| ())
+        fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error> {
+            self.0.dk.verify_claims(token).map_err(|_| ())
         }
     }
 
@@ -92,8 +93,8 @@ mod tests {
     async fn verify_token_middleware_should_work() -> Result<()> {
         let encoding_pem = include_str!("../../fixtures/private.pem");
         let decoding_pem = include_str!("../../fixtures/public.pem");
-        let ek = EncodingKeyPair::load(encoding_pem)?;
-        let dk = DecodingKey::load(decoding_pem)?;
+        let ek = EncodingKeyPair::load(JwtAlgorithm::Ed25519, encoding_pem, None)?;
+        let dk = DecodingKey::load(JwtAlgorithm::Ed25519, decoding_pem, None)?;
         let state = AppState(Arc::new(AppStateInner { ek, dk }));
 
         let user = User::new(1, "Tyr Chen", "tchen@acme.org");
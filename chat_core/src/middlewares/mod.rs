@@ -1,10 +1,11 @@
 mod auth;
+mod rate_limit;
 mod request_id;
 mod server_time;
 
 use std::fmt;
 
-use crate::User;
+use crate::TokenClaims;
 
 use self::{request_id::set_request_id, server_time::ServerTimeLayer};
 use axum::{middleware::from_fn, Router};
@@ -17,10 +18,14 @@ use tower_http::{
 use tracing::Level;
 
 pub use auth::verify_token;
+pub use rate_limit::{
+    rate_limit, MemoryRateLimitStore, PgRateLimitStore, RateLimit, RateLimitKey, RateLimitStore,
+    RateLimited, RateLimiter,
+};
 
 pub trait TokenVerify {
     type Error: fmt::Debug;
-    fn verify(&self, token: &str) -> Result<User, Self::Error>;
+    fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error>;
 }
 
 const REQUEST_ID_HEADER: &str = "x-request-id";
@@ -0,0 +1,345 @@
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::User;
+
+// prune full buckets once the in-memory store tracks this many keys
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+/// Token bucket allowing `burst` requests at once, refilled over `period` seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub period: u64,
+}
+
+/// Where the buckets live, in memory per process or in Postgres shared by all instances.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Take a token from the bucket of `key`, returns how long to wait if it's empty.
+    async fn acquire(&self, key: &str, limit: &RateLimit) -> anyhow::Result<Option<Duration>>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryRateLimitStore {
+    // key -> (tokens, last update, limit of the bucket)
+    buckets: Mutex<HashMap<String, (f64, Instant, RateLimit)>>,
+}
+
+/// Buckets in the `rate_limit_buckets` table.
+#[derive(Debug, Clone)]
+pub struct PgRateLimitStore {
+    pool: PgPool,
+}
+
+/// What the requests are counted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// client ip address
+    Ip,
+    /// authenticated user, falls back to the client ip
+    User,
+}
+
+/// A named limit backed by a store, usable as middleware on any route
+/// or called directly with a custom key, e.g. an email.
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Arc<RateLimiterInner>,
+}
+
+struct RateLimiterInner {
+    name: String,
+    limit: RateLimit,
+    key: RateLimitKey,
+    trust_forwarded_for: bool,
+    store: Arc<dyn RateLimitStore>,
+}
+
+/// The bucket is empty, retry after the given time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, period: u64) -> Self {
+        Self { burst, period }
+    }
+
+    // tokens refilled per second
+    fn rate(&self) -> f64 {
+        self.burst as f64 / self.period.max(1) as f64
+    }
+
+    fn refill(&self, tokens: f64, elapsed: Duration) -> f64 {
+        (tokens + elapsed.as_secs_f64() * self.rate()).min(self.burst as f64)
+    }
+
+    /// Refill the bucket for the elapsed time and take a token, returns the tokens left,
+    /// or how long until a token is available.
+    pub fn take(&self, tokens: f64, elapsed: Duration) -> (f64, Option<Duration>) {
+        let tokens = self.refill(tokens, elapsed);
+        if tokens >= 1.0 {
+            (tokens - 1.0, None)
+        } else {
+            let wait = (1.0 - tokens) / self.rate();
+            (tokens, Some(Duration::from_secs_f64(wait)))
+        }
+    }
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn acquire(&self, key: &str, limit: &RateLimit) -> anyhow::Result<Option<Duration>> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limit buckets poisoned");
+        if buckets.len() >= MAX_MEMORY_BUCKETS {
+            // a full bucket is the same as no bucket
+            buckets.retain(|_, (tokens, updated_at, limit)| {
+                limit.refill(*tokens, now - *updated_at) < limit.burst as f64
+            });
+        }
+        let (tokens, updated_at, _) =
+            buckets
+                .entry(key.to_string())
+                .or_insert((limit.burst as f64, now, *limit));
+        let (left, retry_after) = limit.take(*tokens, now - *updated_at);
+        *tokens = left;
+        *updated_at = now;
+        Ok(retry_after)
+    }
+}
+
+impl PgRateLimitStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for PgRateLimitStore {
+    async fn acquire(&self, key: &str, limit: &RateLimit) -> anyhow::Result<Option<Duration>> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO rate_limit_buckets (key, tokens)
+            VALUES ($1, $2)
+            ON CONFLICT (key) DO NOTHING
+            "#,
+        )
+        .bind(key)
+        .bind(limit.burst as f64)
+        .execute(&mut *tx)
+        .await?;
+        let (tokens, elapsed): (f64, f64) = sqlx::query_as(
+            r#"
+            SELECT tokens, EXTRACT(EPOCH FROM clock_timestamp() - updated_at)::float8
+            FROM rate_limit_buckets
+            WHERE key = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let (left, retry_after) = limit.take(tokens, Duration::from_secs_f64(elapsed.max(0.0)));
+        sqlx::query(
+            "UPDATE rate_limit_buckets SET tokens = $2, updated_at = clock_timestamp() WHERE key = $1",
+        )
+        .bind(key)
+        .bind(left)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(retry_after)
+    }
+}
+
+impl RateLimiter {
+    /// Limit requests per client ip, `name` separates the buckets of different limiters.
+    pub fn new(name: impl Into<String>, limit: RateLimit, store: Arc<dyn RateLimitStore>) -> Self {
+        Self {
+            inner: Arc::new(RateLimiterInner {
+                name: name.into(),
+                limit,
+                key: RateLimitKey::Ip,
+                trust_forwarded_for: false,
+                store,
+            }),
+        }
+    }
+
+    pub fn with_key(self, key: RateLimitKey) -> Self {
+        self.update(|inner| inner.key = key)
+    }
+
+    /// Take the client ip from `X-Forwarded-For`, only when behind a trusted proxy.
+    pub fn trust_forwarded_for(self, trust: bool) -> Self {
+        self.update(|inner| inner.trust_forwarded_for = trust)
+    }
+
+    /// Count a request for `key`, shared by all keys of the same limiter name.
+    ///
+    /// The store failing lets the request through, an outage shouldn't lock everyone out.
+    pub async fn check(&self, key: &str) -> Result<(), RateLimited> {
+        let key = format!("{}:{}", self.inner.name, key);
+        match self.inner.store.acquire(&key, &self.inner.limit).await {
+            Ok(None) => Ok(()),
+            Ok(Some(retry_after)) => Err(RateLimited { retry_after }),
+            Err(e) => {
+                warn!("rate limit store failed: {:?}", e);
+                Ok(())
+            }
+        }
+    }
+
+    fn request_key(&self, req: &Request) -> String {
+        if self.inner.key == RateLimitKey::User {
+            if let Some(user) = req.extensions().get::<User>() {
+                return format!("user:{}", user.id);
+            }
+        }
+        let forwarded = self
+            .inner
+            .trust_forwarded_for
+            .then(|| req.headers().get("x-forwarded-for"))
+            .flatten()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(|ip| ip.trim().to_string());
+        let ip = forwarded.or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+        });
+        format!("ip:{}", ip.unwrap_or_else(|| "unknown".to_string()))
+    }
+
+    // only called while building, before the limiter is cloned
+    fn update(mut self, f: impl FnOnce(&mut RateLimiterInner)) -> Self {
+        let inner = Arc::get_mut(&mut self.inner).expect("rate limiter already shared");
+        f(inner);
+        self
+    }
+}
+
+impl RateLimited {
+    /// Seconds for the `Retry-After` header, rounded up.
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "too many requests, retry after {} seconds",
+            self.retry_after_secs()
+        )
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        let secs = self.retry_after_secs();
+        let mut res = (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response();
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(secs));
+        res
+    }
+}
+
+pub async fn rate_limit(State(limiter): State<RateLimiter>, req: Request, next: Next) -> Response {
+    let key = limiter.request_key(&req);
+    match limiter.check(&key).await {
+        Ok(()) => next.run(req).await,
+        Err(e) => {
+            warn!("rate limited {}: {}", limiter.inner.name, key);
+            e.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{body::Body, middleware::from_fn_with_state, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn take_should_refill_over_time() {
+        let limit = RateLimit::new(10, 60);
+        assert_eq!(limit.take(10.0, Duration::ZERO), (9.0, None));
+
+        let (tokens, retry_after) = limit.take(0.0, Duration::from_secs(3));
+        assert_eq!(retry_after, Some(Duration::from_secs(3)));
+        assert_eq!(tokens, 0.5);
+        // never more than the burst
+        assert_eq!(limit.take(5.0, Duration::from_secs(3600)), (9.0, None));
+    }
+
+    #[tokio::test]
+    async fn memory_store_should_limit_per_key() -> Result<()> {
+        let store = Arc::new(MemoryRateLimitStore::new());
+        let limiter = RateLimiter::new("test", RateLimit::new(2, 60), store);
+        limiter.check("a").await.unwrap();
+        limiter.check("a").await.unwrap();
+        let err = limiter.check("a").await.unwrap_err();
+        assert_eq!(err.retry_after_secs(), 30);
+        limiter.check("b").await.unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limit_middleware_should_return_429() -> Result<()> {
+        let store = Arc::new(MemoryRateLimitStore::new());
+        let limiter = RateLimiter::new("test", RateLimit::new(1, 10), store)
+            .with_key(RateLimitKey::User)
+            .trust_forwarded_for(true);
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(from_fn_with_state(limiter, rate_limit));
+
+        let req = |ip: &str| {
+            Request::builder()
+                .uri("/")
+                .header("x-forwarded-for", format!("{}, 10.0.0.1", ip))
+                .body(Body::empty())
+        };
+        let res = app.clone().oneshot(req("1.1.1.1")?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.clone().oneshot(req("1.1.1.1")?).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "10");
+        let res = app.oneshot(req("2.2.2.2")?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
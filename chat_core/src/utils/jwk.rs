@@ -0,0 +1,298 @@
+use jwt_simple::{prelude::*, JWTError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+type AppError = jwt_simple::Error;
+
+/// Algorithms access tokens can be signed with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum JwtAlgorithm {
+    #[default]
+    Ed25519,
+    ES256,
+    ES384,
+    RS256,
+}
+
+/// A public key in JSON Web Key format (RFC 7517).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+pub(crate) enum SigningKey {
+    Ed25519(Ed25519KeyPair),
+    ES256(ES256KeyPair),
+    ES384(ES384KeyPair),
+    RS256(RS256KeyPair),
+}
+
+// only a handful of keys are ever loaded, boxing RSA keys is not worth it
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub(crate) enum VerifyingKey {
+    Ed25519(Ed25519PublicKey),
+    ES256(ES256PublicKey),
+    ES384(ES384PublicKey),
+    RS256(RS256PublicKey),
+}
+
+impl JwtAlgorithm {
+    /// Name of the algorithm in the JWT header and JWK `alg` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ed25519 => "EdDSA",
+            Self::ES256 => "ES256",
+            Self::ES384 => "ES384",
+            Self::RS256 => "RS256",
+        }
+    }
+}
+
+impl SigningKey {
+    pub(crate) fn load(alg: JwtAlgorithm, pem: &str) -> Result<Self, AppError> {
+        let key = match alg {
+            JwtAlgorithm::Ed25519 => Self::Ed25519(Ed25519KeyPair::from_pem(pem)?),
+            JwtAlgorithm::ES256 => Self::ES256(ES256KeyPair::from_pem(pem)?),
+            JwtAlgorithm::ES384 => Self::ES384(ES384KeyPair::from_pem(pem)?),
+            JwtAlgorithm::RS256 => Self::RS256(RS256KeyPair::from_pem(pem)?),
+        };
+        Ok(key)
+    }
+
+    pub(crate) fn public_key(&self) -> VerifyingKey {
+        match self {
+            Self::Ed25519(key) => VerifyingKey::Ed25519(key.public_key()),
+            Self::ES256(key) => VerifyingKey::ES256(key.public_key()),
+            Self::ES384(key) => VerifyingKey::ES384(key.public_key()),
+            Self::RS256(key) => VerifyingKey::RS256(key.public_key()),
+        }
+    }
+
+    pub(crate) fn with_key_id(self, kid: &str) -> Self {
+        match self {
+            Self::Ed25519(key) => Self::Ed25519(key.with_key_id(kid)),
+            Self::ES256(key) => Self::ES256(key.with_key_id(kid)),
+            Self::ES384(key) => Self::ES384(key.with_key_id(kid)),
+            Self::RS256(key) => Self::RS256(key.with_key_id(kid)),
+        }
+    }
+
+    pub(crate) fn sign<T: Serialize + DeserializeOwned>(
+        &self,
+        claims: JWTClaims<T>,
+    ) -> Result<String, AppError> {
+        match self {
+            Self::Ed25519(key) => key.sign(claims),
+            Self::ES256(key) => key.sign(claims),
+            Self::ES384(key) => key.sign(claims),
+            Self::RS256(key) => key.sign(claims),
+        }
+    }
+}
+
+impl VerifyingKey {
+    pub(crate) fn load(alg: JwtAlgorithm, pem: &str) -> Result<Self, AppError> {
+        let key = match alg {
+            JwtAlgorithm::Ed25519 => Self::Ed25519(Ed25519PublicKey::from_pem(pem)?),
+            JwtAlgorithm::ES256 => Self::ES256(ES256PublicKey::from_pem(pem)?),
+            JwtAlgorithm::ES384 => Self::ES384(ES384PublicKey::from_pem(pem)?),
+            JwtAlgorithm::RS256 => Self::RS256(RS256PublicKey::from_pem(pem)?),
+        };
+        Ok(key)
+    }
+
+    /// Parse a JWK, `None` if its algorithm is not supported.
+    pub(crate) fn from_jwk(jwk: &Jwk) -> Result<Option<Self>, AppError> {
+        let field = |v: &Option<String>| -> Result<Vec<u8>, AppError> {
+            let v = v.as_deref().ok_or(JWTError::InvalidPublicKey)?;
+            Ok(Base64UrlSafeNoPadding::decode_to_vec(v, None)?)
+        };
+        // EC keys in uncompressed SEC1 form
+        let point = || -> Result<Vec<u8>, AppError> {
+            Ok([vec![4], field(&jwk.x)?, field(&jwk.y)?].concat())
+        };
+        let key = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("OKP", Some("Ed25519")) => {
+                Self::Ed25519(Ed25519PublicKey::from_bytes(&field(&jwk.x)?)?)
+            }
+            ("EC", Some("P-256")) => Self::ES256(ES256PublicKey::from_bytes(&point()?)?),
+            ("EC", Some("P-384")) => Self::ES384(ES384PublicKey::from_bytes(&point()?)?),
+            ("RSA", _) if jwk.alg == JwtAlgorithm::RS256.name() => Self::RS256(
+                RS256PublicKey::from_components(&field(&jwk.n)?, &field(&jwk.e)?)?,
+            ),
+            _ => return Ok(None),
+        };
+        Ok(Some(key))
+    }
+
+    pub(crate) fn algorithm(&self) -> JwtAlgorithm {
+        match self {
+            Self::Ed25519(_) => JwtAlgorithm::Ed25519,
+            Self::ES256(_) => JwtAlgorithm::ES256,
+            Self::ES384(_) => JwtAlgorithm::ES384,
+            Self::RS256(_) => JwtAlgorithm::RS256,
+        }
+    }
+
+    pub(crate) fn with_key_id(self, kid: &str) -> Self {
+        match self {
+            Self::Ed25519(key) => Self::Ed25519(key.with_key_id(kid)),
+            Self::ES256(key) => Self::ES256(key.with_key_id(kid)),
+            Self::ES384(key) => Self::ES384(key.with_key_id(kid)),
+            Self::RS256(key) => Self::RS256(key.with_key_id(kid)),
+        }
+    }
+
+    pub(crate) fn verify_token<T: Serialize + DeserializeOwned>(
+        &self,
+        token: &str,
+        options: Option<VerificationOptions>,
+    ) -> Result<JWTClaims<T>, AppError> {
+        match self {
+            Self::Ed25519(key) => key.verify_token(token, options),
+            Self::ES256(key) => key.verify_token(token, options),
+            Self::ES384(key) => key.verify_token(token, options),
+            Self::RS256(key) => key.verify_token(token, options),
+        }
+    }
+
+    pub(crate) fn to_jwk(&self, kid: &str) -> Jwk {
+        let b64 = |v: &[u8]| {
+            Base64UrlSafeNoPadding::encode_to_string(v).expect("base64 encoding should not fail")
+        };
+        // uncompressed SEC1 point is 0x04 || x || y
+        let ec = |crv: &'static str, point: Vec<u8>| {
+            let (x, y) = point[1..].split_at((point.len() - 1) / 2);
+            ("EC", Some(crv), Some(b64(x)), Some(b64(y)), None, None)
+        };
+        let (kty, crv, x, y, n, e) = match self {
+            Self::Ed25519(key) => (
+                "OKP",
+                Some("Ed25519"),
+                Some(b64(&key.to_bytes())),
+                None,
+                None,
+                None,
+            ),
+            Self::ES256(key) => ec("P-256", key.public_key().to_bytes_uncompressed()),
+            Self::ES384(key) => ec("P-384", key.public_key().to_bytes_uncompressed()),
+            Self::RS256(key) => {
+                let c = key.to_components();
+                ("RSA", None, None, None, Some(b64(&c.n)), Some(b64(&c.e)))
+            }
+        };
+        Jwk {
+            kty: kty.to_string(),
+            crv: crv.map(|v| v.to_string()),
+            x,
+            y,
+            n,
+            e,
+            kid: kid.to_string(),
+            alg: self.algorithm().name().to_string(),
+            key_use: "sig".to_string(),
+        }
+    }
+
+    /// JWK thumbprint (RFC 7638), used as the default key id.
+    pub(crate) fn thumbprint(&self) -> String {
+        let jwk = self.to_jwk("");
+        // required members only, in lexicographic order
+        let members = match jwk.kty.as_str() {
+            "RSA" => vec![("e", jwk.e), ("kty", Some(jwk.kty)), ("n", jwk.n)],
+            "EC" => vec![
+                ("crv", jwk.crv),
+                ("kty", Some(jwk.kty)),
+                ("x", jwk.x),
+                ("y", jwk.y),
+            ],
+            _ => vec![("crv", jwk.crv), ("kty", Some(jwk.kty)), ("x", jwk.x)],
+        };
+        let members: Vec<_> = members
+            .into_iter()
+            .map(|(k, v)| format!(r#""{}":"{}""#, k, v.unwrap_or_default()))
+            .collect();
+        let hash = Sha256::digest(format!("{{{}}}", members.join(",")));
+        Base64UrlSafeNoPadding::encode_to_string(hash).expect("base64 encoding should not fail")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn jwk_roundtrip_should_work_for_all_algorithms() -> Result<()> {
+        let keys = [
+            (
+                JwtAlgorithm::Ed25519,
+                include_str!("../../fixtures/public.pem"),
+            ),
+            (
+                JwtAlgorithm::ES256,
+                include_str!("../../fixtures/es256public.pem"),
+            ),
+            (
+                JwtAlgorithm::ES384,
+                include_str!("../../fixtures/es384public.pem"),
+            ),
+            (
+                JwtAlgorithm::RS256,
+                include_str!("../../fixtures/384public.pem"),
+            ),
+        ];
+        for (alg, pem) in keys {
+            let key = VerifyingKey::load(alg, pem)?;
+            let jwk = key.to_jwk("kid");
+            assert_eq!(jwk.alg, alg.name());
+            let key2 = VerifyingKey::from_jwk(&jwk)?.expect("jwk should be supported");
+            assert_eq!(key2.algorithm(), alg);
+            assert_eq!(key2.thumbprint(), key.thumbprint());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn jwk_thumbprint_should_match_rfc7638() -> Result<()> {
+        // example key from RFC 7638 section 3.1
+        let jwk = Jwk {
+            kty: "RSA".to_string(),
+            crv: None,
+            x: None,
+            y: None,
+            n: Some("0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw".to_string()),
+            e: Some("AQAB".to_string()),
+            kid: "2011-04-29".to_string(),
+            alg: "RS256".to_string(),
+            key_use: "sig".to_string(),
+        };
+        let key = VerifyingKey::from_jwk(&jwk)?.expect("jwk should be supported");
+        assert_eq!(
+            key.thumbprint(),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+        Ok(())
+    }
+}
@@ -1,45 +1,177 @@
-use jwt_simple::prelude::*;
+use std::collections::HashMap;
 
+use chrono::{DateTime, Utc};
+use jwt_simple::{prelude::*, JWTError};
+use uuid::Uuid;
+
+use super::jwk::{JwkSet, JwtAlgorithm, SigningKey, VerifyingKey};
 use crate::User;
 type AppError = jwt_simple::Error;
 
-const JWT_DURATION: u64 = 60 * 60 * 24 * 7;
+// access tokens are short-lived, clients renew them with a refresh token
+pub const JWT_DURATION: u64 = 60 * 15;
+// clock skew allowed between servers, kept small so expiry stays meaningful
+const JWT_TIME_TOLERANCE: u64 = 5;
 const JWT_ISSUER: &str = "chat_server";
 const JWT_AUDIENCE: &str = "chat_web";
 // 加密
-pub struct EncodingKeyPair(Ed25519KeyPair);
+pub struct EncodingKeyPair {
+    key: SigningKey,
+    kid: String,
+    // lifetime of the tokens signed by `sign` in seconds
+    ttl: u64,
+}
+
+/// Public keys accepted when verifying a token, keyed by `kid`.
+#[derive(Debug, Default)]
+pub struct DecodingKey {
+    keys: HashMap<String, VerifyingKey>,
+    // used for tokens issued before tokens carried a `kid`
+    default_kid: Option<String>,
+}
 
-#[derive(Debug)]
-pub struct DecodingKey(Ed25519PublicKey);
+/// Verified claims of an access token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenClaims {
+    pub user: User,
+    pub jti: String,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
 
 impl EncodingKeyPair {
-    pub fn load(pem: &str) -> Result<Self, AppError> {
-        let key_pair = Ed25519KeyPair::from_pem(pem)?;
-        Ok(Self(key_pair))
+    /// Load the signing key, the key id defaults to the public key thumbprint.
+    pub fn load(alg: JwtAlgorithm, pem: &str, kid: Option<&str>) -> Result<Self, AppError> {
+        let key = SigningKey::load(alg, pem)?;
+        let kid = match kid {
+            Some(kid) => kid.to_string(),
+            None => key.public_key().thumbprint(),
+        };
+        let key = key.with_key_id(&kid);
+        Ok(Self {
+            key,
+            kid,
+            ttl: JWT_DURATION,
+        })
+    }
+
+    /// Set the lifetime of the tokens signed by `sign`.
+    pub fn with_ttl(mut self, secs: u64) -> Self {
+        self.ttl = secs;
+        self
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn ttl(&self) -> u64 {
+        self.ttl
     }
 
     pub fn sign(&self, user: impl Into<User>) -> Result<String, AppError> {
-        let claims = Claims::with_custom_claims(user.into(), Duration::from_secs(JWT_DURATION));
-        let claims = claims.with_issuer(JWT_ISSUER).with_audience(JWT_AUDIENCE);
-        self.0.sign(claims)
+        self.sign_with_duration(user, self.ttl)
+    }
+
+    pub fn sign_with_duration(&self, user: impl Into<User>, secs: u64) -> Result<String, AppError> {
+        let claims = Claims::with_custom_claims(user.into(), Duration::from_secs(secs));
+        let claims = claims
+            .with_issuer(JWT_ISSUER)
+            .with_audience(JWT_AUDIENCE)
+            .with_jwt_id(Uuid::now_v7().to_string());
+        self.key.sign(claims)
     }
 }
 
 impl DecodingKey {
-    pub fn load(pem: &str) -> Result<Self, AppError> {
-        let key = Ed25519PublicKey::from_pem(pem)?;
-        Ok(Self(key))
+    /// Load a single verification key, which also verifies tokens without a `kid`.
+    pub fn load(alg: JwtAlgorithm, pem: &str, kid: Option<&str>) -> Result<Self, AppError> {
+        let mut dk = Self::default();
+        dk.add_key(alg, pem, kid)?;
+        Ok(dk)
+    }
+
+    /// Add a verification key and return its key id. The first key added is
+    /// used for tokens that carry no `kid`.
+    pub fn add_key(
+        &mut self,
+        alg: JwtAlgorithm,
+        pem: &str,
+        kid: Option<&str>,
+    ) -> Result<String, AppError> {
+        let key = VerifyingKey::load(alg, pem)?;
+        Ok(self.insert(key, kid))
+    }
+
+    /// Build from a JWKS document, keys with unsupported algorithms are skipped.
+    pub fn from_jwks(jwks: &JwkSet) -> Result<Self, AppError> {
+        let mut dk = Self::default();
+        for jwk in &jwks.keys {
+            if let Some(key) = VerifyingKey::from_jwk(jwk)? {
+                dk.insert(key, Some(&jwk.kid));
+            }
+        }
+        Ok(dk)
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        let mut keys: Vec<_> = self.keys.iter().map(|(kid, key)| key.to_jwk(kid)).collect();
+        keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+        JwkSet { keys }
+    }
+
+    fn insert(&mut self, key: VerifyingKey, kid: Option<&str>) -> String {
+        let kid = match kid {
+            Some(kid) => kid.to_string(),
+            None => key.thumbprint(),
+        };
+        if self.default_kid.is_none() {
+            self.default_kid = Some(kid.clone());
+        }
+        self.keys.insert(kid.clone(), key.with_key_id(&kid));
+        kid
     }
+
     #[allow(unused)]
     pub fn verify(&self, token: &str) -> Result<User, AppError> {
+        Ok(self.verify_claims(token)?.user)
+    }
+
+    pub fn verify_claims(&self, token: &str) -> Result<TokenClaims, AppError> {
+        let metadata = Token::decode_metadata(token)?;
+        let key = metadata
+            .key_id()
+            .or(self.default_kid.as_deref())
+            .and_then(|kid| self.keys.get(kid))
+            .ok_or(JWTError::KeyIdentifierMismatch)?;
         let options = VerificationOptions {
             allowed_issuers: Some(HashSet::from_strings(&[JWT_ISSUER])),
             allowed_audiences: Some(HashSet::from_strings(&[JWT_AUDIENCE])),
+            time_tolerance: Some(Duration::from_secs(JWT_TIME_TOLERANCE)),
             ..Default::default()
         };
 
-        let claims = self.0.verify_token::<User>(token, Some(options))?;
-        Ok(claims.custom)
+        let claims = key.verify_token::<User>(token, Some(options))?;
+        let timestamp = |t: Option<UnixTimeStamp>| {
+            t.and_then(|t| DateTime::from_timestamp(t.as_secs() as _, 0))
+                .unwrap_or_default()
+        };
+        let jti = claims.jwt_id.unwrap_or_default();
+        // `iat` has whole seconds, the uuid v7 jti carries the issue time in milliseconds
+        let issued_at = Uuid::parse_str(&jti)
+            .ok()
+            .and_then(|id| id.get_timestamp())
+            .and_then(|ts| {
+                let (secs, nanos) = ts.to_unix();
+                DateTime::from_timestamp(secs as _, nanos)
+            })
+            .unwrap_or_else(|| timestamp(claims.issued_at));
+        Ok(TokenClaims {
+            jti,
+            issued_at,
+            expires_at: timestamp(claims.expires_at),
+            user: claims.custom,
+        })
     }
 }
 
@@ -47,13 +179,14 @@ impl DecodingKey {
 mod tests {
     use super::*;
     use anyhow::Result;
+    use chrono::SubsecRound;
 
     #[tokio::test]
     async fn jwt_sign_verify_should_work() -> Result<()> {
         let encoding_pem = include_str!("../../fixtures/private.pem");
         let decoding_pem = include_str!("../../fixtures/public.pem");
-        let ek = EncodingKeyPair::load(encoding_pem)?;
-        let dk = DecodingKey::load(decoding_pem)?;
+        let ek = EncodingKeyPair::load(JwtAlgorithm::Ed25519, encoding_pem, None)?;
+        let dk = DecodingKey::load(JwtAlgorithm::Ed25519, decoding_pem, None)?;
 
         let user = User::new(1, "Tyr Chen", "tchen@acme.org");
 
@@ -61,6 +194,124 @@ mod tests {
         let user2 = dk.verify(&token)?;
 
         assert_eq!(user, user2);
+
+        // issued_at has millisecond precision
+        let before = Utc::now().trunc_subsecs(3);
+        let claims = dk.verify_claims(&ek.sign(user)?)?;
+        assert!(claims.issued_at >= before);
+        assert!(claims.issued_at <= Utc::now());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn jwt_expired_token_should_fail() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/private.pem");
+        let decoding_pem = include_str!("../../fixtures/public.pem");
+        let ek = EncodingKeyPair::load(JwtAlgorithm::Ed25519, encoding_pem, None)?;
+        let dk = DecodingKey::load(JwtAlgorithm::Ed25519, decoding_pem, None)?;
+
+        let user = User::new(1, "Tyr Chen", "tchen@acme.org");
+        // expired a minute ago, well within jwt-simple's default 15 minute tolerance
+        let mut claims = Claims::with_custom_claims(user, Duration::from_secs(0));
+        claims.expires_at = Some(Clock::now_since_epoch() - Duration::from_mins(1));
+        let claims = claims.with_issuer(JWT_ISSUER).with_audience(JWT_AUDIENCE);
+        let token = ek.key.sign(claims)?;
+
+        assert!(dk.verify(&token).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_should_verify_with_matching_kid() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/private.pem");
+        let decoding_pem = include_str!("../../fixtures/public.pem");
+        let old = EncodingKeyPair::load(JwtAlgorithm::Ed25519, encoding_pem, Some("old"))?;
+        let new = EncodingKeyPair::load(JwtAlgorithm::Ed25519, encoding_pem, Some("new"))?;
+        let mut dk = DecodingKey::load(JwtAlgorithm::Ed25519, decoding_pem, Some("new"))?;
+
+        let user = User::new(1, "Tyr Chen", "tchen@acme.org");
+        assert!(dk.verify(&new.sign(user.clone())?).is_ok());
+        assert!(dk.verify(&old.sign(user.clone())?).is_err());
+
+        dk.add_key(JwtAlgorithm::Ed25519, decoding_pem, Some("old"))?;
+        assert_eq!(dk.verify(&old.sign(user.clone())?)?, user);
+        Ok(())
+    }
+
+    #[test]
+    fn jwks_roundtrip_should_work() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/private.pem");
+        let decoding_pem = include_str!("../../fixtures/public.pem");
+        let ek = EncodingKeyPair::load(JwtAlgorithm::Ed25519, encoding_pem, None)?;
+        let dk = DecodingKey::load(JwtAlgorithm::Ed25519, decoding_pem, None)?;
+
+        let jwks = dk.jwks();
+        assert_eq!(jwks.keys.len(), 1);
+        assert_eq!(jwks.keys[0].kid, ek.kid());
+        assert_eq!(jwks.keys[0].kty, "OKP");
+        assert_eq!(jwks.keys[0].alg, "EdDSA");
+
+        let dk = DecodingKey::from_jwks(&jwks)?;
+        let user = User::new(1, "Tyr Chen", "tchen@acme.org");
+        assert_eq!(dk.verify(&ek.sign(user.clone())?)?, user);
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_sign_verify_should_work_for_all_algorithms() -> Result<()> {
+        let keys = [
+            (
+                JwtAlgorithm::Ed25519,
+                include_str!("../../fixtures/private.pem"),
+                include_str!("../../fixtures/public.pem"),
+            ),
+            (
+                JwtAlgorithm::ES256,
+                include_str!("../../fixtures/es256private.pem"),
+                include_str!("../../fixtures/es256public.pem"),
+            ),
+            (
+                JwtAlgorithm::ES384,
+                include_str!("../../fixtures/es384private.pem"),
+                include_str!("../../fixtures/es384public.pem"),
+            ),
+            (
+                JwtAlgorithm::RS256,
+                include_str!("../../fixtures/384private.pem"),
+                include_str!("../../fixtures/384public.pem"),
+            ),
+        ];
+        let user = User::new(1, "Tyr Chen", "tchen@acme.org");
+        for (alg, encoding_pem, decoding_pem) in keys {
+            let ek = EncodingKeyPair::load(alg, encoding_pem, None)?;
+            let dk = DecodingKey::load(alg, decoding_pem, None)?;
+            let token = ek.sign(user.clone())?;
+            let metadata = Token::decode_metadata(&token)?;
+            assert_eq!(metadata.algorithm(), alg.name());
+            assert_eq!(metadata.key_id(), Some(ek.kid()));
+            assert_eq!(dk.verify(&token)?, user);
+
+            // verifiers only get the published keys
+            let dk = DecodingKey::from_jwks(&dk.jwks())?;
+            assert_eq!(dk.verify(&token)?, user);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_should_reject_token_of_other_algorithm() -> Result<()> {
+        let ek = EncodingKeyPair::load(
+            JwtAlgorithm::ES256,
+            include_str!("../../fixtures/es256private.pem"),
+            Some("key"),
+        )?;
+        let dk = DecodingKey::load(
+            JwtAlgorithm::Ed25519,
+            include_str!("../../fixtures/public.pem"),
+            Some("key"),
+        )?;
+        let user = User::new(1, "Tyr Chen", "tchen@acme.org");
+        assert!(dk.verify(&ek.sign(user)?).is_err());
         Ok(())
     }
 }
@@ -1,3 +1,7 @@
+mod jwk;
 mod jwt;
+mod revocation;
 
-pub use jwt::{DecodingKey, EncodingKeyPair};
+pub use jwk::{Jwk, JwkSet, JwtAlgorithm};
+pub use jwt::{DecodingKey, EncodingKeyPair, TokenClaims, JWT_DURATION};
+pub use revocation::{RevocationList, TokenRevocation};
@@ -0,0 +1,105 @@
+use crate::TokenClaims;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+/// Either a single revoked token (`jti`), or all tokens of a user issued before a time.
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct TokenRevocation {
+    pub user_id: i64,
+    pub jti: Option<String>,
+    pub issued_before: Option<DateTime<Utc>>,
+    // once passed, none of the revoked tokens can be valid anymore
+    pub expires_at: DateTime<Utc>,
+}
+
+/// In-memory revocation list consulted on every token verification.
+#[derive(Debug, Clone, Default)]
+pub struct RevocationList(Arc<RwLock<RevocationListInner>>);
+
+#[derive(Debug, Default)]
+struct RevocationListInner {
+    // jti -> expires_at
+    tokens: HashMap<String, DateTime<Utc>>,
+    // user id -> (issued_before, expires_at)
+    users: HashMap<i64, (DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl RevocationList {
+    pub fn insert(&self, revocation: TokenRevocation) {
+        let now = Utc::now();
+        let mut inner = self.0.write().expect("revocation list poisoned");
+        inner.tokens.retain(|_, expires_at| *expires_at > now);
+        inner.users.retain(|_, (_, expires_at)| *expires_at > now);
+
+        if let Some(jti) = revocation.jti {
+            inner.tokens.insert(jti, revocation.expires_at);
+        }
+        if let Some(issued_before) = revocation.issued_before {
+            let entry = inner
+                .users
+                .entry(revocation.user_id)
+                .or_insert((issued_before, revocation.expires_at));
+            entry.0 = entry.0.max(issued_before);
+            entry.1 = entry.1.max(revocation.expires_at);
+        }
+    }
+
+    pub fn is_revoked(&self, claims: &TokenClaims) -> bool {
+        let inner = self.0.read().expect("revocation list poisoned");
+        inner.tokens.contains_key(&claims.jti)
+            || inner
+                .users
+                .get(&claims.user.id)
+                .is_some_and(|(issued_before, _)| claims.issued_at < *issued_before)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::User;
+    use chrono::Duration;
+
+    fn claims(jti: &str, issued_at: DateTime<Utc>) -> TokenClaims {
+        TokenClaims {
+            user: User::new(1, "Tyr Chen", "tchen@acme.org"),
+            jti: jti.to_string(),
+            issued_at,
+            expires_at: issued_at + Duration::minutes(15),
+        }
+    }
+
+    #[test]
+    fn revocation_list_should_work() {
+        let now = Utc::now();
+        let list = RevocationList::default();
+        let token = claims("a", now - Duration::minutes(1));
+        let other = claims("b", now - Duration::minutes(1));
+        assert!(!list.is_revoked(&token));
+
+        list.insert(TokenRevocation {
+            user_id: 1,
+            jti: Some("a".to_string()),
+            issued_before: None,
+            expires_at: token.expires_at,
+        });
+        assert!(list.is_revoked(&token));
+        assert!(!list.is_revoked(&other));
+
+        list.insert(TokenRevocation {
+            user_id: 1,
+            jti: None,
+            issued_before: Some(now),
+            expires_at: now + Duration::minutes(15),
+        });
+        assert!(list.is_revoked(&other));
+        assert!(list.is_revoked(&claims("c", now - Duration::milliseconds(1))));
+        assert!(!list.is_revoked(&claims("d", now)));
+        assert!(!list.is_revoked(&claims("e", now + Duration::milliseconds(1))));
+    }
+}
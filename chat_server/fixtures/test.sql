@@ -14,13 +14,51 @@ INSERT INTO users(ws_id, email, fullname, password_hash)
 (1, 'charlie@acme.org', 'Charlie Shi', '$argon2id$v=19$m=19456,t=2,p=1$MxGhY+ib/kplwBPLa7u2ug$c5h9u7Sc8Px8J5+qgNdOjSY7ZJO2QN4rugKpapGW4XU'),
 (1, 'daisy@acme.org', 'Daisy Shi', '$argon2id$v=19$m=19456,t=2,p=1$MxGhY+ib/kplwBPLa7u2ug$c5h9u7Sc8Px8J5+qgNdOjSY7ZJO2QN4rugKpapGW4XU');
 
--- insert 4 chats
+-- user 1 owns workspace acm
+UPDATE
+  workspaces
+SET
+  owner_id = 1
+WHERE
+  id = 1;
+
+INSERT INTO workspace_members(ws_id, user_id, role)
+SELECT
+  ws_id,
+  id,
+  CASE WHEN id = 1 THEN
+    'owner'::workspace_role
+  ELSE
+    'member'::workspace_role
+  END
+FROM
+  users
+WHERE
+  id > 0;
+
+-- insert 4 chats, all created by user 1
 -- insert public/private channel
-INSERT INTO chats(ws_id, name, type, members)
-  VALUES (1, 'general', 'public_channel', '{1,2,3,4,5}'),
-(1, 'private', 'private_channel', '{1,2,3}');
+INSERT INTO chats(ws_id, name, type, created_by)
+  VALUES (1, 'general', 'public_channel', 1),
+(1, 'private', 'private_channel', 1);
 
 -- insert unnamed chat
-INSERT INTO chats(ws_id, type, members)
-  VALUES (1, 'single', '{1,2}'),
-(1, 'group', '{1,3,4}');
+INSERT INTO chats(ws_id, type, created_by)
+  VALUES (1, 'single', 1),
+(1, 'group', 1);
+
+-- insert chat members, user 1 owns all chats
+INSERT INTO chat_members(chat_id, user_id, role)
+  VALUES (1, 1, 'owner'),
+(1, 2, 'member'),
+(1, 3, 'member'),
+(1, 4, 'member'),
+(1, 5, 'member'),
+(2, 1, 'owner'),
+(2, 2, 'member'),
+(2, 3, 'member'),
+(3, 1, 'owner'),
+(3, 2, 'member'),
+(4, 1, 'owner'),
+(4, 3, 'member'),
+(4, 4, 'member');
@@ -1,21 +1,61 @@
 use std::fs::File;
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use chat_core::{
+    middlewares::{
+        MemoryRateLimitStore, PgRateLimitStore, RateLimit, RateLimitKey, RateLimitStore,
+        RateLimiter,
+    },
+    DecodingKey, EncodingKeyPair, JwtAlgorithm,
+};
 use serde::Deserialize;
 use serde::Serialize;
+use sqlx::PgPool;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
-    pub auth:AuthConfig
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub mail: MailConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
 }
 
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthConfig {
+    /// one of Ed25519, ES256, ES384, RS256
+    #[serde(default)]
+    pub algorithm: JwtAlgorithm,
     pub sk: String,
     pub pk: String,
+    /// key id of the signing key, defaults to the public key thumbprint
+    #[serde(default)]
+    pub kid: Option<String>,
+    /// retired public keys that still verify tokens signed before a rotation
+    #[serde(default)]
+    pub previous_keys: Vec<VerificationKey>,
+    /// lifetime of access tokens in seconds
+    #[serde(default = "default_access_token_ttl")]
+    pub access_token_ttl: u64,
+    /// lifetime of refresh tokens in seconds
+    #[serde(default = "default_refresh_token_ttl")]
+    pub refresh_token_ttl: u64,
+    /// hmac key of signed file urls, changing it invalidates the urls handed out
+    pub file_url_secret: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerificationKey {
+    /// defaults to the algorithm of the signing key
+    #[serde(default)]
+    pub algorithm: Option<JwtAlgorithm>,
+    pub kid: Option<String>,
+    pub pk: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -23,6 +63,213 @@ pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
     pub base_dir: PathBuf,
+    /// largest file accepted by upload, in bytes
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// largest upload request with all its files, in bytes
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+    /// bytes of uploaded files a workspace may store
+    #[serde(default = "default_workspace_quota")]
+    pub workspace_quota: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MailConfig {
+    /// sender of outgoing mails, e.g. `Chat <noreply@acme.org>`
+    pub from: String,
+    /// url of the web app, links in mails point to it
+    pub app_url: String,
+    pub transport: MailTransport,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp(SmtpConfig),
+    /// write mails to a directory instead of sending them
+    File {
+        dir: PathBuf,
+    },
+}
+
+/// Where uploaded files are stored, uploads are staged under `server.base_dir` either way.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// files under `server.base_dir`
+    #[default]
+    Local,
+    /// a bucket of an S3 compatible store, e.g. AWS S3 or MinIO
+    S3(S3Config),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S3Config {
+    /// e.g. `https://s3.us-east-1.amazonaws.com` or `http://localhost:9000`
+    pub endpoint: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    /// use implicit TLS, only disable it for a local relay
+    #[serde(default = "default_smtp_tls")]
+    pub tls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            from: "Chat <noreply@localhost>".to_string(),
+            app_url: "http://localhost:6688".to_string(),
+            transport: MailTransport::File {
+                dir: std::env::temp_dir().join("chat_server/mails"),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// keep buckets in memory, or in postgres to share them between instances
+    pub store: RateLimitStoreKind,
+    /// take the client ip from X-Forwarded-For, only behind a trusted proxy
+    pub trust_forwarded_for: bool,
+    /// per client ip on signin, signup and the other unauthenticated routes
+    pub auth: RateLimit,
+    /// per email on signin
+    pub signin: RateLimit,
+    /// per user on all authenticated routes
+    pub api: RateLimit,
+    pub lockout: LockoutConfig,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStoreKind {
+    #[default]
+    Memory,
+    Postgres,
+}
+
+/// Lock signin for an email after repeated failures, doubling the delay every time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    /// failures before the first lock
+    pub threshold: u32,
+    /// seconds of the first lock
+    pub base_delay: u64,
+    /// upper bound of the lock in seconds
+    pub max_delay: u64,
+}
+
+/// Limiters built from the config, sharing one store.
+pub struct RateLimiters {
+    pub auth: RateLimiter,
+    pub signin: RateLimiter,
+    pub api: RateLimiter,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            store: RateLimitStoreKind::default(),
+            trust_forwarded_for: false,
+            auth: RateLimit::new(30, 60),
+            signin: RateLimit::new(10, 300),
+            api: RateLimit::new(600, 60),
+            lockout: LockoutConfig::default(),
+        }
+    }
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            base_delay: 30,
+            max_delay: 60 * 60,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn limiters(&self, pool: &PgPool) -> RateLimiters {
+        let store: Arc<dyn RateLimitStore> = match self.store {
+            RateLimitStoreKind::Memory => Arc::new(MemoryRateLimitStore::new()),
+            RateLimitStoreKind::Postgres => Arc::new(PgRateLimitStore::new(pool.clone())),
+        };
+        let limiter = |name: &str, limit: RateLimit| {
+            RateLimiter::new(name, limit, store.clone())
+                .trust_forwarded_for(self.trust_forwarded_for)
+        };
+        RateLimiters {
+            auth: limiter("auth", self.auth),
+            signin: limiter("signin", self.signin),
+            api: limiter("api", self.api).with_key(RateLimitKey::User),
+        }
+    }
+}
+
+fn default_max_file_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_upload_size() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_workspace_quota() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    465
+}
+
+fn default_smtp_tls() -> bool {
+    true
+}
+
+fn default_access_token_ttl() -> u64 {
+    chat_core::JWT_DURATION
+}
+
+fn default_refresh_token_ttl() -> u64 {
+    60 * 60 * 24 * 30
+}
+
+impl AuthConfig {
+    /// Load the signing key and every key tokens may be verified with.
+    pub fn load_keys(&self) -> Result<(EncodingKeyPair, DecodingKey)> {
+        let ek = EncodingKeyPair::load(self.algorithm, &self.sk, self.kid.as_deref())
+            .context("load sk failed")?
+            .with_ttl(self.access_token_ttl);
+        let mut dk = DecodingKey::load(self.algorithm, &self.pk, Some(ek.kid()))
+            .context("load pk failed")?;
+        for key in &self.previous_keys {
+            let algorithm = key.algorithm.unwrap_or(self.algorithm);
+            dk.add_key(algorithm, &key.pk, key.kid.as_deref())
+                .context("load previous pk failed")?;
+        }
+        Ok((ek, dk))
+    }
 }
 
 impl AppConfig {
@@ -1,6 +1,7 @@
-use axum::http::StatusCode;
+use axum::http::{header::RETRY_AFTER, HeaderValue, StatusCode};
 use axum::response::Json;
 use axum::response::{IntoResponse, Response};
+use chat_core::middlewares::RateLimited;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use utoipa::ToSchema;
@@ -15,18 +16,54 @@ pub enum AppError {
     #[error("email already exists: {0}")]
     EmailAlreadyExists(String),
 
+    #[error("workspace already exists: {0}")]
+    WorkspaceAlreadyExists(String),
+
+    #[error("update workspace error: {0}")]
+    UpdateWorkspaceError(String),
+
+    #[error("mfa error: {0}")]
+    MfaError(String),
+
+    #[error("invite error: {0}")]
+    InviteError(String),
+
     #[error("create chat error: {0}")]
     CreateChatError(String),
 
+    #[error("update chat error: {0}")]
+    UpdateChatError(String),
+
     #[error("create message error: {0}")]
     CreateMessageError(String),
 
+    #[error("update message error: {0}")]
+    UpdateMessageError(String),
+
+    #[error("search error: {0}")]
+    SearchError(String),
+
     #[error("{0}")]
     ChatFileError(String),
 
+    #[error("payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("storage quota exceeded: {used} of {quota} bytes used")]
+    QuotaExceeded { used: u64, quota: u64 },
+
     #[error("Not found: {0}")]
     NotFound(String),
 
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("{0}")]
+    TooManyRequests(RateLimited),
+
     #[error("io error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -41,6 +78,15 @@ pub enum AppError {
 
     #[error("http header parse error: {0}")]
     HttpHeaderError(#[from] axum::http::header::InvalidHeaderValue),
+
+    #[error("multipart error: {0}")]
+    MultipartError(#[from] axum::extract::multipart::MultipartError),
+}
+
+impl From<RateLimited> for AppError {
+    fn from(e: RateLimited) -> Self {
+        Self::TooManyRequests(e)
+    }
 }
 
 impl ErrorOutput {
@@ -59,13 +105,35 @@ impl IntoResponse for AppError {
             Self::AnyError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::HttpHeaderError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Self::EmailAlreadyExists(_) => StatusCode::CONFLICT,
+            Self::WorkspaceAlreadyExists(_) => StatusCode::CONFLICT,
+            Self::UpdateWorkspaceError(_) => StatusCode::BAD_REQUEST,
+            Self::MfaError(_) => StatusCode::BAD_REQUEST,
+            Self::InviteError(_) => StatusCode::BAD_REQUEST,
             Self::CreateChatError(_) => StatusCode::BAD_REQUEST,
+            Self::UpdateChatError(_) => StatusCode::BAD_REQUEST,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
             Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::CreateMessageError(_) => StatusCode::BAD_REQUEST,
+            Self::UpdateMessageError(_) => StatusCode::BAD_REQUEST,
+            Self::SearchError(_) => StatusCode::BAD_REQUEST,
             Self::ChatFileError(_) => StatusCode::BAD_REQUEST,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::QuotaExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::MultipartError(e) => e.status(),
         };
 
-        (status, Json(ErrorOutput::new(self.to_string()))).into_response()
+        let retry_after = match &self {
+            Self::TooManyRequests(e) => Some(e.retry_after_secs()),
+            _ => None,
+        };
+        let mut res = (status, Json(ErrorOutput::new(self.to_string()))).into_response();
+        if let Some(secs) = retry_after {
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        res
     }
 }
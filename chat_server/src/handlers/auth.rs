@@ -1,25 +1,62 @@
 use crate::{
-    models::{CreateUser, SigninUser},
-    AppError, AppState, ErrorOutput,
+    models::{
+        CreateUser, ForgotPassword, MfaChallenge, MfaCode, MfaEnrollment, MfaSignin, RecoveryCodes,
+        RefreshTokenInput, ResetPassword, SigninUser, SignoutInput, VerifyEmail,
+    },
+    AppError, AppState, ErrorOutput, RefreshToken,
 };
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use chat_core::{JwkSet, TokenClaims, User};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 use utoipa::ToSchema;
 
 #[derive(Debug, ToSchema, Serialize, Deserialize)]
 pub struct AuthOutput {
+    /// short-lived access token
     token: String,
+    expires_at: DateTime<Utc>,
+    /// opaque token to get a new access token from /api/refresh, single use
+    refresh_token: String,
+    refresh_expires_at: DateTime<Utc>,
+}
+
+/// Returned by signup when the user joins the workspace only after verifying the email.
+#[derive(Debug, ToSchema, Serialize, Deserialize)]
+pub struct SignupPending {
+    message: String,
+}
+
+impl AuthOutput {
+    pub(crate) fn new(
+        state: &AppState,
+        user: User,
+        refresh: RefreshToken,
+    ) -> Result<Self, AppError> {
+        let token = state.ek.sign(user)?;
+        Ok(Self {
+            token,
+            expires_at: Utc::now() + Duration::seconds(state.ek.ttl() as i64),
+            refresh_token: refresh.token,
+            refresh_expires_at: refresh.expires_at,
+        })
+    }
 }
 /// Create a new user in the chat system with email and password.
 ///
 /// - If the email already exists, it will return 409.
 /// - Otherwise, it will return 201 with a token.
 /// - If the workspace doesn't exist, it will create one.
+/// - A mail is sent to verify the email address.
+/// - Joining through the allowed email domain returns 202 without a token,
+///   the user joins the workspace once the email is verified.
 #[utoipa::path(
     post,
     path = "/api/signup",
     responses(
-        (status = 200, description = "User created", body = AuthOutput),
+        (status = 201, description = "User created", body = AuthOutput),
+        (status = 202, description = "User created, verify the email to join", body = SignupPending),
     )
 )]
 pub(crate) async fn signup_handler(
@@ -27,28 +64,59 @@ pub(crate) async fn signup_handler(
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.create_user(&input).await?;
-    let token = state.ek.sign(user)?;
-    let body = Json(AuthOutput { token });
-    Ok((StatusCode::CREATED, body))
+    // the account works without a verified email, a failed mail can be resent
+    if let Err(e) = state.send_verification_email(&user).await {
+        warn!("failed to send verification mail to {}: {}", user.email, e);
+    }
+    let Some(user) = state.signin_workspace(user).await? else {
+        let body = Json(SignupPending {
+            message: "Verify your email to join the workspace".to_string(),
+        });
+        return Ok((StatusCode::ACCEPTED, body).into_response());
+    };
+    let refresh = state
+        .create_refresh_token(user.id as _, user.ws_id as _)
+        .await?;
+    let body = Json(AuthOutput::new(&state, user, refresh)?);
+    Ok((StatusCode::CREATED, body).into_response())
 }
 
+/// Sign in with email and password.
+///
+/// - Users with MFA enabled get 202 with a challenge token, submit it with a code to /api/signin/mfa.
+/// - Repeated failures lock the email with an increasing delay.
 #[utoipa::path(
     post,
     path = "/api/signin",
     responses(
         (status = 200, description = "User signed in", body = AuthOutput),
+        (status = 202, description = "MFA code required", body = MfaChallenge),
+        (status = 429, description = "Too many attempts, see the Retry-After header", body = ErrorOutput),
     )
 )]
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
     Json(input): Json<SigninUser>,
 ) -> Result<impl IntoResponse, AppError> {
+    // per email on top of the per ip limit, so a botnet can't go after one account
+    state
+        .limiters
+        .signin
+        .check(&input.email.to_lowercase())
+        .await?;
     let user = state.verify_user(&input).await?;
 
     match user {
+        Some(user) if user.mfa_enabled => {
+            let body = Json(state.create_mfa_challenge(user.id as _).await?);
+            Ok((StatusCode::ACCEPTED, body).into_response())
+        }
         Some(user) => {
-            let token = state.ek.sign(user)?;
-            Ok((StatusCode::OK, Json(AuthOutput { token })).into_response())
+            let refresh = state
+                .create_refresh_token(user.id as _, user.ws_id as _)
+                .await?;
+            let body = Json(AuthOutput::new(&state, user, refresh)?);
+            Ok((StatusCode::OK, body).into_response())
         }
         None => {
             let body = Json(ErrorOutput::new("Invalid email or password"));
@@ -57,30 +125,346 @@ pub(crate) async fn signin_handler(
     }
 }
 
+/// Finish signin with the challenge token and a TOTP or recovery code.
+///
+/// - The challenge expires after 5 minutes or 5 wrong codes.
+#[utoipa::path(
+    post,
+    path = "/api/signin/mfa",
+    responses(
+        (status = 200, description = "User signed in", body = AuthOutput),
+        (status = 401, description = "Invalid code or challenge", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn mfa_signin_handler(
+    State(state): State<AppState>,
+    Json(input): Json<MfaSignin>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.verify_mfa_challenge(&input).await?;
+    let refresh = state
+        .create_refresh_token(user.id as _, user.ws_id as _)
+        .await?;
+    let body = Json(AuthOutput::new(&state, user, refresh)?);
+    Ok((StatusCode::OK, body))
+}
+
+/// Exchange a refresh token for a new access token and refresh token.
+///
+/// - Each refresh token can be used only once.
+/// - Reusing a refresh token revokes all tokens issued from the same signin.
+#[utoipa::path(
+    post,
+    path = "/api/refresh",
+    responses(
+        (status = 200, description = "Tokens refreshed", body = AuthOutput),
+        (status = 401, description = "Invalid, expired or reused refresh token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RefreshTokenInput>,
+) -> Result<impl IntoResponse, AppError> {
+    let (user, refresh) = state.rotate_refresh_token(&input.refresh_token).await?;
+    let body = Json(AuthOutput::new(&state, user, refresh)?);
+    Ok((StatusCode::OK, body))
+}
+
+/// Sign out the current session, the access token (and refresh token if given) stop working.
+#[utoipa::path(
+    post,
+    path = "/api/signout",
+    request_body(content = Option<SignoutInput>),
+    responses(
+        (status = 204, description = "Signed out"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn signout_handler(
+    Extension(claims): Extension<TokenClaims>,
+    State(state): State<AppState>,
+    input: Option<Json<SignoutInput>>,
+) -> Result<impl IntoResponse, AppError> {
+    let input = input.map(|Json(v)| v).unwrap_or_default();
+    state.signout(&claims, input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sign out everywhere, all tokens issued to the user so far stop working.
+#[utoipa::path(
+    post,
+    path = "/api/signout/all",
+    responses(
+        (status = 204, description = "Signed out from all sessions"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn signout_all_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.signout_all(user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Mail a password reset link.
+///
+/// - Always returns 202, whether an account exists for the email or not.
+#[utoipa::path(
+    post,
+    path = "/api/password/forgot",
+    responses(
+        (status = 202, description = "Reset link sent if the account exists"),
+    )
+)]
+pub(crate) async fn forgot_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ForgotPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Err(e) = state.request_password_reset(&input.email).await {
+        warn!(
+            "failed to send password reset mail to {}: {}",
+            input.email, e
+        );
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Set a new password with the token from the reset mail.
+///
+/// - The token can be used only once and expires after an hour.
+/// - All sessions of the user are signed out.
+#[utoipa::path(
+    post,
+    path = "/api/password/reset",
+    responses(
+        (status = 204, description = "Password changed"),
+        (status = 403, description = "Invalid or expired token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn reset_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ResetPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.reset_password(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Verify the email address with the token from the verification mail.
+#[utoipa::path(
+    post,
+    path = "/api/email/verify",
+    responses(
+        (status = 204, description = "Email verified"),
+        (status = 403, description = "Invalid or expired token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn verify_email_handler(
+    State(state): State<AppState>,
+    Json(input): Json<VerifyEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    state.verify_email(&input.token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Send the verification mail again, nothing is sent if the email is verified already.
+#[utoipa::path(
+    post,
+    path = "/api/email/verify/resend",
+    responses(
+        (status = 202, description = "Verification mail sent"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn resend_verification_email_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.send_verification_email(&user).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Start MFA enrollment, add the secret to an authenticator app.
+#[utoipa::path(
+    post,
+    path = "/api/mfa/enroll",
+    responses(
+        (status = 200, description = "TOTP secret", body = MfaEnrollment),
+        (status = 400, description = "MFA already enabled", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn enroll_mfa_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let enrollment = state.enroll_mfa(&user).await?;
+    Ok(Json(enrollment))
+}
+
+/// Enable MFA with a code from the authenticator app.
+///
+/// - Returns recovery codes, they are not shown again.
+#[utoipa::path(
+    post,
+    path = "/api/mfa/enable",
+    responses(
+        (status = 200, description = "MFA enabled", body = RecoveryCodes),
+        (status = 400, description = "Invalid code", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn enable_mfa_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<MfaCode>,
+) -> Result<impl IntoResponse, AppError> {
+    let codes = state.enable_mfa(user.id as _, &input.code).await?;
+    Ok(Json(codes))
+}
+
+/// Disable MFA, it requires a TOTP or recovery code.
+#[utoipa::path(
+    post,
+    path = "/api/mfa/disable",
+    responses(
+        (status = 204, description = "MFA disabled"),
+        (status = 400, description = "Invalid code", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn disable_mfa_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<MfaCode>,
+) -> Result<impl IntoResponse, AppError> {
+    state.disable_mfa(user.id as _, &input.code).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Public keys to verify access tokens with, in JWKS format.
+///
+/// - Retired keys stay listed until they are removed from the config.
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    responses(
+        (status = 200, description = "Token verification keys", body = JwkSet),
+    )
+)]
+pub(crate) async fn jwks_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.dk.jwks())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::CreateInvite;
     use anyhow::Result;
+    use chat_core::WorkspaceRole;
     use http_body_util::BodyExt;
 
     #[tokio::test]
     async fn signup_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("acme", "Tian Chen", "tyr@acme.org", "123456");
-        let ret = signup_handler(State(state), Json(input))
+        let input = CreateUser::new("new", "Tian Chen", "tyr@acme.org", "123456");
+        let ret = signup_handler(State(state.clone()), Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::CREATED);
         let body = ret.into_body().collect().await?.to_bytes();
         let ret: AuthOutput = serde_json::from_slice(&body)?;
         assert_ne!(ret.token, "");
+
+        let mails = state.sent_mails("tyr@acme.org").await?;
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].subject, "Verify your email");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signup_existing_workspace_without_invite_should_403() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("acm", "Tian Chen", "tyr@acme.org", "123456");
+        let ret = signup_handler(State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(
+            ret.error,
+            "permission denied: workspace acm requires an invite"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signup_with_allowed_domain_should_wait_for_verification() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE workspaces SET allowed_domain = 'acme.org' WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let input = CreateUser::new("acm", "Tian Chen", "tyr@acme.org", "123456");
+        let ret = signup_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::ACCEPTED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: SignupPending = serde_json::from_slice(&body)?;
+        assert_eq!(ret.message, "Verify your email to join the workspace");
+
+        let mails = state.sent_mails("tyr@acme.org").await?;
+        assert_eq!(mails.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signup_with_invite_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let invite = CreateInvite::new("tyr@acme.org", Some(WorkspaceRole::Admin));
+        let invite = state.create_invite(1, 1, invite).await?;
+
+        // the workspace name is taken from the invite
+        let mut input = CreateUser::new("ignored", "Tian Chen", "tyr@acme.org", "123456");
+        input.invite = Some(invite.token.clone());
+        let ret = signup_handler(State(state.clone()), Json(input.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        let user = state.dk.verify(&ret.token)?;
+        assert_eq!(user.ws_id, 1);
+        assert_eq!(
+            state.get_workspace_role(1, user.id as _).await?,
+            WorkspaceRole::Admin
+        );
+
+        // an invite can only be used once
+        input.email = "tyr2@acme.org".to_string();
+        let ret = signup_handler(State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
         Ok(())
     }
 
     #[tokio::test]
     async fn signup_duplicate_user_should_409() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("acme", "Tyr Chen", "tchen@acme.org", "123456");
+        let input = CreateUser::new("acm", "Eli Shi", "elixy@qq.com", "123456");
 
         let ret = signup_handler(State(state), Json(input))
             .await
@@ -89,14 +473,14 @@ mod tests {
         let body = ret.into_body().collect().await?.to_bytes();
         let ret: ErrorOutput = serde_json::from_slice(&body)?;
 
-        assert_eq!(ret.error, "email already exists: tchen@acme.org");
+        assert_eq!(ret.error, "email already exists: elixy@qq.com");
         Ok(())
     }
 
     #[tokio::test]
     async fn signin_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let email = "tchen@acme.org";
+        let email = "elixy@qq.com";
         let password = "123456";
         let input = SigninUser::new(email, password);
         let ret = signin_handler(State(state), Json(input))
@@ -110,6 +494,100 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn signin_with_mfa_should_require_code() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query(
+            "INSERT INTO user_mfa (user_id, secret, enabled_at) VALUES (1, 'JBSWY3DPEHPK3PXP', now())",
+        )
+        .execute(&state.pool)
+        .await?;
+        let input = SigninUser::new("elixy@qq.com", "123456");
+        let ret = signin_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::ACCEPTED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let challenge: MfaChallenge = serde_json::from_slice(&body)?;
+
+        let input = MfaSignin {
+            mfa_token: challenge.mfa_token,
+            code: "12345-abcde".to_string(),
+        };
+        let ret = mfa_signin_handler(State(state), Json(input)).await;
+        assert!(matches!(ret, Err(AppError::Unauthorized(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_lockout_should_return_429() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = SigninUser::new("elixy@qq.com", "wrong");
+        for _ in 0..5 {
+            let ret = signin_handler(State(state.clone()), Json(input.clone()))
+                .await?
+                .into_response();
+            assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        }
+        let ret = signin_handler(State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(ret.headers().get("retry-after").unwrap(), "30");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = SigninUser::new("elixy@qq.com", "123456");
+        let ret = signin_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let auth: AuthOutput = serde_json::from_slice(&body)?;
+        assert!(auth.refresh_expires_at > auth.expires_at);
+
+        let input = RefreshTokenInput {
+            refresh_token: auth.refresh_token.clone(),
+        };
+        let ret = refresh_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert_ne!(ret.refresh_token, auth.refresh_token);
+        assert_eq!(state.dk.verify(&ret.token)?.id, 1);
+
+        // replaying the old refresh token fails
+        let input = RefreshTokenInput {
+            refresh_token: auth.refresh_token,
+        };
+        let ret = refresh_handler(State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn jwks_should_verify_issued_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = jwks_handler(State(state.clone())).await.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let jwks: JwkSet = serde_json::from_slice(&body)?;
+        assert_eq!(jwks.keys.len(), 1);
+        assert_eq!(jwks.keys[0].kid, state.ek.kid());
+
+        let dk = chat_core::DecodingKey::from_jwks(&jwks)?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let token = state.ek.sign(user)?;
+        assert_eq!(dk.verify(&token)?.id, 1);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn signin_with_non_exist_user_should_403() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -1,17 +1,20 @@
-use crate::{AppError, AppState, CreateChat, ErrorOutput};
+use crate::{
+    AppError, AppState, ChatSummary, CreateChat, ErrorOutput, MarkChatRead, UpdateChat,
+    UpdateChatMember,
+};
 use axum::{
     extract::{Path, State},
     http::StatusCode,
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::{Chat, User};
+use chat_core::{Chat, ChatMember, User};
 
 #[utoipa::path(
     get,
     path = "/api/chats",
     responses(
-        (status = 200, description = "List of chats", body = Vec<Chat>),
+        (status = 200, description = "List of chats", body = Vec<ChatSummary>),
     ),
     security(
         ("token" = [])
@@ -21,7 +24,9 @@ pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.fetch_chats(user.id as _, user.ws_id as _).await?;
+    let chat = state
+        .fetch_chat_summaries(user.id as _, user.ws_id as _)
+        .await?;
     Ok((StatusCode::OK, Json(chat)))
 }
 
@@ -71,12 +76,231 @@ pub(crate) async fn get_chat_handler(
     }
 }
 
-// TODO: finish this as a homework
-pub(crate) async fn update_chat_handler() -> impl IntoResponse {
-    "update chat"
+/// Update a chat's name, visibility or members.
+///
+/// - Omitted fields keep their current value.
+/// - The chat type is recomputed from the resulting name, members and visibility.
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Chat updated", body = Chat),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.update_chat(id, input, user.id as _).await?;
+    Ok(Json(chat))
+}
+
+/// Archive a chat, it is hidden from the chat list and no longer accepts messages.
+///
+/// - Only the chat creator or the workspace owner can archive a chat.
+/// - Messages are kept, the chat can be restored later.
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Chat archived", body = Chat),
+        (status = 403, description = "Permission denied", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.archive_chat(id, &user).await?;
+    Ok(Json(chat))
+}
+
+/// Restore an archived chat, only the chat creator or the workspace owner can do it.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/restore",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Chat restored", body = Chat),
+        (status = 403, description = "Permission denied", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn restore_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.restore_chat(id, &user).await?;
+    Ok(Json(chat))
+}
+
+/// Mark the chat as read up to a message, or up to the latest one if none given.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/read",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    request_body(content = Option<MarkChatRead>),
+    responses(
+        (status = 200, description = "Read position of the user", body = ChatMember),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn mark_chat_read_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    input: Option<Json<MarkChatRead>>,
+) -> Result<impl IntoResponse, AppError> {
+    let input = input.map(|Json(v)| v).unwrap_or_default();
+    let member = state.mark_chat_read(input, id, user.id as _).await?;
+    Ok(Json(member))
+}
+
+/// List the public channels of the user's workspace, joined or not.
+#[utoipa::path(
+    get,
+    path = "/api/chats/discover",
+    responses(
+        (status = 200, description = "List of public channels", body = Vec<Chat>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn discover_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .verify_not_guest(user.ws_id as _, user.id as _)
+        .await?;
+    let chats = state.fetch_public_chats(user.ws_id as _).await?;
+    Ok(Json(chats))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/join",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Chat joined", body = Chat),
+        (status = 403, description = "Chat is not a public channel", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn join_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.join_chat(id, &user).await?;
+    Ok(Json(chat))
 }
 
-// TODO: finish this as a homework
-pub(crate) async fn delete_chat_handler() -> impl IntoResponse {
-    "delete chat"
+/// Leave a chat, single chats can't be left and the owner must stay.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/leave",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 204, description = "Chat left"),
+        (status = 400, description = "Chat can not be left", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn leave_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.leave_chat(id, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/members",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "List of chat members", body = Vec<ChatMember>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_chat_members_handler(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let members = state.fetch_chat_members(id).await?;
+    Ok(Json(members))
+}
+
+/// Change the role of a chat member, only the chat owner can do it.
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}/members/{user_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("user_id" = u64, Path, description = "Member user id")
+    ),
+    responses(
+        (status = 200, description = "Chat member updated", body = ChatMember),
+        (status = 403, description = "Permission denied", body = ErrorOutput),
+        (status = 404, description = "Chat member not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_chat_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, member_id)): Path<(u64, u64)>,
+    Json(input): Json<UpdateChatMember>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state
+        .update_chat_member_role(id, member_id, input.role, user.id as _)
+        .await?;
+    Ok(Json(member))
 }
@@ -1,14 +1,27 @@
 use axum::{
-    extract::{Multipart, Path, Query, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    body::Body,
+    extract::{multipart::Field, Multipart, Path, Query, State},
+    http::{
+        header::{
+            ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+            IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+        },
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
     Extension, Json,
 };
-use tokio::fs;
+use sha1::{Digest, Sha1};
+use tokio::{fs, io::AsyncWriteExt};
 use tracing::{info, warn};
+use uuid::Uuid;
 
-use crate::{AppError, AppState, ChatFile, CreateMessage, ErrorOutput, ListMessages};
-use chat_core::{Message, User};
+use crate::{
+    AppError, AppState, ChatFile, CreateMessage, CreateReaction, ErrorOutput, FileSignature,
+    ListMessages, SearchMessages, SearchResult, SignFileUrl, SignedFileUrl, UpdateMessage,
+    UploadLimits,
+};
+use chat_core::{FileInfo, Message, MessageEdit, Reaction, User};
 
 #[utoipa::path(
     post,
@@ -23,7 +36,8 @@ use chat_core::{Message, User};
     security(
         ("token" = [])
     )
-)]pub(crate) async fn send_message_handler(
+)]
+pub(crate) async fn send_message_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
@@ -34,7 +48,6 @@ use chat_core::{Message, User};
     Ok((StatusCode::CREATED, Json(msg)))
 }
 
-
 #[utoipa::path(
     get,
     path = "/api/chats/{id}/messages",
@@ -52,11 +65,220 @@ use chat_core::{Message, User};
     )
 )]
 pub(crate) async fn list_message_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
     Query(input): Query<ListMessages>,
 ) -> Result<impl IntoResponse, AppError> {
-    let messages = state.list_messages(input, id).await?;
+    let messages = state.list_messages(input, id, user.id as _).await?;
+    Ok(Json(messages))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/messages/{msg_id}/thread",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id"),
+        ListMessages
+    ),
+    responses(
+        (status = 200, description = "List of thread replies", body = Vec<Message>),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_thread_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state
+        .list_thread_messages(input, id, msg_id, user.id as _)
+        .await?;
+    Ok(Json(messages))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}/messages/{msg_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id")
+    ),
+    responses(
+        (status = 200, description = "Message updated", body = Message),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 403, description = "Not the sender of the message", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+    Json(input): Json<UpdateMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let msg = state
+        .update_message(input, id, msg_id, user.id as _)
+        .await?;
+    Ok(Json(msg))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/messages/{msg_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id")
+    ),
+    responses(
+        (status = 200, description = "Message deleted", body = Message),
+        (status = 403, description = "Not the sender of the message", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let msg = state.delete_message(id, msg_id, user.id as _).await?;
+    Ok(Json(msg))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/messages/{msg_id}/edits",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id")
+    ),
+    responses(
+        (status = 200, description = "Prior revisions of the message", body = Vec<MessageEdit>),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_message_edits_handler(
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let edits = state.list_message_edits(id, msg_id).await?;
+    Ok(Json(edits))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/messages/{msg_id}/reactions",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id")
+    ),
+    responses(
+        (status = 201, description = "Reactions of the message", body = Vec<Reaction>),
+        (status = 400, description = "Invalid emoji", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn add_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+    Json(input): Json<CreateReaction>,
+) -> Result<impl IntoResponse, AppError> {
+    let reactions = state.add_reaction(input, id, msg_id, user.id as _).await?;
+    Ok((StatusCode::CREATED, Json(reactions)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/messages/{msg_id}/reactions",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id"),
+        CreateReaction
+    ),
+    responses(
+        (status = 200, description = "Reactions of the message", body = Vec<Reaction>),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn remove_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+    Query(input): Query<CreateReaction>,
+) -> Result<impl IntoResponse, AppError> {
+    let reactions = state
+        .remove_reaction(input, id, msg_id, user.id as _)
+        .await?;
+    Ok(Json(reactions))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/search",
+    params(
+        SearchMessages
+    ),
+    responses(
+        (status = 200, description = "Matching messages, newest first", body = Vec<SearchResult>),
+        (status = 400, description = "Invalid query", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn search_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<SearchMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let results = state
+        .search_messages(input, user.id as _, user.ws_id as _)
+        .await?;
+    Ok(Json(results))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/mentions",
+    params(
+        ListMessages
+    ),
+    responses(
+        (status = 200, description = "Messages mentioning the user, newest first", body = Vec<Message>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_mentions_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state
+        .list_mentions(input, user.id as _, user.ws_id as _)
+        .await?;
     Ok(Json(messages))
 }
 
@@ -64,51 +286,688 @@ pub(crate) async fn file_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path((ws_id, path)): Path<(i64, String)>,
-) -> Result<impl IntoResponse, AppError> {
-    if user.ws_id != ws_id {
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let file = parse_file_path(ws_id, &path)?;
+    if user.ws_id != ws_id || !state.can_access_file(&file, user.id as _).await? {
         return Err(AppError::NotFound(
             "File doesn't exist or you don't have permission".to_string(),
         ));
     }
-    let base_dir = state.config.server.base_dir.join(ws_id.to_string());
-    let path = base_dir.join(path);
-    if !path.exists() {
+    serve_file(&state, &file, &headers).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/signed/files",
+    responses(
+        (status = 200, description = "Download url that works without a token", body = SignedFileUrl),
+        (status = 404, description = "File not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn sign_file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<SignFileUrl>,
+) -> Result<impl IntoResponse, AppError> {
+    let signed = state
+        .sign_file_url(&input, user.id as _, user.ws_id as _)
+        .await?;
+    Ok(Json(signed))
+}
+
+/// Download a file with a signed url instead of a token.
+pub(crate) async fn signed_file_handler(
+    State(state): State<AppState>,
+    Path((ws_id, path)): Path<(i64, String)>,
+    Query(signature): Query<FileSignature>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let file = parse_file_path(ws_id, &path)?;
+    if !state.verify_file_signature(&file, &signature) {
+        return Err(AppError::PermissionDenied(
+            "invalid or expired signature".to_string(),
+        ));
+    }
+    // the url stops working once the chat or message sharing the file is deleted
+    if !state.is_file_in_chat(&file).await? {
         return Err(AppError::NotFound("File doesn't exist".to_string()));
     }
+    serve_file(&state, &file, &headers).await
+}
+
+// only paths of uploaded files are served, nothing else under base_dir
+fn parse_file_path(ws_id: i64, path: &str) -> Result<ChatFile, AppError> {
+    match format!("/files/{}/{}", ws_id, path).parse::<ChatFile>() {
+        Ok(file) if is_sha1_hex(&file.hash) => Ok(file),
+        _ => Err(AppError::NotFound("File doesn't exist".to_string())),
+    }
+}
+
+async fn serve_file(
+    state: &AppState,
+    file: &ChatFile,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let key = file.key();
+    let Some(meta) = state.storage.stat(&key).await? else {
+        return Err(AppError::NotFound("File doesn't exist".to_string()));
+    };
+
+    // files are content addressed, the hash never changes for the same url
+    let etag = format!("\"{}\"", file.hash);
+    let last_modified = meta
+        .last_modified
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+    let mut res_headers = HeaderMap::new();
+    res_headers.insert(ETAG, etag.parse()?);
+    res_headers.insert(LAST_MODIFIED, last_modified.parse()?);
+    res_headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=31536000, immutable"),
+    );
+    res_headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    if let Some(value) = headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        if etag_matches(value, &etag) {
+            return Ok((StatusCode::NOT_MODIFIED, res_headers).into_response());
+        }
+    }
+
+    let len = meta.size;
+    let (status, start, end) = match byte_range(headers, &etag, len) {
+        ByteRange::Full => (StatusCode::OK, 0, len),
+        ByteRange::Partial(start, end) => {
+            let range = format!("bytes {}-{}/{}", start, end, len);
+            res_headers.insert(CONTENT_RANGE, range.parse()?);
+            (StatusCode::PARTIAL_CONTENT, start, end + 1)
+        }
+        ByteRange::Unsatisfiable => {
+            res_headers.insert(CONTENT_RANGE, format!("bytes */{}", len).parse()?);
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, res_headers).into_response());
+        }
+    };
 
-    let mime = mime_guess::from_path(&path).first_or_octet_stream();
-    // TODO: streaming
-    let body = fs::read(path).await?;
-    let mut headers = HeaderMap::new();
-    headers.insert("content-type", mime.to_string().parse().unwrap());
-    Ok((headers, body))
+    let mime = mime_guess::from_path(&key).first_or_octet_stream();
+    res_headers.insert(CONTENT_TYPE, mime.to_string().parse()?);
+    res_headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+    let body = Body::from_stream(state.storage.read(&key, start, end).await?);
+    Ok((status, res_headers, body).into_response())
 }
 
 pub(crate) async fn upload_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
-    mut multipart: Multipart,
+    multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
+    let limits = UploadLimits::from(&state.config.server);
+    let files = save_files(&state, &user, multipart, limits).await?;
+    Ok(Json(files))
+}
+
+// stream every file of the upload to disk, files already stored aren't written or counted again
+async fn save_files(
+    state: &AppState,
+    user: &User,
+    mut multipart: Multipart,
+    limits: UploadLimits,
+) -> Result<Vec<FileInfo>, AppError> {
     let ws_id = user.ws_id as u64;
-    let base_dir = &state.config.server.base_dir;
+    // uploads are staged locally whatever the storage is, to hash them before storing
+    let tmp_dir = state.config.server.base_dir.join("tmp");
+    fs::create_dir_all(&tmp_dir).await?;
+
+    let mut upload = Upload {
+        limits,
+        received: 0,
+        used: state.workspace_storage_used(ws_id).await?,
+    };
     let mut files = vec![];
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let filename = field.file_name().map(|name| name.to_string());
-        let (Some(filename), Ok(data)) = (filename, field.bytes().await) else {
-            warn!("Failed to read multipart field");
-            continue;
+    while let Some(field) = multipart.next_field().await? {
+        // every byte of the body counts against the upload limit, so only files are accepted
+        let Some(filename) = field.file_name().map(|name| name.to_string()) else {
+            return Err(AppError::ChatFileError(format!(
+                "multipart field {} is not a file",
+                field.name().unwrap_or_default()
+            )));
+        };
+        let content_type = match field.content_type() {
+            Some(content_type) => content_type.to_string(),
+            None => mime_guess::from_path(&filename)
+                .first_or_octet_stream()
+                .to_string(),
         };
 
-        let file = ChatFile::new(ws_id, &filename, &data);
-        let path = file.path(base_dir);
-        if path.exists() {
-            info!("File {} already exists: {:?}", filename, path);
+        let tmp = tmp_dir.join(Uuid::now_v7().to_string());
+        let (hash, size) = match upload.write_field(field, &filename, &tmp).await {
+            Ok(ret) => ret,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp).await;
+                return Err(e);
+            }
+        };
+
+        let file = ChatFile::from_hash(ws_id, &filename, hash);
+        let key = file.key();
+        if state.storage.exists(&key).await? {
+            info!("File {} already exists: {}", filename, key);
+            fs::remove_file(&tmp).await?;
         } else {
-            fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
-            fs::write(path, data).await?;
+            let stored = match state
+                .reserve_workspace_storage(ws_id, size, limits.quota)
+                .await
+            {
+                Ok(used) => match state.storage.put_file(&key, &tmp).await {
+                    Ok(()) => {
+                        upload.used = used;
+                        Ok(())
+                    }
+                    Err(e) => {
+                        // nothing was stored, the bytes must not count against the quota
+                        if let Err(e) = state.release_workspace_storage(ws_id, size).await {
+                            warn!(
+                                "Failed to release {} bytes of workspace {}: {}",
+                                size, ws_id, e
+                            );
+                        }
+                        Err(e.into())
+                    }
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = stored {
+                let _ = fs::remove_file(&tmp).await;
+                return Err(e);
+            }
         }
-        files.push(file.url());
+        let info = state
+            .create_file_info(&file, &filename, size, &content_type, user.id as _)
+            .await?;
+        files.push(info);
     }
 
-    Ok(Json(files))
+    Ok(files)
+}
+
+struct Upload {
+    limits: UploadLimits,
+    // bytes of all fields so far
+    received: u64,
+    // bytes stored by the workspace
+    used: u64,
+}
+
+impl Upload {
+    // write the field to `path` chunk by chunk, returns its sha1 and size
+    async fn write_field(
+        &mut self,
+        mut field: Field<'_>,
+        filename: &str,
+        path: &std::path::Path,
+    ) -> Result<(String, u64), AppError> {
+        let mut f = fs::File::create(path).await?;
+        let mut hasher = Sha1::new();
+        let mut size = 0;
+        while let Some(chunk) = field.chunk().await? {
+            size += chunk.len() as u64;
+            self.received += chunk.len() as u64;
+            if size > self.limits.max_file_size {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "file {} is larger than {} bytes",
+                    filename, self.limits.max_file_size
+                )));
+            }
+            if self.received > self.limits.max_upload_size {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "upload is larger than {} bytes",
+                    self.limits.max_upload_size
+                )));
+            }
+            // checked again when the file is stored, this only stops early
+            if self.used + size > self.limits.quota {
+                return Err(AppError::QuotaExceeded {
+                    used: self.used,
+                    quota: self.limits.quota,
+                });
+            }
+            hasher.update(&chunk);
+            f.write_all(&chunk).await?;
+        }
+        f.flush().await?;
+        Ok((hex::encode(hasher.finalize()), size))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// first and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+fn is_sha1_hex(hash: &str) -> bool {
+    hash.len() == 40 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// `If-None-Match` is a list of etags or `*`, weak etags compare equal
+fn etag_matches(value: &str, etag: &str) -> bool {
+    value
+        .split(',')
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v.trim_start_matches("W/") == etag)
+}
+
+// only a single `bytes=` range is supported, anything else gets the whole file
+fn byte_range(headers: &HeaderMap, etag: &str, len: u64) -> ByteRange {
+    let Some(range) = headers.get(RANGE).and_then(|v| v.to_str().ok()) else {
+        return ByteRange::Full;
+    };
+    // the client's copy is stale, send it the current file instead of a piece
+    if let Some(if_range) = headers.get(IF_RANGE) {
+        if if_range.as_bytes() != etag.as_bytes() {
+            return ByteRange::Full;
+        }
+    }
+    let Some((start, end)) = range
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|r| !r.contains(','))
+        .and_then(|r| r.split_once('-'))
+    else {
+        return ByteRange::Full;
+    };
+
+    match (start.trim(), end.trim()) {
+        // the last `suffix` bytes
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+            Err(_) => ByteRange::Full,
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return ByteRange::Full;
+            };
+            let end = match end {
+                "" => u64::MAX,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return ByteRange::Full,
+                },
+            };
+            if start >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(start, end.min(len - 1))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{ByteStream, ObjectMeta, Storage};
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use axum::extract::{FromRequest, Request};
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+
+    async fn upload(state: &AppState, ws_id: u64, data: &[u8]) -> Result<ChatFile> {
+        let file = ChatFile::new(ws_id, "test.txt", data);
+        let path = file.path(&state.config.server.base_dir);
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        fs::write(path, data).await?;
+        state
+            .create_file_info(&file, "test.txt", data.len() as _, "text/plain", 1)
+            .await?;
+        Ok(file)
+    }
+
+    async fn get_file(
+        state: &AppState,
+        file: &ChatFile,
+        headers: &[(&'static str, &str)],
+    ) -> Result<Response> {
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let path = file.url();
+        let (_, path) = path.split_once(&format!("/files/{}/", file.ws_id)).unwrap();
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, value.parse()?);
+        }
+        let ret = file_handler(
+            Extension(user),
+            State(state.clone()),
+            Path((file.ws_id as _, path.to_string())),
+            map,
+        )
+        .await;
+        Ok(ret.into_response())
+    }
+
+    #[tokio::test]
+    async fn file_handler_should_stream_with_cache_headers() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = upload(&state, 1, b"file_handler_should_stream").await?;
+        let etag = format!("\"{}\"", file.hash);
+
+        let res = get_file(&state, &file, &[]).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[ETAG], etag.as_str());
+        assert_eq!(res.headers()[CONTENT_LENGTH], "26");
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(res.headers()[ACCEPT_RANGES], "bytes");
+        assert!(res.headers()[CACHE_CONTROL].to_str()?.contains("immutable"));
+        assert!(res.headers().contains_key(LAST_MODIFIED));
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], b"file_handler_should_stream");
+
+        let res = get_file(&state, &file, &[("if-none-match", &etag)]).await?;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[ETAG], etag.as_str());
+        let body = res.into_body().collect().await?.to_bytes();
+        assert!(body.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_handler_should_support_range() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = upload(&state, 1, b"0123456789").await?;
+
+        let res = get_file(&state, &file, &[("range", "bytes=2-5")]).await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(res.headers()[CONTENT_LENGTH], "4");
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], b"2345");
+
+        let res = get_file(&state, &file, &[("range", "bytes=-3")]).await?;
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], b"789");
+
+        let res = get_file(&state, &file, &[("range", "bytes=10-")]).await?;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes */10");
+
+        // a stale If-Range gets the whole file
+        let headers = [("range", "bytes=2-5"), ("if-range", "\"stale\"")];
+        let res = get_file(&state, &file, &headers).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_handler_should_reject_other_paths() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let ret = file_handler(
+            Extension(user),
+            State(state),
+            Path((1, "../../etc/passwd".to_string())),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_handler_should_check_chat_access() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = upload(&state, 1, Uuid::now_v7().as_bytes()).await?;
+        let path = file.url().replace("/files/1/", "");
+
+        // not attached to any message yet, only the uploader can see it
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let ret = file_handler(
+            Extension(user),
+            State(state.clone()),
+            Path((1, path.clone())),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // a signed url of a shared file works without a user
+        let input = CreateMessage {
+            content: "file".to_string(),
+            files: vec![file.url()],
+            reply_to: None,
+        };
+        state.create_message(input, 4, 1).await?;
+        let input = SignFileUrl {
+            url: file.url(),
+            expires_in: None,
+        };
+        let signed = state.sign_file_url(&input, 1, 1).await?;
+        let uri: axum::http::Uri = signed.url.parse()?;
+        let Query(signature) = Query::<FileSignature>::try_from_uri(&uri)?;
+        let res = signed_file_handler(
+            State(state.clone()),
+            Path((1, path.clone())),
+            Query(signature.clone()),
+            HeaderMap::new(),
+        )
+        .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let tampered = FileSignature {
+            expires: signature.expires + 3600,
+            ..signature.clone()
+        };
+        let ret = signed_file_handler(
+            State(state.clone()),
+            Path((1, path.clone())),
+            Query(tampered),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // deleting the chat revokes the url
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        state.archive_chat(4, &user).await?;
+        let ret = signed_file_handler(
+            State(state),
+            Path((1, path)),
+            Query(signature),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    fn multipart_request(files: &[(&str, &[u8])]) -> Result<Request> {
+        let mut body = Vec::new();
+        for (filename, data) in files {
+            body.extend_from_slice(
+                format!(
+                    "--BOUNDARY\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n",
+                    filename
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--BOUNDARY--\r\n");
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/upload")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY")
+            .body(Body::from(body))?;
+        Ok(req)
+    }
+
+    async fn upload_files(
+        state: &AppState,
+        files: &[(&str, &[u8])],
+        limits: UploadLimits,
+    ) -> Result<Result<Vec<FileInfo>, AppError>> {
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let multipart = Multipart::from_request(multipart_request(files)?, state).await?;
+        Ok(save_files(state, &user, multipart, limits).await)
+    }
+
+    const LIMITS: UploadLimits = UploadLimits {
+        max_file_size: 64,
+        max_upload_size: 100,
+        quota: 1000,
+    };
+
+    #[tokio::test]
+    async fn upload_should_stream_and_count_storage() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // files are shared by all test databases, keep the content unique
+        let data = Uuid::now_v7().to_string();
+        let files = upload_files(&state, &[("a.txt", data.as_bytes())], LIMITS).await??;
+        let file = ChatFile::new(1, "a.txt", data.as_bytes());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].url, file.url());
+        assert_eq!(files[0].original_name, "a.txt");
+        assert_eq!(files[0].size, 36);
+        assert_eq!(files[0].content_type, "text/plain");
+        assert_eq!(files[0].uploader_id, 1);
+        let stored = fs::read(file.path(&state.config.server.base_dir)).await?;
+        assert_eq!(stored, data.as_bytes());
+        assert_eq!(state.workspace_storage_used(1).await?, 36);
+
+        // the same content again isn't counted twice
+        upload_files(&state, &[("b.txt", data.as_bytes())], LIMITS).await??;
+        assert_eq!(state.workspace_storage_used(1).await?, 36);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_field_without_file_name_should_400() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let body = "--BOUNDARY\r\nContent-Disposition: form-data; name=\"padding\"\r\n\r\nxxxx\r\n--BOUNDARY--\r\n";
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/upload")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY")
+            .body(Body::from(body))?;
+        let multipart = Multipart::from_request(req, &state).await?;
+        let err = save_files(&state, &user, multipart, LIMITS)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "multipart field padding is not a file");
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_should_keep_traversal_filenames_in_base_dir() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let data = Uuid::now_v7().to_string();
+        let name = "x./../../../../../tmp/pwn";
+        let files = upload_files(&state, &[(name, data.as_bytes())], LIMITS).await??;
+        let file = ChatFile::new(1, name, data.as_bytes());
+        assert_eq!(files[0].url, file.url());
+        assert!(files[0].url.ends_with(".bin"));
+        assert!(file.path(&state.config.server.base_dir).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_over_limits_should_413() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let big = [b'x'; 65];
+        let ret = upload_files(&state, &[("big.txt", &big)], LIMITS).await?;
+        assert!(matches!(ret, Err(AppError::PayloadTooLarge(_))));
+
+        let small = [b'y'; 60];
+        let ret = upload_files(&state, &[("a.txt", &small), ("b.txt", &small)], LIMITS).await?;
+        let err = ret.unwrap_err();
+        assert!(matches!(err, AppError::PayloadTooLarge(_)));
+        assert_eq!(err.into_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let data = Uuid::now_v7().to_string();
+        let limits = UploadLimits {
+            quota: 10,
+            ..LIMITS
+        };
+        let ret = upload_files(&state, &[("a.txt", data.as_bytes())], limits).await?;
+        let err = ret.unwrap_err();
+        assert!(matches!(err, AppError::QuotaExceeded { quota: 10, .. }));
+        assert_eq!(err.into_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let file = ChatFile::new(1, "a.txt", data.as_bytes());
+        assert!(!file.path(&state.config.server.base_dir).exists());
+        Ok(())
+    }
+
+    struct FailingStorage;
+
+    #[async_trait]
+    impl Storage for FailingStorage {
+        async fn put_file(&self, _key: &str, _src: &std::path::Path) -> Result<()> {
+            anyhow::bail!("storage is down")
+        }
+
+        async fn stat(&self, _key: &str) -> Result<Option<ObjectMeta>> {
+            Ok(None)
+        }
+
+        async fn read(&self, _key: &str, _start: u64, _end: u64) -> Result<ByteStream> {
+            anyhow::bail!("storage is down")
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_upload_should_release_storage() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared yet")
+            .storage = Box::new(FailingStorage);
+
+        let data = Uuid::now_v7().to_string();
+        let ret = upload_files(&state, &[("a.txt", data.as_bytes())], LIMITS).await?;
+        assert!(ret.is_err());
+        assert_eq!(state.workspace_storage_used(1).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_malformed_multipart_should_400() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let req = Request::builder()
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY")
+            .body(Body::from("--BOUNDARY\r\ngarbage"))?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let multipart = Multipart::from_request(req, &state).await?;
+        let ret = save_files(&state, &user, multipart, LIMITS).await;
+        let err = ret.unwrap_err();
+        assert!(matches!(err, AppError::MultipartError(_)));
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[test]
+    fn byte_range_should_work() {
+        let range = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RANGE, value.parse().unwrap());
+            byte_range(&headers, "\"etag\"", 10)
+        };
+        assert_eq!(range("bytes=0-0"), ByteRange::Partial(0, 0));
+        assert_eq!(range("bytes=5-"), ByteRange::Partial(5, 9));
+        assert_eq!(range("bytes=5-100"), ByteRange::Partial(5, 9));
+        assert_eq!(range("bytes=-100"), ByteRange::Partial(0, 9));
+        assert_eq!(range("bytes=-0"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-1,4-5"), ByteRange::Full);
+        assert_eq!(range("bytes=5-2"), ByteRange::Full);
+        assert_eq!(range("items=0-1"), ByteRange::Full);
+        assert_eq!(byte_range(&HeaderMap::new(), "", 10), ByteRange::Full);
+    }
 }
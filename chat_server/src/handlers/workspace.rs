@@ -1,7 +1,15 @@
-use crate::{AppError, AppState};
-use axum::{extract::State, response::IntoResponse, Extension, Json};
-use chat_core::{ChatUser, User};
-
+use crate::{
+    handlers::AuthOutput, AcceptInvite, AppError, AppState, CreateInvite, ErrorOutput, Invite,
+    InviteOutput, TransferWorkspace, UpdateWorkspace, UpdateWorkspaceMember, UserWorkspace,
+    WorkspaceMember,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{ChatUser, User, Workspace};
 
 #[utoipa::path(
     get,
@@ -20,3 +28,296 @@ pub(crate) async fn list_chat_users_handler(
     let users = state.fetch_chat_users(user.ws_id as _).await?;
     Ok(Json(users))
 }
+
+/// List the workspaces the user is an active member of.
+#[utoipa::path(
+    get,
+    path = "/api/workspaces",
+    responses(
+        (status = 200, description = "Workspaces of the user", body = Vec<UserWorkspace>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_workspaces_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let workspaces = state.fetch_user_workspaces(user.id as _).await?;
+    Ok(Json(workspaces))
+}
+
+/// Switch to another workspace of the user.
+///
+/// - Returns new tokens scoped to the workspace, the old ones stay valid for the previous one.
+/// - The workspace becomes the default one on signin.
+#[utoipa::path(
+    post,
+    path = "/api/workspaces/{id}/switch",
+    params(
+        ("id" = u64, Path, description = "Workspace id")
+    ),
+    responses(
+        (status = 200, description = "Switched workspace", body = AuthOutput),
+        (status = 403, description = "Not a member of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn switch_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.switch_workspace(user.id as _, id).await?;
+    let refresh = state
+        .create_refresh_token(user.id as _, user.ws_id as _)
+        .await?;
+    let body = AuthOutput::new(&state, user, refresh)?;
+    Ok(Json(body))
+}
+
+/// Rename the workspace or change its settings, only the workspace owner can do this.
+#[utoipa::path(
+    patch,
+    path = "/api/workspace",
+    responses(
+        (status = 200, description = "Workspace updated", body = Workspace),
+        (status = 403, description = "Not the workspace owner", body = ErrorOutput),
+        (status = 409, description = "Workspace name already taken", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state
+        .update_workspace(user.ws_id as _, user.id as _, input)
+        .await?;
+    Ok(Json(ws))
+}
+
+/// List pending invites of the workspace.
+#[utoipa::path(
+    get,
+    path = "/api/invites",
+    responses(
+        (status = 200, description = "Pending invites", body = Vec<Invite>),
+        (status = 403, description = "Not a workspace owner or admin", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_invites_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let invites = state.list_invites(user.ws_id as _, user.id as _).await?;
+    Ok(Json(invites))
+}
+
+/// Invite an email to the workspace, the returned token is used to sign up.
+#[utoipa::path(
+    post,
+    path = "/api/invites",
+    responses(
+        (status = 201, description = "Invite created", body = InviteOutput),
+        (status = 403, description = "Not a workspace owner or admin", body = ErrorOutput),
+        (status = 409, description = "Email already registered", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    let invite = state
+        .create_invite(user.ws_id as _, user.id as _, input)
+        .await?;
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+/// Accept an invite with an existing account, switch to the workspace to use it.
+#[utoipa::path(
+    post,
+    path = "/api/invites/accept",
+    responses(
+        (status = 200, description = "Joined the workspace", body = Workspace),
+        (status = 403, description = "Invalid or expired invite", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn accept_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<AcceptInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.accept_invite(&user, &input.token).await?;
+    Ok(Json(ws))
+}
+
+/// Revoke a pending invite.
+#[utoipa::path(
+    delete,
+    path = "/api/invites/{id}",
+    params(
+        ("id" = u64, Path, description = "Invite id")
+    ),
+    responses(
+        (status = 204, description = "Invite revoked"),
+        (status = 404, description = "Invite not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .delete_invite(id, user.ws_id as _, user.id as _)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Hand the workspace over to another member, the current owner becomes an admin.
+#[utoipa::path(
+    post,
+    path = "/api/workspace/transfer",
+    responses(
+        (status = 200, description = "Ownership transferred", body = Workspace),
+        (status = 403, description = "Not the workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn transfer_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TransferWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state
+        .transfer_workspace(user.ws_id as _, user.id as _, input.user_id)
+        .await?;
+    Ok(Json(ws))
+}
+
+/// List workspace members with their role and status, for owners and admins.
+#[utoipa::path(
+    get,
+    path = "/api/workspace/members",
+    responses(
+        (status = 200, description = "Workspace members", body = Vec<WorkspaceMember>),
+        (status = 403, description = "Not a workspace owner or admin", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_workspace_members_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .verify_workspace_admin(user.ws_id as _, user.id as _)
+        .await?;
+    let members = state.fetch_workspace_members(user.ws_id as _).await?;
+    Ok(Json(members))
+}
+
+/// Change the role of a member.
+///
+/// - Only the owner can grant or revoke the admin role.
+/// - Admins can manage members and guests.
+#[utoipa::path(
+    patch,
+    path = "/api/workspace/members/{id}",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 200, description = "Role updated", body = WorkspaceMember),
+        (status = 403, description = "Not allowed to manage the user", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_workspace_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateWorkspaceMember>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state
+        .update_workspace_member_role(user.ws_id as _, user.id as _, id, input.role)
+        .await?;
+    Ok(Json(member))
+}
+
+/// Deactivate a member, the user is signed out everywhere and can't sign in again.
+#[utoipa::path(
+    post,
+    path = "/api/workspace/members/{id}/deactivate",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 200, description = "User deactivated", body = WorkspaceMember),
+        (status = 403, description = "Not allowed to manage the user", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn deactivate_workspace_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state
+        .deactivate_workspace_member(user.ws_id as _, user.id as _, id)
+        .await?;
+    Ok(Json(member))
+}
+
+/// Reactivate a deactivated member.
+#[utoipa::path(
+    post,
+    path = "/api/workspace/members/{id}/reactivate",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 200, description = "User reactivated", body = WorkspaceMember),
+        (status = 403, description = "Not allowed to manage the user", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn reactivate_workspace_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state
+        .reactivate_workspace_member(user.ws_id as _, user.id as _, id)
+        .await?;
+    Ok(Json(member))
+}
@@ -1,20 +1,24 @@
 mod config;
 mod error;
 mod handlers;
+mod mailer;
 mod middlewares;
 mod models;
 mod openapi;
+mod storage;
 
 use anyhow::Context;
 use chat_core::{
-    middlewares::{set_layer, verify_token, TokenVerify},
-    DecodingKey, EncodingKeyPair, User,
+    middlewares::{rate_limit, set_layer, verify_token, TokenVerify},
+    DecodingKey, EncodingKeyPair, RevocationList, TokenClaims,
 };
 use handlers::*;
+use mailer::Mailer;
 use middlewares::verify_chat;
 use openapi::OpenApiRouter;
 use sqlx::PgPool;
 use std::{fmt, ops::Deref, sync::Arc};
+use storage::Storage;
 use tokio::fs;
 use tower_http::cors::{self, CorsLayer};
 
@@ -22,13 +26,18 @@ pub use error::{AppError, ErrorOutput};
 pub use models::*;
 
 use axum::{
+    extract::DefaultBodyLimit,
     http::Method,
     middleware::from_fn_with_state,
-    routing::{get, post},
+    routing::{delete, get, patch, post},
     Router,
 };
 
 pub use config::AppConfig;
+use config::RateLimiters;
+
+// multipart boundaries and part headers on top of the file bytes of an upload
+const UPLOAD_BODY_OVERHEAD: usize = 64 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
@@ -41,6 +50,10 @@ pub struct AppStateInner {
     pub(crate) dk: DecodingKey,
     pub(crate) ek: EncodingKeyPair,
     pub(crate) pool: PgPool,
+    pub(crate) revocations: RevocationList,
+    pub(crate) mailer: Box<dyn Mailer>,
+    pub(crate) limiters: RateLimiters,
+    pub(crate) storage: Box<dyn Storage>,
 }
 
 pub async fn get_router(state: AppState) -> Result<Router, AppError> {
@@ -53,7 +66,28 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
                 .post(send_message_handler),
         )
         .route("/:id/messages", get(list_message_handler))
+        .route(
+            "/:id/messages/:msg_id",
+            patch(update_message_handler).delete(delete_message_handler),
+        )
+        .route(
+            "/:id/messages/:msg_id/edits",
+            get(list_message_edits_handler),
+        )
+        .route("/:id/messages/:msg_id/thread", get(list_thread_handler))
+        .route(
+            "/:id/messages/:msg_id/reactions",
+            post(add_reaction_handler).delete(remove_reaction_handler),
+        )
+        .route("/:id/members", get(list_chat_members_handler))
+        .route("/:id/members/:user_id", patch(update_chat_member_handler))
+        .route("/:id/leave", post(leave_chat_handler))
+        .route("/:id/read", post(mark_chat_read_handler))
         .layer(from_fn_with_state(state.clone(), verify_chat))
+        // workspace owner may restore a chat without being a member
+        .route("/:id/restore", post(restore_chat_handler))
+        .route("/:id/join", post(join_chat_handler))
+        .route("/discover", get(discover_chat_handler))
         .route("/", get(list_chat_handler).post(create_chat_handler));
 
     let cors = CorsLayer::new()
@@ -67,20 +101,79 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
         ])
         .allow_origin(cors::Any)
         .allow_headers(cors::Any);
+    // routes doesn't need token verification, limited per client ip
+    let auth = Router::new()
+        .route("/signin", post(signin_handler))
+        .route("/signin/mfa", post(mfa_signin_handler))
+        .route("/signup", post(signup_handler))
+        .route("/refresh", post(refresh_handler))
+        .route("/password/forgot", post(forgot_password_handler))
+        .route("/password/reset", post(reset_password_handler))
+        .route("/email/verify", post(verify_email_handler))
+        .layer(from_fn_with_state(state.limiters.auth.clone(), rate_limit));
+    // the signature in the url replaces the token, e.g. for `<img>` tags
+    let signed = Router::new()
+        .route("/signed/files/:ws_id/*path", get(signed_file_handler))
+        .layer(from_fn_with_state(state.limiters.api.clone(), rate_limit));
+
     let api = Router::new()
         .route("/users", get(list_chat_users_handler))
+        .route("/workspaces", get(list_workspaces_handler))
+        .route("/workspaces/:id/switch", post(switch_workspace_handler))
+        .route("/workspace", patch(update_workspace_handler))
+        .route("/workspace/transfer", post(transfer_workspace_handler))
+        .route("/workspace/members", get(list_workspace_members_handler))
+        .route(
+            "/workspace/members/:id",
+            patch(update_workspace_member_handler),
+        )
+        .route(
+            "/workspace/members/:id/deactivate",
+            post(deactivate_workspace_member_handler),
+        )
+        .route(
+            "/workspace/members/:id/reactivate",
+            post(reactivate_workspace_member_handler),
+        )
+        .route(
+            "/invites",
+            get(list_invites_handler).post(create_invite_handler),
+        )
+        .route("/invites/accept", post(accept_invite_handler))
+        .route("/invites/:id", delete(delete_invite_handler))
         .nest("/chats", chat)
-        .route("/upload", post(upload_handler))
+        // the upload handler enforces its own size limits while streaming,
+        // the body limit only bounds what it has to read through
+        .route(
+            "/upload",
+            post(upload_handler).layer(DefaultBodyLimit::max(
+                state.config.server.max_upload_size as usize + UPLOAD_BODY_OVERHEAD,
+            )),
+        )
+        .route("/search", get(search_handler))
+        .route("/mentions", get(list_mentions_handler))
+        .route("/signout", post(signout_handler))
+        .route("/signout/all", post(signout_all_handler))
+        .route("/mfa/enroll", post(enroll_mfa_handler))
+        .route("/mfa/enable", post(enable_mfa_handler))
+        .route("/mfa/disable", post(disable_mfa_handler))
+        .route(
+            "/email/verify/resend",
+            post(resend_verification_email_handler),
+        )
         .route("/files/:ws_id/*path", get(file_handler))
+        .route("/signed/files", post(sign_file_handler))
+        // limited per user, so it runs after the token is verified
+        .layer(from_fn_with_state(state.limiters.api.clone(), rate_limit))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
-        // routes doesn't need token verification
-        .route("/signin", post(signin_handler))
-        .route("/signup", post(signup_handler))
+        .merge(auth)
+        .merge(signed)
         .layer(cors);
 
     let app = Router::new()
         .openapi()
         .route("/", get(index_handler))
+        .route("/.well-known/jwks.json", get(jwks_handler))
         .nest("/api", api)
         .with_state(state);
 
@@ -99,8 +192,12 @@ impl Deref for AppState {
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
-        Ok(self.dk.verify(token)?)
+    fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error> {
+        let claims = self.dk.verify_claims(token)?;
+        if self.revocations.is_revoked(&claims) {
+            return Err(AppError::Unauthorized("token revoked".to_string()));
+        }
+        Ok(claims)
     }
 }
 
@@ -109,19 +206,27 @@ impl AppState {
         fs::create_dir_all(&config.server.base_dir)
             .await
             .context("create base_dir failed")?;
-        let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
-        let ek = EncodingKeyPair::load(&config.auth.sk).context("load sk failed")?;
+        let (ek, dk) = config.auth.load_keys()?;
+        let mailer = config.mail.mailer()?;
+        let storage = config.storage.storage(&config.server.base_dir)?;
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .context("connect to db failed")?;
-        Ok(Self {
+        let limiters = config.rate_limit.limiters(&pool);
+        let state = Self {
             inner: Arc::new(AppStateInner {
                 config,
                 ek,
                 dk,
                 pool,
+                revocations: RevocationList::default(),
+                mailer,
+                limiters,
+                storage,
             }),
-        })
+        };
+        setup_revocation_listener(state.clone()).await?;
+        Ok(state)
     }
 }
 
@@ -136,27 +241,59 @@ impl fmt::Debug for AppStateInner {
 #[cfg(feature = "test-util")]
 mod test_util {
     use super::*;
+    use crate::{
+        config::MailTransport,
+        mailer::{FileMailer, Mail},
+    };
     use sqlx::{Executor, PgPool};
     use sqlx_db_tester::TestPg;
 
     impl AppState {
         pub async fn new_for_test() -> Result<(TestPg, Self), AppError> {
-            let config = AppConfig::load()?;
-            let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
-            let ek = EncodingKeyPair::load(&config.auth.sk).context("load sk failed")?;
+            let mut config = AppConfig::load()?;
+            let (ek, dk) = config.auth.load_keys()?;
             let post = config.server.db_url.rfind('/').expect("invalid db_url");
             let server_url = &config.server.db_url[..post];
             let (tdb, pool) = get_test_pool(Some(server_url)).await;
+            // every test database gets its own mailbox
+            config.mail.transport = MailTransport::File {
+                dir: std::env::temp_dir()
+                    .join("chat_server_mails")
+                    .join(&tdb.dbname),
+            };
+            let mailer = config.mail.mailer()?;
+            let limiters = config.rate_limit.limiters(&pool);
+            let storage = config.storage.storage(&config.server.base_dir)?;
             let state = Self {
                 inner: Arc::new(AppStateInner {
                     config,
                     ek,
                     dk,
                     pool,
+                    revocations: RevocationList::default(),
+                    mailer,
+                    limiters,
+                    storage,
                 }),
             };
             Ok((tdb, state))
         }
+
+        /// Mails sent to the recipient so far, oldest first.
+        pub async fn sent_mails(&self, to: &str) -> Result<Vec<Mail>, AppError> {
+            let MailTransport::File { dir } = &self.config.mail.transport else {
+                return Ok(vec![]);
+            };
+            Ok(FileMailer::new(dir).mails_to(to).await?)
+        }
+
+        /// Insert the 10 sample messages of `fixtures/messages.sql` into chat 1.
+        pub async fn seed_messages(&self) -> Result<(), AppError> {
+            sqlx::raw_sql(include_str!("../fixtures/messages.sql"))
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
     }
 
     pub async fn get_test_pool(url: Option<&str>) -> (TestPg, PgPool) {
@@ -0,0 +1,132 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::info;
+
+use crate::config::{MailConfig, MailTransport, SmtpConfig};
+
+/// A plain text mail to a single recipient.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<()>;
+}
+
+/// Deliver mails through an SMTP relay.
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+/// Write mails to a directory instead of sending them, for development and tests.
+///
+/// Every mail is a JSON file named after the time it was sent.
+pub struct FileMailer {
+    dir: PathBuf,
+    seq: AtomicU64,
+}
+
+impl SmtpMailer {
+    pub fn try_new(from: &str, config: &SmtpConfig) -> Result<Self> {
+        let from = from.parse().context("invalid mail from address")?;
+        let builder = if config.tls {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+        };
+        let mut builder = builder.port(config.port);
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self {
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail.to.parse().context("invalid mail recipient")?)
+            .subject(mail.subject)
+            .body(mail.body)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Mails sent to the recipient, oldest first.
+    #[cfg(feature = "test-util")]
+    pub async fn mails_to(&self, to: &str) -> Result<Vec<Mail>> {
+        let mut names = Vec::new();
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(entry.path());
+        }
+        names.sort();
+
+        let mut mails = Vec::new();
+        for name in names {
+            let mail: Mail = serde_json::from_slice(&fs::read(name).await?)?;
+            if mail.to.eq_ignore_ascii_case(to) {
+                mails.push(mail);
+            }
+        }
+        Ok(mails)
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        let now = Utc::now().format("%Y%m%d%H%M%S%9f");
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{}-{:06}.json", now, seq));
+        fs::write(&path, serde_json::to_vec_pretty(&mail)?).await?;
+        info!("mail to {} written to {}", mail.to, path.display());
+        Ok(())
+    }
+}
+
+impl MailConfig {
+    pub fn mailer(&self) -> Result<Box<dyn Mailer>> {
+        let mailer: Box<dyn Mailer> = match &self.transport {
+            MailTransport::Smtp(config) => Box::new(SmtpMailer::try_new(&self.from, config)?),
+            MailTransport::File { dir } => Box::new(FileMailer::new(dir)),
+        };
+        Ok(mailer)
+    }
+}
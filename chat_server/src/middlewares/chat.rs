@@ -1,23 +1,40 @@
 use crate::{AppError, AppState};
 use axum::{
     extract::{FromRequestParts, Path, Request, State},
+    http::Method,
     middleware::Next,
     response::{IntoResponse, Response},
 };
-use chat_core::User;
+use chat_core::{ChatType, User};
+use serde::Deserialize;
+
+// chat routes may carry more path params (e.g. message id), only the chat id matters here
+#[derive(Debug, Deserialize)]
+struct ChatPath {
+    id: u64,
+}
 
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
-    let Path(chat_id) = Path::<u64>::from_request_parts(&mut parts, &state)
-        .await
-        .unwrap();
+    let chat_id = match Path::<ChatPath>::from_request_parts(&mut parts, &state).await {
+        Ok(Path(path)) => path.id,
+        Err(e) => return e.into_response(),
+    };
 
     let user = parts.extensions.get::<User>().unwrap();
-    if !state
-        .is_chat_member(chat_id, user.id as _)
+    // deactivated members keep their chat memberships but can't use them
+    if let Err(e) = state
+        .get_workspace_role(user.ws_id as _, user.id as _)
         .await
-        .unwrap_or_default()
     {
+        return e.into_response();
+    }
+    let is_member = state
+        .is_chat_member(user.ws_id as _, chat_id, user.id as _)
+        .await
+        .unwrap_or_default();
+    let can_read = parts.method == Method::GET && can_read_public_chat(&state, chat_id, user).await;
+    if !is_member && !can_read {
         let err = AppError::CreateMessageError(format!(
             "User {} are not a member of chat {chat_id}",
             user.id
@@ -29,12 +46,29 @@ pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next
     next.run(req).await
 }
 
+// public channels are readable by anyone in the same workspace except guests
+async fn can_read_public_chat(state: &AppState, chat_id: u64, user: &User) -> bool {
+    let is_public = match state.get_chat_by_id(chat_id).await {
+        Ok(Some(chat)) => chat.ws_id == user.ws_id && chat.r#type == ChatType::PublicChannel,
+        _ => false,
+    };
+    is_public
+        && state
+            .verify_not_guest(user.ws_id as _, user.id as _)
+            .await
+            .is_ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
     use axum::{
-        body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Router,
+        body::Body,
+        http::StatusCode,
+        middleware::from_fn_with_state,
+        routing::{get, post},
+        Router,
     };
     use chat_core::middlewares::verify_token;
     use tower::ServiceExt;
@@ -74,4 +108,100 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn verify_chat_should_forbid_deactivated_member() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let token = state.ek.sign(user)?;
+        sqlx::query("UPDATE workspace_members SET deactivated_at = now() WHERE user_id = 2")
+            .execute(&state.pool)
+            .await?;
+
+        let app = Router::new()
+            .route("/chat/:id/messages", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_chat))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state);
+
+        // user 2 is still listed as a member of chat 2
+        let req = Request::builder()
+            .uri("/chat/2/messages")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_chat_should_reject_other_workspace_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("INSERT INTO workspace_members (ws_id, user_id, role) VALUES (2, 2, 'member')")
+            .execute(&state.pool)
+            .await?;
+
+        // user 2 is a member of chat 1, but the token is for workspace 2
+        let user = state.switch_workspace(2, 2).await?;
+        let token = state.ek.sign(user)?;
+
+        let app = Router::new()
+            .route("/chat/:id/messages", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_chat))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state);
+
+        let req = Request::builder()
+            .uri("/chat/1/messages")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_chat_should_allow_reading_public_chat() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.leave_chat(1, 5).await?;
+
+        let user = state.find_user_by_id(5).await?.expect("user should exist");
+        let token = state.ek.sign(user)?;
+
+        let app = Router::new()
+            .route("/chat/:id/messages", get(handler))
+            .route("/chat/:id", post(handler))
+            .layer(from_fn_with_state(state.clone(), verify_chat))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state);
+
+        // public channel is readable
+        let req = Request::builder()
+            .uri("/chat/1/messages")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // but not writable
+        let req = Request::builder()
+            .method("POST")
+            .uri("/chat/1")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // private channel is not readable
+        let req = Request::builder()
+            .uri("/chat/2/messages")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,277 @@
+use crate::{mailer::Mail, AppError, AppState};
+use chat_core::User;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{
+    refresh_token::{generate_token, hash_token},
+    user::hash_password,
+};
+
+const PASSWORD_RESET_TTL: i64 = 60 * 60;
+const EMAIL_VERIFICATION_TTL: i64 = 60 * 60 * 24;
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct ResetPassword {
+    /// token from the password reset mail
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct VerifyEmail {
+    /// token from the verification mail
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "account_token_purpose", rename_all = "snake_case")]
+enum AccountTokenPurpose {
+    PasswordReset,
+    EmailVerification,
+}
+
+impl AppState {
+    /// Mail a password reset link to the user.
+    ///
+    /// Unknown emails are silently ignored so the endpoint can't be used to find out
+    /// who has an account.
+    pub async fn request_password_reset(&self, email: &str) -> Result<(), AppError> {
+        let Some(user) = self.find_user_by_email(email.trim()).await? else {
+            return Ok(());
+        };
+        let token = self
+            .create_account_token(
+                user.id,
+                AccountTokenPurpose::PasswordReset,
+                PASSWORD_RESET_TTL,
+            )
+            .await?;
+        let body = format!(
+            "Hi {},\n\nUse the link below to reset your password, it expires in an hour:\n\n{}/reset-password?token={}\n\nIf you didn't ask for it, you can ignore this mail.\n",
+            user.fullname, self.config.mail.app_url, token
+        );
+        self.send_mail(&user.email, "Reset your password", body)
+            .await
+    }
+
+    /// Set a new password with a reset token, the user is signed out everywhere.
+    pub async fn reset_password(&self, input: &ResetPassword) -> Result<(), AppError> {
+        let Some(user_id) = self
+            .consume_account_token(&input.token, AccountTokenPurpose::PasswordReset)
+            .await?
+        else {
+            return Err(AppError::PermissionDenied(
+                "invalid or expired token".to_string(),
+            ));
+        };
+
+        let password_hash = hash_password(&input.password)?;
+        // the reset link was mailed to the user, so the email is verified as well
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET password_hash = $2, email_verified_at = COALESCE(email_verified_at, now())
+            WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .bind(password_hash)
+        .execute(&self.pool)
+        .await?;
+        // other reset links mailed before are useless now
+        sqlx::query(
+            r#"
+            UPDATE account_tokens
+            SET used_at = now()
+            WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(AccountTokenPurpose::PasswordReset)
+        .execute(&self.pool)
+        .await?;
+        self.join_allowed_workspace(user_id).await?;
+
+        self.signout_all(user_id as _).await
+    }
+
+    /// Mail a link to verify the email of the user, e.g. after signup.
+    pub async fn send_verification_email(&self, user: &User) -> Result<(), AppError> {
+        if self.email_verified_at(user.id as _).await?.is_some() {
+            return Ok(());
+        }
+        let token = self
+            .create_account_token(
+                user.id,
+                AccountTokenPurpose::EmailVerification,
+                EMAIL_VERIFICATION_TTL,
+            )
+            .await?;
+        let body = format!(
+            "Hi {},\n\nPlease confirm your email address with the link below:\n\n{}/verify-email?token={}\n",
+            user.fullname, self.config.mail.app_url, token
+        );
+        self.send_mail(&user.email, "Verify your email", body).await
+    }
+
+    /// Mark the email of the user as verified, a signup through the allowed email domain
+    /// joins the workspace now.
+    pub async fn verify_email(&self, token: &str) -> Result<(), AppError> {
+        let Some(user_id) = self
+            .consume_account_token(token, AccountTokenPurpose::EmailVerification)
+            .await?
+        else {
+            return Err(AppError::PermissionDenied(
+                "invalid or expired token".to_string(),
+            ));
+        };
+        sqlx::query(
+            "UPDATE users SET email_verified_at = COALESCE(email_verified_at, now()) WHERE id = $1",
+        )
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        self.join_allowed_workspace(user_id).await
+    }
+
+    pub async fn email_verified_at(&self, user_id: u64) -> Result<Option<DateTime<Utc>>, AppError> {
+        let verified_at = sqlx::query_scalar("SELECT email_verified_at FROM users WHERE id = $1")
+            .bind(user_id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(verified_at.flatten())
+    }
+
+    async fn create_account_token(
+        &self,
+        user_id: i64,
+        purpose: AccountTokenPurpose,
+        ttl: i64,
+    ) -> Result<String, AppError> {
+        let (token, token_hash) = generate_token();
+        sqlx::query(
+            r#"
+            INSERT INTO account_tokens (user_id, purpose, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .bind(token_hash)
+        .bind(Utc::now() + Duration::seconds(ttl))
+        .execute(&self.pool)
+        .await?;
+        Ok(token)
+    }
+
+    // mark the token used and return its user, a token works only once
+    async fn consume_account_token(
+        &self,
+        token: &str,
+        purpose: AccountTokenPurpose,
+    ) -> Result<Option<i64>, AppError> {
+        let user_id = sqlx::query_scalar(
+            r#"
+            UPDATE account_tokens
+            SET used_at = now()
+            WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > now()
+            RETURNING user_id
+            "#,
+        )
+        .bind(hash_token(token))
+        .bind(purpose)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user_id)
+    }
+
+    async fn send_mail(&self, to: &str, subject: &str, body: String) -> Result<(), AppError> {
+        let mail = Mail {
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body,
+        };
+        self.mailer.send(mail).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateUser, SigninUser};
+    use anyhow::Result;
+
+    // the token is the last query parameter of the link in the mail
+    async fn last_token(state: &AppState, to: &str) -> Result<String> {
+        let mails = state.sent_mails(to).await?;
+        let mail = mails.last().expect("mail should be sent");
+        let (_, token) = mail.body.split_once("token=").expect("token in mail");
+        Ok(token.lines().next().unwrap_or_default().to_string())
+    }
+
+    #[tokio::test]
+    async fn reset_password_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.request_password_reset("alice@acme.org").await?;
+        let token = last_token(&state, "alice@acme.org").await?;
+
+        let input = ResetPassword {
+            token: token.clone(),
+            password: "new-password".to_string(),
+        };
+        state.reset_password(&input).await?;
+        let signin = SigninUser::new("alice@acme.org", "123456");
+        assert!(state.verify_user(&signin).await?.is_none());
+        let signin = SigninUser::new("alice@acme.org", "new-password");
+        assert!(state.verify_user(&signin).await?.is_some());
+        assert!(state.email_verified_at(2).await?.is_some());
+
+        // tokens are single use
+        let ret = state.reset_password(&input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forgot_password_should_ignore_unknown_email() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.request_password_reset("nobody@acme.org").await?;
+        assert!(state.sent_mails("nobody@acme.org").await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_email_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("new", "Tyr Chen", "tyr@new.org", "hunter42");
+        let user = state.create_user(&input).await?;
+        state.send_verification_email(&user).await?;
+        let token = last_token(&state, "tyr@new.org").await?;
+        assert!(state.email_verified_at(user.id as _).await?.is_none());
+
+        // a verification token can't reset the password
+        let input = ResetPassword {
+            token: token.clone(),
+            password: "whatever".to_string(),
+        };
+        let ret = state.reset_password(&input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        state.verify_email(&token).await?;
+        assert!(state.email_verified_at(user.id as _).await?.is_some());
+        assert!(state.verify_email(&token).await.is_err());
+
+        // verified users don't get another mail
+        state.send_verification_email(&user).await?;
+        assert_eq!(state.sent_mails("tyr@new.org").await?.len(), 1);
+        Ok(())
+    }
+}
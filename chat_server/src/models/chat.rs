@@ -1,6 +1,7 @@
 use crate::{AppError, AppState};
-use chat_core::{Chat, ChatType};
+use chat_core::{Chat, ChatMember, ChatRole, ChatType, Message, User};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use utoipa::ToSchema;
 
 #[derive(Debug, Clone, Default, Serialize, ToSchema, Deserialize)]
@@ -10,6 +11,41 @@ pub struct CreateChat {
     pub public: bool,
 }
 
+/// Partial update of a chat, fields left out keep their current value.
+#[derive(Debug, Clone, Default, Serialize, ToSchema, Deserialize)]
+pub struct UpdateChat {
+    pub name: Option<String>,
+    pub public: Option<bool>,
+    #[serde(default)]
+    pub add_members: Vec<i64>,
+    #[serde(default)]
+    pub remove_members: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct UpdateChatMember {
+    pub role: ChatRole,
+}
+
+/// Advance the read position, defaults to the latest message of the chat.
+#[derive(Debug, Clone, Default, Serialize, ToSchema, Deserialize)]
+pub struct MarkChatRead {
+    #[serde(default)]
+    pub message_id: Option<u64>,
+}
+
+/// A chat as listed for a user, with unread count and latest message.
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ChatSummary {
+    #[serde(flatten)]
+    pub chat: Chat,
+    #[serde(alias = "unreadCount")]
+    pub unread_count: i64,
+    #[serde(alias = "lastMessage")]
+    pub last_message: Option<Message>,
+}
+
 #[allow(dead_code)]
 impl AppState {
     pub async fn create_chat(
@@ -18,74 +54,267 @@ impl AppState {
         user_id: u64,
         ws_id: u64,
     ) -> Result<Chat, AppError> {
-        let len = input.members.len();
-        if len < 2 {
+        self.verify_not_guest(ws_id, user_id).await?;
+        validate_chat(
+            &input.name,
+            &input.members,
+            user_id,
+            AppError::CreateChatError,
+        )?;
+        // verify if all members exist
+        let users = self.fetch_chat_user_by_ids(ws_id, &input.members).await?;
+        if users.len() != input.members.len() {
             return Err(AppError::CreateChatError(
-                "Chat must have at least 2 members".to_string(),
+                "Some members do not exist".to_string(),
             ));
         }
 
-        if !input.members.contains(&(user_id as i64)) {
-            return Err(AppError::CreateChatError(
-                "You must be a member of the chat".to_string(),
+        let chat_type = get_chat_type(&input.name, input.members.len(), input.public);
+        let mut tx = self.pool.begin().await?;
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO chats (ws_id, name, type, created_by)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.name)
+        .bind(chat_type)
+        .bind(user_id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // the creator owns the chat
+        sqlx::query(
+            r#"
+            INSERT INTO chat_members (chat_id, user_id, role)
+            SELECT $1, user_id, CASE WHEN user_id = $3 THEN 'owner'::chat_role ELSE 'member'::chat_role END
+            FROM unnest($2::bigint[]) AS user_id
+            "#,
+        )
+        .bind(id)
+        .bind(&input.members)
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        let chat = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, chat_member_ids(id) AS members, created_at, archived_at
+            FROM chats
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+
+    /// Update a chat, renaming, changing visibility or removing members
+    /// requires the owner or admin role.
+    pub async fn update_chat(
+        &self,
+        id: u64,
+        input: UpdateChat,
+        user_id: u64,
+    ) -> Result<Chat, AppError> {
+        let Some(chat) = self.get_chat_by_id(id).await? else {
+            return Err(AppError::NotFound(format!("chat id {id}")));
+        };
+        if chat.archived_at.is_some() {
+            return Err(AppError::UpdateChatError(format!("chat {id} is archived")));
+        }
+
+        let roles = self.fetch_chat_members(id).await?;
+        let role_of = |user_id: i64| {
+            roles
+                .iter()
+                .find(|member| member.user_id == user_id)
+                .map(|member| member.role)
+        };
+        let is_manager = matches!(
+            role_of(user_id as _),
+            Some(ChatRole::Owner | ChatRole::Admin)
+        );
+        let removes = input
+            .remove_members
+            .iter()
+            .any(|member| chat.members.contains(member));
+        if (input.name.is_some() || input.public.is_some() || removes) && !is_manager {
+            return Err(AppError::PermissionDenied(format!(
+                "user {user_id} is not an owner or admin of chat {id}"
+            )));
+        }
+        if input
+            .remove_members
+            .iter()
+            .any(|member| role_of(*member) == Some(ChatRole::Owner))
+        {
+            return Err(AppError::UpdateChatError(
+                "Chat owner can not be removed".to_string(),
             ));
         }
 
-        if let Some(name) = &input.name {
-            if name.len() < 3 {
-                return Err(AppError::CreateChatError(
-                    "Chat name must have at least 3 characters".to_string(),
-                ));
+        let mut members: Vec<i64> = chat
+            .members
+            .iter()
+            .filter(|member| !input.remove_members.contains(member))
+            .copied()
+            .collect();
+        for member in &input.add_members {
+            if !members.contains(member) {
+                members.push(*member);
             }
         }
 
-        if len > 8 && input.name.is_none() {
-            return Err(AppError::CreateChatError(
-                "Group chat with more than 8 members must have a name".to_string(),
-            ));
-        }
-        // verify if all members exist
-        let users = self.fetch_chat_user_by_ids(&input.members).await?;
-        if users.len() != len {
-            return Err(AppError::CreateChatError(
+        let name = input.name.or(chat.name);
+        let public = input
+            .public
+            .unwrap_or(chat.r#type == ChatType::PublicChannel);
+
+        validate_chat(&name, &members, user_id, AppError::UpdateChatError)?;
+        // only new members need to be active in the workspace
+        let added: Vec<i64> = members
+            .iter()
+            .filter(|member| !chat.members.contains(member))
+            .copied()
+            .collect();
+        let users = self.fetch_chat_user_by_ids(chat.ws_id as _, &added).await?;
+        if users.len() != added.len() {
+            return Err(AppError::UpdateChatError(
                 "Some members do not exist".to_string(),
             ));
         }
 
-        let chat_type = match (&input.name, len) {
-            (None, 2) => ChatType::Single,
-            (None, _) => ChatType::Group,
-            (Some(_), _) => {
-                if input.public {
-                    ChatType::PublicChannel
-                } else {
-                    ChatType::PrivateChannel
-                }
-            }
-        };
+        let chat_type = get_chat_type(&name, members.len(), public);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM chat_members
+            WHERE chat_id = $1 AND user_id = ANY($2)
+            "#,
+        )
+        .bind(id as i64)
+        .bind(&input.remove_members)
+        .execute(&mut *tx)
+        .await?;
+
+        if !added.is_empty() {
+            sqlx::query(
+                r#"
+                INSERT INTO chat_members (chat_id, user_id)
+                SELECT $1, unnest($2::bigint[])
+                "#,
+            )
+            .bind(id as i64)
+            .bind(&added)
+            .execute(&mut *tx)
+            .await?;
+        }
+
         let chat = sqlx::query_as(
             r#"
-            INSERT INTO chats (ws_id, name, type, members)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
+            UPDATE chats
+            SET name = $1, type = $2
+            WHERE id = $3
+            RETURNING id, ws_id, name, type, chat_member_ids(id) AS members, created_at, archived_at
             "#,
         )
-        .bind(ws_id as i64)
-        .bind(input.name)
+        .bind(name)
         .bind(chat_type)
-        .bind(input.members)
-        .fetch_one(&self.pool)
+        .bind(id as i64)
+        .fetch_one(&mut *tx)
         .await?;
+        tx.commit().await?;
 
         Ok(chat)
     }
 
+    /// Archive a chat, only its owner or the workspace owner can do it.
+    pub async fn archive_chat(&self, id: u64, user: &User) -> Result<Chat, AppError> {
+        self.verify_chat_manager(id, user).await?;
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET archived_at = NOW()
+            WHERE id = $1 AND archived_at IS NULL
+            RETURNING id, ws_id, name, type, chat_member_ids(id) AS members, created_at, archived_at
+            "#,
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match chat {
+            Some(chat) => Ok(chat),
+            None => Err(AppError::UpdateChatError(format!(
+                "chat {id} is already archived"
+            ))),
+        }
+    }
+
+    /// Restore an archived chat, only its owner or the workspace owner can do it.
+    pub async fn restore_chat(&self, id: u64, user: &User) -> Result<Chat, AppError> {
+        self.verify_chat_manager(id, user).await?;
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET archived_at = NULL
+            WHERE id = $1 AND archived_at IS NOT NULL
+            RETURNING id, ws_id, name, type, chat_member_ids(id) AS members, created_at, archived_at
+            "#,
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match chat {
+            Some(chat) => Ok(chat),
+            None => Err(AppError::UpdateChatError(format!(
+                "chat {id} is not archived"
+            ))),
+        }
+    }
+
+    async fn verify_chat_manager(&self, id: u64, user: &User) -> Result<(), AppError> {
+        let row: Option<(Option<ChatRole>, i64)> = sqlx::query_as(
+            r#"
+            SELECT m.role, w.owner_id
+            FROM chats c
+            JOIN workspaces w ON w.id = c.ws_id
+            LEFT JOIN chat_members m ON m.chat_id = c.id AND m.user_id = $3
+            WHERE c.id = $1 AND c.ws_id = $2
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user.ws_id)
+        .bind(user.id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some((role, owner_id)) = row else {
+            return Err(AppError::NotFound(format!("chat id {id}")));
+        };
+        if role != Some(ChatRole::Owner) && owner_id != user.id {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} can not manage chat {id}",
+                user.id
+            )));
+        }
+        Ok(())
+    }
+
     pub async fn fetch_chats(&self, user_id: u64, ws_id: u64) -> Result<Vec<Chat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
-            FROM chats
-            WHERE ws_id = $1 AND $2 = ANY(members)
+            SELECT c.id, c.ws_id, c.name, c.type, chat_member_ids(c.id) AS members, c.created_at, c.archived_at
+            FROM chats c
+            JOIN chat_members m ON m.chat_id = c.id
+            WHERE c.ws_id = $1 AND m.user_id = $2 AND c.archived_at IS NULL
             "#,
         )
         .bind(ws_id as i64)
@@ -96,10 +325,202 @@ impl AppState {
         Ok(chats)
     }
 
+    /// Chats of the user with unread counts, thread replies and own messages are not counted.
+    pub async fn fetch_chat_summaries(
+        &self,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<Vec<ChatSummary>, AppError> {
+        let chats = self.fetch_chats(user_id, ws_id).await?;
+        let ids: Vec<i64> = chats.iter().map(|c| c.id).collect();
+
+        let unread: HashMap<i64, i64> = sqlx::query_as(
+            r#"
+            SELECT cm.chat_id, count(m.id)
+            FROM chat_members cm
+            LEFT JOIN messages m ON m.chat_id = cm.chat_id
+              AND m.id > COALESCE(cm.last_read_message_id, 0)
+              AND m.sender_id <> cm.user_id
+              AND m.deleted_at IS NULL
+              AND m.thread_root_id IS NULL
+            WHERE cm.user_id = $1 AND cm.chat_id = ANY($2)
+            GROUP BY cm.chat_id
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .collect();
+
+        let messages: Vec<Message> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT ON (chat_id) id, chat_id, sender_id, content, files, created_at,
+              updated_at, deleted_at, reply_to, thread_root_id
+            FROM messages
+            WHERE chat_id = ANY($1) AND deleted_at IS NULL AND thread_root_id IS NULL
+            ORDER BY chat_id, id DESC
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut last_messages: HashMap<i64, Message> =
+            messages.into_iter().map(|m| (m.chat_id, m)).collect();
+
+        let summaries = chats
+            .into_iter()
+            .map(|chat| ChatSummary {
+                unread_count: unread.get(&chat.id).copied().unwrap_or_default(),
+                last_message: last_messages.remove(&chat.id),
+                chat,
+            })
+            .collect();
+        Ok(summaries)
+    }
+
+    /// Move the user's read position forward, it never goes backwards.
+    pub async fn mark_chat_read(
+        &self,
+        input: MarkChatRead,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ChatMember, AppError> {
+        let message_id: Option<i64> = match input.message_id {
+            Some(id) => match self.get_message(chat_id, id).await? {
+                Some(msg) => Some(msg.id),
+                None => return Err(AppError::NotFound(format!("message id {id}"))),
+            },
+            None => {
+                sqlx::query_scalar("SELECT max(id) FROM messages WHERE chat_id = $1")
+                    .bind(chat_id as i64)
+                    .fetch_one(&self.pool)
+                    .await?
+            }
+        };
+
+        if let Some(message_id) = message_id {
+            sqlx::query(
+                r#"
+                UPDATE chat_members
+                SET last_read_message_id = $3
+                WHERE chat_id = $1 AND user_id = $2
+                  AND (last_read_message_id IS NULL OR last_read_message_id < $3)
+                "#,
+            )
+            .bind(chat_id as i64)
+            .bind(user_id as i64)
+            .bind(message_id)
+            .execute(&self.pool)
+            .await?;
+        }
+
+        let member = sqlx::query_as(
+            r#"
+            SELECT chat_id, user_id, role, joined_at, last_read_message_id
+            FROM chat_members
+            WHERE chat_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        member.ok_or_else(|| AppError::NotFound(format!("user {user_id} in chat {chat_id}")))
+    }
+
+    /// Public channels of a workspace, whether or not the user joined them.
+    pub async fn fetch_public_chats(&self, ws_id: u64) -> Result<Vec<Chat>, AppError> {
+        let chats = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, chat_member_ids(id) AS members, created_at, archived_at
+            FROM chats
+            WHERE ws_id = $1 AND type = 'public_channel' AND archived_at IS NULL
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chats)
+    }
+
+    /// Join a public channel of the user's workspace.
+    pub async fn join_chat(&self, id: u64, user: &User) -> Result<Chat, AppError> {
+        self.verify_not_guest(user.ws_id as _, user.id as _).await?;
+        let chat = match self.get_chat_by_id(id).await? {
+            Some(chat) if chat.ws_id == user.ws_id && chat.archived_at.is_none() => chat,
+            _ => return Err(AppError::NotFound(format!("chat id {id}"))),
+        };
+        if chat.r#type != ChatType::PublicChannel {
+            return Err(AppError::PermissionDenied(format!(
+                "chat {id} is not a public channel"
+            )));
+        }
+        if chat.members.contains(&user.id) {
+            return Ok(chat);
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO chat_members (chat_id, user_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user.id)
+        .execute(&self.pool)
+        .await?;
+
+        // the chat may be deleted in the meantime
+        self.get_chat_by_id(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("chat id {id}")))
+    }
+
+    /// Leave a chat, single chats can't be left and the owner must stay.
+    pub async fn leave_chat(&self, id: u64, user_id: u64) -> Result<(), AppError> {
+        let Some(chat) = self.get_chat_by_id(id).await? else {
+            return Err(AppError::NotFound(format!("chat id {id}")));
+        };
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Can not leave a single chat".to_string(),
+            ));
+        }
+        match self.get_chat_member_role(id, user_id).await? {
+            None => {
+                return Err(AppError::NotFound(format!("user {user_id} in chat {id}")));
+            }
+            Some(ChatRole::Owner) => {
+                return Err(AppError::UpdateChatError(
+                    "Chat owner can not leave the chat".to_string(),
+                ));
+            }
+            _ => {}
+        }
+
+        sqlx::query(
+            r#"
+            DELETE FROM chat_members
+            WHERE chat_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn get_chat_by_id(&self, id: u64) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, chat_member_ids(id) AS members, created_at, archived_at
             FROM chats
             WHERE id = $1
             "#,
@@ -111,21 +532,153 @@ impl AppState {
         Ok(chat)
     }
 
-    pub async fn is_chat_member(&self, chat_id: u64, user_id: u64) -> Result<bool, AppError> {
+    /// Whether the user is a member of a chat in the given workspace and still active there.
+    pub async fn is_chat_member(
+        &self,
+        ws_id: u64,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<bool, AppError> {
         let is_member = sqlx::query(
             r#"
             SELECT 1
-            FROM chats
-            WHERE id = $1 AND $2 = ANY(members)
+            FROM chat_members cm
+            JOIN chats c ON c.id = cm.chat_id
+            JOIN workspace_members wm ON wm.ws_id = c.ws_id AND wm.user_id = cm.user_id
+            WHERE cm.chat_id = $1 AND cm.user_id = $2 AND c.ws_id = $3
+            AND wm.deactivated_at IS NULL
             "#,
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
+        .bind(ws_id as i64)
         .fetch_optional(&self.pool)
         .await?;
 
         Ok(is_member.is_some())
     }
+
+    pub async fn get_chat_member_role(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Option<ChatRole>, AppError> {
+        let role = sqlx::query_scalar(
+            r#"
+            SELECT role
+            FROM chat_members
+            WHERE chat_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(role)
+    }
+
+    pub async fn fetch_chat_members(&self, chat_id: u64) -> Result<Vec<ChatMember>, AppError> {
+        let members = sqlx::query_as(
+            r#"
+            SELECT chat_id, user_id, role, joined_at, last_read_message_id
+            FROM chat_members
+            WHERE chat_id = $1
+            ORDER BY user_id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    /// Change a member's role, only the chat owner can do it and ownership can't be assigned.
+    pub async fn update_chat_member_role(
+        &self,
+        chat_id: u64,
+        member_id: u64,
+        role: ChatRole,
+        user_id: u64,
+    ) -> Result<ChatMember, AppError> {
+        if self.get_chat_member_role(chat_id, user_id).await? != Some(ChatRole::Owner) {
+            return Err(AppError::PermissionDenied(format!(
+                "user {user_id} is not the owner of chat {chat_id}"
+            )));
+        }
+        if role == ChatRole::Owner || member_id == user_id {
+            return Err(AppError::UpdateChatError(
+                "Chat ownership can not be changed".to_string(),
+            ));
+        }
+
+        let member = sqlx::query_as(
+            r#"
+            UPDATE chat_members
+            SET role = $1
+            WHERE chat_id = $2 AND user_id = $3
+            RETURNING chat_id, user_id, role, joined_at, last_read_message_id
+            "#,
+        )
+        .bind(role)
+        .bind(chat_id as i64)
+        .bind(member_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match member {
+            Some(member) => Ok(member),
+            None => Err(AppError::NotFound(format!(
+                "user {member_id} in chat {chat_id}"
+            ))),
+        }
+    }
+}
+
+/// Validation rules shared by chat creation and update, `err` builds the error to return.
+fn validate_chat(
+    name: &Option<String>,
+    members: &[i64],
+    user_id: u64,
+    err: fn(String) -> AppError,
+) -> Result<(), AppError> {
+    let len = members.len();
+    if len < 2 {
+        return Err(err("Chat must have at least 2 members".to_string()));
+    }
+
+    if !members.contains(&(user_id as i64)) {
+        return Err(err("You must be a member of the chat".to_string()));
+    }
+
+    if let Some(name) = name {
+        if name.len() < 3 {
+            return Err(err("Chat name must have at least 3 characters".to_string()));
+        }
+    }
+
+    if len > 8 && name.is_none() {
+        return Err(err(
+            "Group chat with more than 8 members must have a name".to_string()
+        ));
+    }
+
+    Ok(())
+}
+
+fn get_chat_type(name: &Option<String>, len: usize, public: bool) -> ChatType {
+    match (name, len) {
+        (None, 2) => ChatType::Single,
+        (None, _) => ChatType::Group,
+        (Some(_), _) => {
+            if public {
+                ChatType::PublicChannel
+            } else {
+                ChatType::PrivateChannel
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -177,6 +730,267 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn update_chat_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateChat {
+            name: Some("rust".to_string()),
+            add_members: vec![5],
+            ..Default::default()
+        };
+        let chat = state
+            .update_chat(4, input, 1)
+            .await
+            .expect("update chat failed");
+        assert_eq!(chat.name.as_deref(), Some("rust"));
+        assert_eq!(chat.members, vec![1, 3, 4, 5]);
+        assert_eq!(chat.r#type, ChatType::PrivateChannel);
+
+        let input = UpdateChat {
+            public: Some(true),
+            remove_members: vec![3, 4],
+            ..Default::default()
+        };
+        let chat = state
+            .update_chat(4, input, 1)
+            .await
+            .expect("update chat failed");
+        assert_eq!(chat.members, vec![1, 5]);
+        assert_eq!(chat.r#type, ChatType::PublicChannel);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_with_invalid_input_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateChat {
+            remove_members: vec![2],
+            ..Default::default()
+        };
+        let err = state.update_chat(3, input, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Chat must have at least 2 members"
+        );
+
+        let input = UpdateChat {
+            name: Some("ab".to_string()),
+            ..Default::default()
+        };
+        let err = state.update_chat(3, input, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Chat name must have at least 3 characters"
+        );
+
+        let input = UpdateChat {
+            add_members: vec![10],
+            ..Default::default()
+        };
+        let err = state.update_chat(3, input, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Some members do not exist"
+        );
+
+        let err = state
+            .update_chat(10, UpdateChat::default(), 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Not found: chat id 10");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_by_plain_member_should_be_restricted() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateChat {
+            name: Some("rename".to_string()),
+            ..Default::default()
+        };
+        let err = state.update_chat(2, input.clone(), 2).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        // plain members can still add people
+        let add = UpdateChat {
+            add_members: vec![4],
+            ..Default::default()
+        };
+        let chat = state.update_chat(2, add, 2).await?;
+        assert_eq!(chat.members, vec![1, 2, 3, 4]);
+
+        // admins can rename and remove members, but not the owner
+        state
+            .update_chat_member_role(2, 2, ChatRole::Admin, 1)
+            .await?;
+        let chat = state.update_chat(2, input, 2).await?;
+        assert_eq!(chat.name.as_deref(), Some("rename"));
+
+        let remove_owner = UpdateChat {
+            remove_members: vec![1],
+            ..Default::default()
+        };
+        let err = state.update_chat(2, remove_owner, 2).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Chat owner can not be removed"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_member_role_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let member = state
+            .update_chat_member_role(1, 3, ChatRole::Admin, 1)
+            .await?;
+        assert_eq!(member.role, ChatRole::Admin);
+
+        // only the owner can change roles
+        let err = state
+            .update_chat_member_role(1, 4, ChatRole::Admin, 3)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let err = state
+            .update_chat_member_role(1, 3, ChatRole::Owner, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Chat ownership can not be changed"
+        );
+
+        let members = state.fetch_chat_members(1).await?;
+        assert_eq!(members.len(), 5);
+        assert_eq!(members[0].role, ChatRole::Owner);
+        assert_eq!(members[2].role, ChatRole::Admin);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mark_chat_read_should_update_unread_count() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.seed_messages().await?;
+        let chats = state.fetch_chat_summaries(2, 1).await?;
+        let chat = chats
+            .iter()
+            .find(|c| c.chat.id == 1)
+            .expect("chat 1 exists");
+        // user 2 sent 2 of the 10 messages
+        assert_eq!(chat.unread_count, 8);
+        assert_eq!(chat.last_message.as_ref().map(|m| m.id), Some(10));
+
+        let input = MarkChatRead {
+            message_id: Some(5),
+        };
+        let member = state.mark_chat_read(input, 1, 2).await?;
+        assert_eq!(member.last_read_message_id, Some(5));
+
+        // read position never goes backwards
+        let input = MarkChatRead {
+            message_id: Some(3),
+        };
+        let member = state.mark_chat_read(input, 1, 2).await?;
+        assert_eq!(member.last_read_message_id, Some(5));
+
+        let chats = state.fetch_chat_summaries(2, 1).await?;
+        let chat = chats
+            .iter()
+            .find(|c| c.chat.id == 1)
+            .expect("chat 1 exists");
+        assert_eq!(chat.unread_count, 4);
+
+        let member = state.mark_chat_read(MarkChatRead::default(), 1, 2).await?;
+        assert_eq!(member.last_read_message_id, Some(10));
+        let chats = state.fetch_chat_summaries(2, 1).await?;
+        assert!(chats.iter().all(|c| c.unread_count == 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_and_leave_public_chat_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateChat::new("rust", &[1, 2], true);
+        let chat = state.create_chat(input, 1, 1).await?;
+        let chats = state.fetch_public_chats(1).await?;
+        assert_eq!(chats.len(), 2);
+
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        let chat = state.join_chat(chat.id as _, &user).await?;
+        assert_eq!(chat.members, vec![1, 2, 3]);
+        assert!(state.is_chat_member(1, chat.id as _, 3).await?);
+
+        state.leave_chat(chat.id as _, 3).await?;
+        assert!(!state.is_chat_member(1, chat.id as _, 3).await?);
+
+        let err = state.leave_chat(chat.id as _, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Chat owner can not leave the chat"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_private_or_leave_single_chat_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(4).await?.expect("user should exist");
+        let err = state.join_chat(2, &user).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let err = state.leave_chat(3, 2).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Can not leave a single chat"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archive_and_restore_chat_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = CreateChat::new("archive", &[1, 2], false);
+        let chat = state.create_chat(input, 1, 1).await?;
+
+        let archived = state.archive_chat(chat.id as _, &user).await?;
+        assert!(archived.archived_at.is_some());
+        let chats = state.fetch_chats(1, 1).await?;
+        assert!(chats.iter().all(|c| c.id != chat.id));
+
+        let err = state.archive_chat(chat.id as _, &user).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("update chat error: chat {} is already archived", chat.id)
+        );
+
+        let restored = state.restore_chat(chat.id as _, &user).await?;
+        assert!(restored.archived_at.is_none());
+        let chats = state.fetch_chats(1, 1).await?;
+        assert!(chats.iter().any(|c| c.id == chat.id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archive_chat_by_non_manager_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let input = CreateChat::new("archive", &[1, 2], false);
+        let chat = state.create_chat(input, 1, 1).await?;
+
+        let err = state.archive_chat(chat.id as _, &user).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        // workspace owner can manage chats they do not own
+        state.update_workspace_owner(1, 2).await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let chat = state.archive_chat(1, &user).await?;
+        assert!(chat.archived_at.is_some());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn chat_get_by_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -197,7 +1011,10 @@ mod tests {
     #[tokio::test]
     async fn chat_fetch_all_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let chats = state.fetch_chats(1, 1).await.expect("fetch all chats failed");
+        let chats = state
+            .fetch_chats(1, 1)
+            .await
+            .expect("fetch all chats failed");
 
         assert_eq!(chats.len(), 4);
 
@@ -207,19 +1024,48 @@ mod tests {
     #[tokio::test]
     async fn chat_is_member_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let is_member = state.is_chat_member(1, 1).await.expect("is member failed");
+        let is_member = state
+            .is_chat_member(1, 1, 1)
+            .await
+            .expect("is member failed");
         assert!(is_member);
 
         // user 6 doesn't exist
-        let is_member = state.is_chat_member(1, 6).await.expect("is member failed");
+        let is_member = state
+            .is_chat_member(1, 1, 6)
+            .await
+            .expect("is member failed");
         assert!(!is_member);
 
         // chat 10 doesn't exist
-        let is_member = state.is_chat_member(10, 1).await.expect("is member failed");
+        let is_member = state
+            .is_chat_member(1, 10, 1)
+            .await
+            .expect("is member failed");
         assert!(!is_member);
 
         // user 4 is not a member of chat 2
-        let is_member = state.is_chat_member(2, 4).await.expect("is member failed");
+        let is_member = state
+            .is_chat_member(1, 2, 4)
+            .await
+            .expect("is member failed");
+        assert!(!is_member);
+
+        // chat 1 is not in workspace 2
+        let is_member = state
+            .is_chat_member(2, 1, 1)
+            .await
+            .expect("is member failed");
+        assert!(!is_member);
+
+        // deactivated members lose access to their chats
+        sqlx::query("UPDATE workspace_members SET deactivated_at = now() WHERE user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        let is_member = state
+            .is_chat_member(1, 1, 2)
+            .await
+            .expect("is member failed");
         assert!(!is_member);
 
         Ok(())
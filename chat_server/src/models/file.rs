@@ -3,16 +3,74 @@ use std::{
     str::FromStr,
 };
 
-use crate::{AppError, ChatFile};
+use crate::{config::ServerConfig, AppError, AppState, ChatFile};
+use chat_core::FileInfo;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use utoipa::{IntoParams, ToSchema};
+
+const SIGNED_URL_TTL: u64 = 60 * 60;
+const MAX_SIGNED_URL_TTL: u64 = 60 * 60 * 24 * 7;
+// longest extension kept from the uploaded filename
+const MAX_EXT_LEN: usize = 10;
+
+/// Size limits of a single upload request, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadLimits {
+    pub max_file_size: u64,
+    pub max_upload_size: u64,
+    pub quota: u64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct SignFileUrl {
+    /// url of the file as returned by upload, e.g. `/files/1/339/807/e635afbeab088ce33206fdf4223a6bb156.png`
+    pub url: String,
+    /// seconds the signed url works, an hour by default and a week at most
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct SignedFileUrl {
+    /// download url that works without a token, e.g. in an `<img>` tag
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, IntoParams, Serialize, Deserialize)]
+pub struct FileSignature {
+    /// unix timestamp the url expires at
+    pub expires: i64,
+    pub signature: String,
+}
 
 impl ChatFile {
     pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
-        let hash = Sha1::digest(data);
+        Self::from_hash(ws_id, filename, hex::encode(Sha1::digest(data)))
+    }
+
+    /// File with the hex encoded sha1 of its content, e.g. hashed while streamed.
+    ///
+    /// The extension becomes part of the storage key, so anything but a short alphanumeric
+    /// extension of the client's filename is stored as `bin`.
+    pub fn from_hash(ws_id: u64, filename: &str, hash: String) -> Self {
+        let ext = match filename.rsplit_once('.') {
+            Some((_, ext))
+                if (1..=MAX_EXT_LEN).contains(&ext.len())
+                    && ext.bytes().all(|b| b.is_ascii_alphanumeric()) =>
+            {
+                ext
+            }
+            _ => "bin",
+        };
         Self {
             ws_id,
-            ext: filename.split('.').last().unwrap_or("txt").to_string(),
-            hash: hex::encode(hash),
+            ext: ext.to_string(),
+            hash,
         }
     }
 
@@ -21,7 +79,12 @@ impl ChatFile {
     }
 
     pub fn path(&self, base_dir: &Path) -> PathBuf {
-        base_dir.join(self.hash_to_path())
+        base_dir.join(self.key())
+    }
+
+    /// Key of the file in the storage.
+    pub fn key(&self) -> String {
+        self.hash_to_path()
     }
 
     // split hash into 3 parts, first 2 with 3 chars
@@ -75,6 +138,276 @@ impl FromStr for ChatFile {
     }
 }
 
+impl From<&FileInfo> for ChatFile {
+    fn from(info: &FileInfo) -> Self {
+        Self {
+            ws_id: info.ws_id as _,
+            ext: info.ext.clone(),
+            hash: info.hash.clone(),
+        }
+    }
+}
+
+impl From<&ServerConfig> for UploadLimits {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            max_file_size: config.max_file_size,
+            max_upload_size: config.max_upload_size,
+            quota: config.workspace_quota,
+        }
+    }
+}
+
+impl AppState {
+    /// Bytes of uploaded files stored by the workspace.
+    pub async fn workspace_storage_used(&self, ws_id: u64) -> Result<u64, AppError> {
+        let used: Option<i64> =
+            sqlx::query_scalar("SELECT bytes_used FROM workspace_storage WHERE ws_id = $1")
+                .bind(ws_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(used.unwrap_or_default() as u64)
+    }
+
+    /// Count `size` more bytes against the workspace, unless it would go over `quota`.
+    ///
+    /// Returns the bytes used afterwards.
+    pub async fn reserve_workspace_storage(
+        &self,
+        ws_id: u64,
+        size: u64,
+        quota: u64,
+    ) -> Result<u64, AppError> {
+        let used: Option<i64> = sqlx::query_scalar(
+            r#"
+            INSERT INTO workspace_storage (ws_id, bytes_used)
+            SELECT $1, $2 WHERE $2 <= $3
+            ON CONFLICT (ws_id) DO UPDATE
+            SET bytes_used = workspace_storage.bytes_used + EXCLUDED.bytes_used, updated_at = now()
+            WHERE workspace_storage.bytes_used + EXCLUDED.bytes_used <= $3
+            RETURNING bytes_used
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(size as i64)
+        .bind(quota as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        match used {
+            Some(used) => Ok(used as u64),
+            None => Err(AppError::QuotaExceeded {
+                used: self.workspace_storage_used(ws_id).await?,
+                quota,
+            }),
+        }
+    }
+
+    /// Give back bytes reserved for a file that couldn't be stored.
+    pub async fn release_workspace_storage(&self, ws_id: u64, size: u64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE workspace_storage
+            SET bytes_used = GREATEST(bytes_used - $2, 0), updated_at = now()
+            WHERE ws_id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(size as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Record who uploaded the file and what it was called.
+    ///
+    /// Files are stored once per content, uploading it again keeps the first metadata.
+    pub async fn create_file_info(
+        &self,
+        file: &ChatFile,
+        original_name: &str,
+        size: u64,
+        content_type: &str,
+        uploader_id: u64,
+    ) -> Result<FileInfo, AppError> {
+        // the no-op update returns the existing row on conflict
+        let mut info: FileInfo = sqlx::query_as(
+            r#"
+            INSERT INTO files (ws_id, hash, ext, original_name, size, content_type, uploader_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (ws_id, hash, ext) DO UPDATE SET hash = files.hash
+            RETURNING ws_id, hash, ext, original_name, size, content_type, uploader_id, created_at
+            "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(&file.hash)
+        .bind(&file.ext)
+        .bind(original_name)
+        .bind(size as i64)
+        .bind(content_type)
+        .bind(uploader_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO file_uploaders (ws_id, hash, ext, user_id)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(&file.hash)
+        .bind(&file.ext)
+        .bind(uploader_id as i64)
+        .execute(&self.pool)
+        .await?;
+        info.url = file.url();
+        Ok(info)
+    }
+
+    /// Whether the user uploaded the file or can read a message it's attached to.
+    pub async fn can_access_file(&self, file: &ChatFile, user_id: u64) -> Result<bool, AppError> {
+        let allowed = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+              SELECT 1 FROM file_uploaders
+              WHERE ws_id = $1 AND hash = $2 AND ext = $3 AND user_id = $4
+            ) OR EXISTS (
+              SELECT 1
+              FROM messages m
+              JOIN chats c ON c.id = m.chat_id
+              WHERE m.files @> ARRAY[$5]
+              AND m.deleted_at IS NULL
+              AND c.ws_id = $1
+              AND (
+                EXISTS (
+                  SELECT 1 FROM chat_members cm WHERE cm.chat_id = c.id AND cm.user_id = $4
+                )
+                -- public channels are readable by the workspace except guests
+                OR (c.type = 'public_channel' AND EXISTS (
+                  SELECT 1 FROM workspace_members wm
+                  WHERE wm.ws_id = c.ws_id AND wm.user_id = $4
+                  AND wm.role <> 'guest' AND wm.deactivated_at IS NULL
+                ))
+              )
+            )
+            "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(&file.hash)
+        .bind(&file.ext)
+        .bind(user_id as i64)
+        .bind(file.url())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(allowed)
+    }
+
+    /// Url to download a file the user can access without a token until it expires.
+    pub async fn sign_file_url(
+        &self,
+        input: &SignFileUrl,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<SignedFileUrl, AppError> {
+        let file = match input.url.parse::<ChatFile>() {
+            // signed urls are for sharing files of a chat, not uploads nobody can see yet
+            Ok(file)
+                if file.ws_id == ws_id
+                    && self.can_access_file(&file, user_id).await?
+                    && self.is_file_in_chat(&file).await? =>
+            {
+                file
+            }
+            _ => {
+                return Err(AppError::NotFound(
+                    "File doesn't exist or you don't have permission".to_string(),
+                ))
+            }
+        };
+        let ttl = input
+            .expires_in
+            .unwrap_or(SIGNED_URL_TTL)
+            .clamp(1, MAX_SIGNED_URL_TTL);
+        let expires = (Utc::now() + Duration::seconds(ttl as _)).timestamp();
+        let signature = hex::encode(self.file_mac(&file, expires).finalize().into_bytes());
+        Ok(SignedFileUrl {
+            url: format!(
+                "/api/signed{}?expires={}&signature={}",
+                file.url(),
+                expires,
+                signature
+            ),
+            expires_at: DateTime::from_timestamp(expires, 0).unwrap_or_default(),
+        })
+    }
+
+    /// Whether the file is still attached to a message of a chat that wasn't deleted.
+    pub async fn is_file_in_chat(&self, file: &ChatFile) -> Result<bool, AppError> {
+        let ret: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+              SELECT 1
+              FROM messages m
+              JOIN chats c ON c.id = m.chat_id
+              WHERE m.files @> ARRAY[$1]
+              AND m.deleted_at IS NULL
+              AND c.ws_id = $2
+              AND c.archived_at IS NULL
+            )
+            "#,
+        )
+        .bind(file.url())
+        .bind(file.ws_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(ret)
+    }
+
+    pub fn verify_file_signature(&self, file: &ChatFile, input: &FileSignature) -> bool {
+        if input.expires <= Utc::now().timestamp() {
+            return false;
+        }
+        let Ok(signature) = hex::decode(&input.signature) else {
+            return false;
+        };
+        self.file_mac(file, input.expires)
+            .verify_slice(&signature)
+            .is_ok()
+    }
+
+    fn file_mac(&self, file: &ChatFile, expires: i64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.config.auth.file_url_secret.as_bytes())
+            .expect("hmac accepts any key length");
+        mac.update(format!("{}:{}", file.url(), expires).as_bytes());
+        mac
+    }
+
+    /// Metadata of the files, files without any are left out.
+    pub async fn get_file_infos(&self, files: &[ChatFile]) -> Result<Vec<FileInfo>, AppError> {
+        let ws_ids: Vec<i64> = files.iter().map(|f| f.ws_id as i64).collect();
+        let hashes: Vec<&str> = files.iter().map(|f| f.hash.as_str()).collect();
+        let exts: Vec<&str> = files.iter().map(|f| f.ext.as_str()).collect();
+        let mut infos: Vec<FileInfo> = sqlx::query_as(
+            r#"
+            SELECT f.ws_id, f.hash, f.ext, f.original_name, f.size, f.content_type, f.uploader_id,
+              f.created_at
+            FROM files f
+            JOIN unnest($1::bigint[], $2::text[], $3::text[]) AS u(ws_id, hash, ext)
+              ON f.ws_id = u.ws_id AND f.hash = u.hash AND f.ext = u.ext
+            "#,
+        )
+        .bind(ws_ids)
+        .bind(hashes)
+        .bind(exts)
+        .fetch_all(&self.pool)
+        .await?;
+        for info in infos.iter_mut() {
+            info.url = ChatFile::from(&*info).url();
+        }
+        Ok(infos)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -86,4 +419,138 @@ mod tests {
         assert_eq!(file.ext, "txt");
         assert_eq!(file.hash, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
     }
+
+    #[test]
+    fn chat_file_should_not_take_unsafe_extensions() {
+        let file = ChatFile::new(1, "x./../../../../../tmp/pwn", b"hello world");
+        assert_eq!(file.ext, "bin");
+        assert_eq!(
+            file.key(),
+            "1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.bin"
+        );
+        assert_eq!(ChatFile::new(1, "README", b"").ext, "bin");
+        assert_eq!(ChatFile::new(1, "a.verylongextension", b"").ext, "bin");
+        assert_eq!(ChatFile::new(1, "photo.JPG", b"").ext, "JPG");
+    }
+
+    #[tokio::test]
+    async fn reserve_workspace_storage_should_respect_quota() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert_eq!(state.workspace_storage_used(1).await?, 0);
+        assert_eq!(state.reserve_workspace_storage(1, 60, 100).await?, 60);
+        assert_eq!(state.reserve_workspace_storage(1, 40, 100).await?, 100);
+
+        let ret = state.reserve_workspace_storage(1, 1, 100).await;
+        assert!(matches!(
+            ret,
+            Err(AppError::QuotaExceeded {
+                used: 100,
+                quota: 100
+            })
+        ));
+        // a first upload over the quota is rejected as well
+        let ret = state.reserve_workspace_storage(2, 101, 100).await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded { used: 0, .. })));
+        assert_eq!(state.workspace_storage_used(2).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_info_should_keep_first_upload() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "report.pdf", b"report");
+        let info = state
+            .create_file_info(&file, "report.pdf", 6, "application/pdf", 1)
+            .await?;
+        assert_eq!(info.url, file.url());
+        assert_eq!(info.original_name, "report.pdf");
+        assert_eq!(info.size, 6);
+
+        let again = ChatFile::new(1, "copy.pdf", b"report");
+        let info = state
+            .create_file_info(&again, "copy.pdf", 6, "application/pdf", 2)
+            .await?;
+        assert_eq!(info.original_name, "report.pdf");
+        assert_eq!(info.uploader_id, 1);
+
+        let other = ChatFile::new(1, "other.pdf", b"other");
+        let infos = state.get_file_infos(&[file.clone(), other]).await?;
+        assert_eq!(infos, vec![info]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_access_file_should_follow_chats() -> anyhow::Result<()> {
+        use crate::CreateMessage;
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "secret.txt", b"secret");
+        let path = file.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().expect("file path parent should exists"))?;
+        std::fs::write(&path, b"secret")?;
+        state
+            .create_file_info(&file, "secret.txt", 6, "text/plain", 1)
+            .await?;
+        assert!(state.can_access_file(&file, 1).await?);
+        assert!(!state.can_access_file(&file, 2).await?);
+
+        // chat 2 is a private channel of users 1, 2 and 3
+        let input = CreateMessage {
+            content: "secret".to_string(),
+            files: vec![file.url()],
+            reply_to: None,
+        };
+        state.create_message(input, 2, 1).await?;
+        assert!(state.can_access_file(&file, 2).await?);
+        assert!(!state.can_access_file(&file, 4).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signed_file_url_should_expire() -> anyhow::Result<()> {
+        use crate::CreateMessage;
+        use axum::extract::Query;
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "photo.png", b"photo");
+        let path = file.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().expect("file path parent should exists"))?;
+        std::fs::write(&path, b"photo")?;
+        state
+            .create_file_info(&file, "photo.png", 5, "image/png", 1)
+            .await?;
+        let input = SignFileUrl {
+            url: file.url(),
+            expires_in: Some(60),
+        };
+        // not shared in any chat yet
+        let ret = state.sign_file_url(&input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // user 2 is not in chat 4
+        let message = CreateMessage {
+            content: "photo".to_string(),
+            files: vec![file.url()],
+            reply_to: None,
+        };
+        state.create_message(message, 4, 1).await?;
+        let ret = state.sign_file_url(&input, 2, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let signed = state.sign_file_url(&input, 1, 1).await?;
+        let uri: axum::http::Uri = signed.url.parse()?;
+        assert_eq!(uri.path(), format!("/api/signed{}", file.url()));
+        let Query(sig) = Query::<FileSignature>::try_from_uri(&uri)?;
+        assert_eq!(sig.expires, signed.expires_at.timestamp());
+        assert!(state.verify_file_signature(&file, &sig));
+
+        let other = ChatFile::new(1, "other.png", b"other");
+        assert!(!state.verify_file_signature(&other, &sig));
+        let expired = FileSignature {
+            expires: Utc::now().timestamp() - 1,
+            ..sig
+        };
+        assert!(!state.verify_file_signature(&file, &expired));
+        Ok(())
+    }
 }
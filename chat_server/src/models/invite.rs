@@ -0,0 +1,343 @@
+use crate::{AppError, AppState};
+use chat_core::{User, Workspace, WorkspaceRole};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use super::refresh_token::{generate_token, hash_token};
+
+// invites are valid for a week unless specified otherwise
+const DEFAULT_INVITE_TTL: u64 = 60 * 60 * 24 * 7;
+const MAX_INVITE_TTL: u64 = 60 * 60 * 24 * 30;
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct CreateInvite {
+    pub email: String,
+    /// role of the user after signing up, defaults to member
+    #[serde(default)]
+    pub role: Option<WorkspaceRole>,
+    /// seconds until the invite expires, defaults to 7 days and 30 days at most
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Invite {
+    pub id: i64,
+    #[serde(alias = "wsId")]
+    pub ws_id: i64,
+    pub email: String,
+    pub role: WorkspaceRole,
+    #[serde(alias = "createdBy")]
+    pub created_by: i64,
+    #[serde(alias = "expiresAt")]
+    pub expires_at: DateTime<Utc>,
+    #[serde(alias = "acceptedAt")]
+    pub accepted_at: Option<DateTime<Utc>>,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// A freshly created invite, the plain token is only returned once.
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct InviteOutput {
+    #[serde(flatten)]
+    pub invite: Invite,
+    pub token: String,
+}
+
+impl AppState {
+    /// Invite an email to the workspace, only owners and admins can invite.
+    pub async fn create_invite(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        input: CreateInvite,
+    ) -> Result<InviteOutput, AppError> {
+        self.verify_workspace_admin(ws_id, user_id).await?;
+        let role = input.role.unwrap_or(WorkspaceRole::Member);
+        if role == WorkspaceRole::Owner {
+            return Err(AppError::PermissionDenied(
+                "can not invite a workspace owner".to_string(),
+            ));
+        }
+        if role == WorkspaceRole::Admin
+            && self.get_workspace_role(ws_id, user_id).await? != WorkspaceRole::Owner
+        {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner can invite admins".to_string(),
+            ));
+        }
+        let email = input.email.trim().to_lowercase();
+        // users of other workspaces can be invited, they accept with their own account
+        if let Some(user) = self.find_user_by_email(&email).await? {
+            if self.is_workspace_member(ws_id, user.id as _).await? {
+                return Err(AppError::EmailAlreadyExists(email));
+            }
+        }
+
+        let (token, token_hash) = generate_token();
+        let ttl = input.expires_in.unwrap_or(DEFAULT_INVITE_TTL);
+        if !(1..=MAX_INVITE_TTL).contains(&ttl) {
+            return Err(AppError::InviteError(format!(
+                "expires_in must be between 1 and {} seconds",
+                MAX_INVITE_TTL
+            )));
+        }
+        let expires_at = Utc::now() + Duration::seconds(ttl as i64);
+        let invite = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_invites (ws_id, email, role, token_hash, created_by, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, ws_id, email, role, created_by, expires_at, accepted_at, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&email)
+        .bind(role)
+        .bind(token_hash)
+        .bind(user_id as i64)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(InviteOutput { invite, token })
+    }
+
+    /// List invites of the workspace that are not accepted yet.
+    pub async fn list_invites(&self, ws_id: u64, user_id: u64) -> Result<Vec<Invite>, AppError> {
+        self.verify_workspace_admin(ws_id, user_id).await?;
+        let invites = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, email, role, created_by, expires_at, accepted_at, created_at
+            FROM workspace_invites
+            WHERE ws_id = $1 AND accepted_at IS NULL
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invites)
+    }
+
+    /// Revoke an invite which is not accepted yet.
+    pub async fn delete_invite(&self, id: u64, ws_id: u64, user_id: u64) -> Result<(), AppError> {
+        self.verify_workspace_admin(ws_id, user_id).await?;
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM workspace_invites
+            WHERE id = $1 AND ws_id = $2 AND accepted_at IS NULL
+            "#,
+        )
+        .bind(id as i64)
+        .bind(ws_id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("invite {}", id)));
+        }
+        Ok(())
+    }
+
+    /// Accept an invite with an existing account, the user joins the workspace
+    /// with the role of the invite.
+    pub async fn accept_invite(&self, user: &User, token: &str) -> Result<Workspace, AppError> {
+        let invite = match self.find_valid_invite(token).await? {
+            Some(invite) if invite.email.eq_ignore_ascii_case(&user.email) => invite,
+            _ => {
+                return Err(AppError::PermissionDenied(
+                    "invalid or expired invite".to_string(),
+                ))
+            }
+        };
+        let Some(ws) = self.find_workspace_by_id(invite.ws_id as _).await? else {
+            return Err(AppError::NotFound(format!("workspace {}", invite.ws_id)));
+        };
+
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            "UPDATE workspace_invites SET accepted_at = now() WHERE id = $1 AND accepted_at IS NULL",
+        )
+        .bind(invite.id)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::PermissionDenied(
+                "invalid or expired invite".to_string(),
+            ));
+        }
+        // an existing membership keeps its role and status
+        sqlx::query(
+            r#"
+            INSERT INTO workspace_members (ws_id, user_id, role)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (ws_id, user_id) DO NOTHING
+            "#,
+        )
+        .bind(invite.ws_id)
+        .bind(user.id)
+        .bind(invite.role)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(ws)
+    }
+
+    /// Join the workspace the user signed up to through its allowed email domain,
+    /// the domain only vouches for users who proved they own the email.
+    pub(crate) async fn join_allowed_workspace(&self, user_id: i64) -> Result<(), AppError> {
+        let Some(user) = self.find_user_by_id(user_id).await? else {
+            return Ok(());
+        };
+        let Some(ws) = self.find_workspace_by_id(user.ws_id as _).await? else {
+            return Ok(());
+        };
+        if !is_allowed_email(&ws, &user.email) {
+            return Ok(());
+        }
+        // an existing membership keeps its role and status
+        sqlx::query(
+            r#"
+            INSERT INTO workspace_members (ws_id, user_id, role)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (ws_id, user_id) DO NOTHING
+            "#,
+        )
+        .bind(ws.id)
+        .bind(user.id)
+        .bind(WorkspaceRole::Member)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Find an invite that can still be accepted.
+    pub async fn find_valid_invite(&self, token: &str) -> Result<Option<Invite>, AppError> {
+        let invite = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, email, role, created_by, expires_at, accepted_at, created_at
+            FROM workspace_invites
+            WHERE token_hash = $1 AND accepted_at IS NULL AND expires_at > now()
+            "#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(invite)
+    }
+}
+
+/// Whether the email belongs to the domain the workspace allows to join freely.
+pub(crate) fn is_allowed_email(ws: &Workspace, email: &str) -> bool {
+    let Some(domain) = ws.allowed_domain.as_deref() else {
+        return false;
+    };
+    email
+        .rsplit_once('@')
+        .is_some_and(|(_, d)| d.eq_ignore_ascii_case(domain))
+}
+
+/// Token of an invite sent to an existing account.
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct AcceptInvite {
+    pub token: String,
+}
+
+#[cfg(test)]
+impl CreateInvite {
+    pub fn new(email: &str, role: Option<WorkspaceRole>) -> Self {
+        Self {
+            email: email.to_string(),
+            role,
+            expires_in: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn create_and_list_invites_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateInvite::new("Tyr@acme.org", Some(WorkspaceRole::Admin));
+        let ret = state.create_invite(1, 1, input).await?;
+        assert_eq!(ret.invite.email, "tyr@acme.org");
+        assert_eq!(ret.invite.role, WorkspaceRole::Admin);
+
+        let invite = state.find_valid_invite(&ret.token).await?;
+        assert_eq!(invite, Some(ret.invite.clone()));
+
+        let invites = state.list_invites(1, 1).await?;
+        assert_eq!(invites.len(), 1);
+
+        state.delete_invite(ret.invite.id as _, 1, 1).await?;
+        assert!(state.find_valid_invite(&ret.token).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_invite_with_invalid_ttl_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        for expires_in in [0, MAX_INVITE_TTL + 1, u64::MAX] {
+            let mut input = CreateInvite::new("tyr@acme.org", None);
+            input.expires_in = Some(expires_in);
+            let ret = state.create_invite(1, 1, input).await;
+            assert!(matches!(ret, Err(AppError::InviteError(_))));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn member_create_invite_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateInvite::new("tyr@acme.org", None);
+        let ret = state.create_invite(1, 2, input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_existing_user_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateInvite::new("alice@acme.org", None);
+        let ret = state.create_invite(1, 1, input).await;
+        assert!(matches!(ret, Err(AppError::EmailAlreadyExists(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_invite_should_join_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateInvite::new("alice@acme.org", Some(WorkspaceRole::Guest));
+        let ret = state.create_invite(2, 1, input).await;
+        // user 1 is not a member of foo
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        sqlx::query("INSERT INTO workspace_members (ws_id, user_id, role) VALUES (2, 1, 'member')")
+            .execute(&state.pool)
+            .await?;
+        state.update_workspace_owner(2, 1).await?;
+        let input = CreateInvite::new("alice@acme.org", Some(WorkspaceRole::Guest));
+        let ret = state.create_invite(2, 1, input).await?;
+
+        let alice = state.find_user_by_id(2).await?.expect("user should exist");
+        let ws = state.accept_invite(&alice, &ret.token).await?;
+        assert_eq!(ws.id, 2);
+        assert_eq!(state.get_workspace_role(2, 2).await?, WorkspaceRole::Guest);
+
+        // invites can only be used once
+        let ret = state.accept_invite(&alice, &ret.token).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+}
@@ -1,14 +1,43 @@
 use crate::{AppError, AppState, ChatFile};
-use chat_core::Message;
+use chat_core::{Chat, ChatUser, FileInfo, Message, MessageEdit, Reaction};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use sqlx::FromRow;
+use std::{
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+};
 use utoipa::{IntoParams, ToSchema};
 
+const SEARCH_PAGE_SIZE: i64 = 20;
+const SEARCH_MAX_PAGE_SIZE: i64 = 100;
+
 #[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
 pub struct CreateMessage {
     pub content: String,
     #[serde(default)]
     pub files: Vec<String>,
+    /// message being replied to, the thread root is derived from it
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct UpdateMessage {
+    pub content: String,
+}
+
+#[derive(Debug, Clone, IntoParams, Serialize, ToSchema, Deserialize)]
+pub struct CreateReaction {
+    pub emoji: String,
+}
+
+#[derive(Debug, FromRow)]
+struct ReactionRow {
+    message_id: i64,
+    emoji: String,
+    count: i64,
+    reacted: bool,
 }
 
 #[derive(Debug, Clone, IntoParams, Serialize, ToSchema, Deserialize)]
@@ -19,6 +48,44 @@ pub struct ListMessages {
     pub limit: u64,
 }
 
+/// Full-text search in the user's chats, `has:file` in the query acts like `has_file=true`.
+#[derive(Debug, Clone, Default, IntoParams, Serialize, ToSchema, Deserialize)]
+pub struct SearchMessages {
+    pub q: String,
+    #[serde(default)]
+    pub chat_id: Option<u64>,
+    #[serde(default)]
+    pub sender_id: Option<u64>,
+    #[serde(default)]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub has_file: bool,
+    #[serde(default)]
+    pub last_id: Option<u64>,
+    /// page size, 20 if left out or 0 and 100 at most
+    #[serde(default)]
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema, Deserialize)]
+pub struct SearchResult {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub message: Message,
+    /// HTML-escaped content with matched terms wrapped in `<mark>`
+    pub snippet: String,
+}
+
+// `@123` / `@alice` (email handle) mention a user, `@channel` / `@here` the whole chat
+#[derive(Debug, Clone, PartialEq)]
+enum Mention {
+    User(String),
+    Channel,
+    Here,
+}
+
 #[allow(dead_code)]
 impl AppState {
     pub async fn create_message(
@@ -27,7 +94,6 @@ impl AppState {
         chat_id: u64,
         user_id: u64,
     ) -> Result<Message, AppError> {
-        let base_dir = &self.config.server.base_dir;
         // verify content - not empty
         if input.content.is_empty() {
             return Err(AppError::CreateMessageError(
@@ -35,10 +101,24 @@ impl AppState {
             ));
         }
 
-        // verify files exist
+        // verify chat is not archived
+        let Some(chat) = self.get_chat_by_id(chat_id).await? else {
+            return Err(AppError::NotFound(format!("chat id {chat_id}")));
+        };
+        if chat.archived_at.is_some() {
+            return Err(AppError::CreateMessageError(format!(
+                "chat {chat_id} is archived"
+            )));
+        }
+
+        // verify files exist and the sender can share them, otherwise a leaked url could be
+        // attached to get access to the file
         for s in &input.files {
             let file = ChatFile::from_str(s)?;
-            if !file.path(base_dir).exists() {
+            if file.ws_id != chat.ws_id as u64
+                || !self.storage.exists(&file.key()).await?
+                || !self.can_access_file(&file, user_id).await?
+            {
                 return Err(AppError::CreateMessageError(format!(
                     "File {} doesn't exist",
                     s
@@ -46,28 +126,174 @@ impl AppState {
             }
         }
 
+        // verify replied message exists in the same chat
+        let thread_root_id = match input.reply_to {
+            Some(id) => match self.get_message(chat_id, id).await? {
+                Some(msg) if !msg.deleted => Some(msg.thread_root_id.unwrap_or(msg.id)),
+                _ => {
+                    return Err(AppError::CreateMessageError(format!(
+                        "Message {id} doesn't exist in chat {chat_id}"
+                    )))
+                }
+            },
+            None => None,
+        };
+
+        // verify mentioned users are chat members
+        let mentions = self
+            .resolve_mentions(&input.content, &chat, user_id)
+            .await?;
+
         // create message
+        let mut tx = self.pool.begin().await?;
         let message: Message = sqlx::query_as(
             r#"
-          INSERT INTO messages (chat_id, sender_id, content, files)
-          VALUES ($1, $2, $3, $4)
-          RETURNING id, chat_id, sender_id, content, files, created_at
+          INSERT INTO messages (chat_id, sender_id, content, files, reply_to, thread_root_id)
+          VALUES ($1, $2, $3, $4, $5, $6)
+          RETURNING id, chat_id, sender_id, content, files, created_at, updated_at, deleted_at,
+            reply_to, thread_root_id
           "#,
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
         .bind(input.content)
         .bind(&input.files)
-        .fetch_one(&self.pool)
+        .bind(input.reply_to.map(|id| id as i64))
+        .bind(thread_root_id)
+        .fetch_one(&mut *tx)
         .await?;
 
+        if !mentions.is_empty() {
+            sqlx::query(
+                r#"
+                INSERT INTO message_mentions (message_id, user_id)
+                SELECT $1, unnest($2::bigint[])
+                "#,
+            )
+            .bind(message.id)
+            .bind(&mentions)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
         Ok(message)
     }
 
+    // user ids mentioned in content, excluding the sender.
+    // Tokens that don't name a workspace user are plain text, users outside the chat are rejected.
+    async fn resolve_mentions(
+        &self,
+        content: &str,
+        chat: &Chat,
+        user_id: u64,
+    ) -> Result<Vec<i64>, AppError> {
+        let mentions = parse_mentions(content);
+        if mentions.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (ids, handles): (Vec<_>, Vec<_>) = mentions
+            .iter()
+            .filter_map(|m| match m {
+                Mention::User(token) => Some(token),
+                Mention::Channel | Mention::Here => None,
+            })
+            .partition(|token| token.parse::<i64>().is_ok());
+        let ids: Vec<i64> = ids.iter().filter_map(|id| id.parse().ok()).collect();
+        let handles: Vec<String> = handles.iter().map(|h| h.to_lowercase()).collect();
+        let users: Vec<ChatUser> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email
+            FROM users u
+            JOIN workspace_members wm ON wm.user_id = u.id
+            WHERE wm.ws_id = $1 AND wm.deactivated_at IS NULL
+            AND (u.id = ANY($2) OR lower(split_part(u.email, '@', 1)) = ANY($3))
+            "#,
+        )
+        .bind(chat.ws_id)
+        .bind(&ids)
+        .bind(&handles)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let members = &chat.members;
+        let mut mentioned = BTreeSet::new();
+        for mention in mentions {
+            match mention {
+                Mention::Channel | Mention::Here => mentioned.extend(members.iter().copied()),
+                Mention::User(token) => {
+                    let user = users.iter().find(|u| match token.parse::<i64>() {
+                        Ok(id) => u.id == id,
+                        Err(_) => u
+                            .email
+                            .split('@')
+                            .next()
+                            .is_some_and(|handle| handle.eq_ignore_ascii_case(&token)),
+                    });
+                    let Some(user) = user else {
+                        continue;
+                    };
+                    if !members.contains(&user.id) {
+                        return Err(AppError::CreateMessageError(format!(
+                            "Mentioned user @{token} is not a member of chat {}",
+                            chat.id
+                        )));
+                    }
+                    mentioned.insert(user.id);
+                }
+            }
+        }
+        mentioned.remove(&(user_id as i64));
+
+        Ok(mentioned.into_iter().collect())
+    }
+
+    /// Messages mentioning the user in chats they are still a member of, newest first.
+    pub async fn list_mentions(
+        &self,
+        input: ListMessages,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<Vec<Message>, AppError> {
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let limit = match input.limit {
+            0 => i64::MAX,
+            1..=100 => input.limit as _,
+            _ => 100,
+        };
+
+        let messages = sqlx::query_as(
+            r#"
+        SELECT m.id, m.chat_id, m.sender_id, m.content, m.files, m.created_at, m.updated_at,
+          m.deleted_at, m.reply_to, m.thread_root_id
+        FROM message_mentions mm
+        JOIN messages m ON m.id = mm.message_id
+        JOIN chats c ON c.id = m.chat_id
+        JOIN chat_members cm ON cm.chat_id = m.chat_id AND cm.user_id = mm.user_id
+        WHERE mm.user_id = $1
+        AND c.ws_id = $2
+        AND m.deleted_at IS NULL
+        AND m.id < $3
+        ORDER BY m.id DESC
+        LIMIT $4
+        "#,
+        )
+        .bind(user_id as i64)
+        .bind(ws_id as i64)
+        .bind(last_id as i64)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages)
+    }
+
     pub async fn list_messages(
         &self,
         input: ListMessages,
         chat_id: u64,
+        user_id: u64,
     ) -> Result<Vec<Message>, AppError> {
         let last_id = input.last_id.unwrap_or(i64::MAX as _);
         let limit = match input.limit {
@@ -76,11 +302,18 @@ impl AppState {
             _ => 100,
         };
 
-        let messages: Vec<Message> = sqlx::query_as(
+        let mut messages: Vec<Message> = sqlx::query_as(
             r#"
-        SELECT id, chat_id, sender_id, content, files, created_at
-        FROM messages
+        SELECT id, chat_id, sender_id, content, files, created_at, updated_at, deleted_at,
+          deleted_at IS NOT NULL AS deleted, reply_to, thread_root_id, reply_count, last_reply_at
+        FROM messages m
+        LEFT JOIN LATERAL (
+          SELECT count(*) AS reply_count, max(created_at) AS last_reply_at
+          FROM messages r
+          WHERE r.thread_root_id = m.id AND r.deleted_at IS NULL
+        ) t ON true
         WHERE chat_id = $1
+        AND thread_root_id IS NULL
         AND id < $2
         ORDER BY id DESC
         LIMIT $3
@@ -91,9 +324,440 @@ impl AppState {
         .bind(limit)
         .fetch_all(&self.pool)
         .await?;
+        self.fill_reactions(&mut messages, user_id).await?;
+        self.fill_attachments(&mut messages).await?;
 
         Ok(messages)
     }
+
+    /// Replies in the thread of a message, newest first.
+    pub async fn list_thread_messages(
+        &self,
+        input: ListMessages,
+        chat_id: u64,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Vec<Message>, AppError> {
+        let Some(message) = self.get_message(chat_id, id).await? else {
+            return Err(AppError::NotFound(format!("message id {id}")));
+        };
+        let root_id = message.thread_root_id.unwrap_or(message.id);
+
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let limit = match input.limit {
+            0 => i64::MAX,
+            1..=100 => input.limit as _,
+            _ => 100,
+        };
+
+        let mut messages: Vec<Message> = sqlx::query_as(
+            r#"
+        SELECT id, chat_id, sender_id, content, files, created_at, updated_at, deleted_at,
+          deleted_at IS NOT NULL AS deleted, reply_to, thread_root_id
+        FROM messages
+        WHERE thread_root_id = $1
+        AND id < $2
+        ORDER BY id DESC
+        LIMIT $3
+        "#,
+        )
+        .bind(root_id)
+        .bind(last_id as i64)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        self.fill_reactions(&mut messages, user_id).await?;
+        self.fill_attachments(&mut messages).await?;
+
+        Ok(messages)
+    }
+
+    pub async fn get_message(&self, chat_id: u64, id: u64) -> Result<Option<Message>, AppError> {
+        let message = sqlx::query_as(
+            r#"
+        SELECT id, chat_id, sender_id, content, files, created_at, updated_at, deleted_at,
+          deleted_at IS NOT NULL AS deleted, reply_to, thread_root_id, reply_count, last_reply_at
+        FROM messages m
+        LEFT JOIN LATERAL (
+          SELECT count(*) AS reply_count, max(created_at) AS last_reply_at
+          FROM messages r
+          WHERE r.thread_root_id = m.id AND r.deleted_at IS NULL
+        ) t ON true
+        WHERE chat_id = $1 AND id = $2
+        "#,
+        )
+        .bind(chat_id as i64)
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(message)
+    }
+
+    /// Edit a message, the previous revision is kept in message_edits.
+    pub async fn update_message(
+        &self,
+        input: UpdateMessage,
+        chat_id: u64,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        if input.content.is_empty() {
+            return Err(AppError::UpdateMessageError(
+                "Content cannot be empty".to_string(),
+            ));
+        }
+        let message = self.verify_message_sender(chat_id, id, user_id).await?;
+        if message.content == input.content {
+            return Ok(message);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO message_edits (message_id, content, files, created_at)
+            SELECT id, content, files, COALESCE(updated_at, created_at)
+            FROM messages
+            WHERE id = $1
+            "#,
+        )
+        .bind(id as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE messages
+            SET content = $2, updated_at = now()
+            WHERE id = $1
+            "#,
+        )
+        .bind(id as i64)
+        .bind(input.content)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        let message = self
+            .get_message(chat_id, id)
+            .await?
+            .expect("message should exist");
+        Ok(message)
+    }
+
+    /// Delete a message, leaving a tombstone without content or history.
+    pub async fn delete_message(
+        &self,
+        chat_id: u64,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        self.verify_message_sender(chat_id, id, user_id).await?;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM message_edits WHERE message_id = $1")
+            .bind(id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM message_reactions WHERE message_id = $1")
+            .bind(id as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE messages
+            SET content = '', files = '{}', deleted_at = now()
+            WHERE id = $1
+            "#,
+        )
+        .bind(id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        let message = self
+            .get_message(chat_id, id)
+            .await?
+            .expect("message should exist");
+        Ok(message)
+    }
+
+    /// Search messages of non-archived chats the user is a member of, newest first.
+    pub async fn search_messages(
+        &self,
+        input: SearchMessages,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<Vec<SearchResult>, AppError> {
+        let mut has_file = input.has_file;
+        let q = input
+            .q
+            .split_whitespace()
+            .filter(|term| {
+                let is_filter = *term == "has:file";
+                has_file |= is_filter;
+                !is_filter
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        if q.is_empty() {
+            return Err(AppError::SearchError("Query cannot be empty".to_string()));
+        }
+
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let limit = search_page_size(input.limit);
+
+        let results = sqlx::query_as(
+            r#"
+        SELECT m.id, m.chat_id, m.sender_id, m.content, m.files, m.created_at, m.updated_at,
+          m.deleted_at, m.reply_to, m.thread_root_id,
+          ts_headline('english', replace(replace(replace(replace(replace(m.content,
+            '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '"', '&quot;'), '''', '&#39;'),
+            q, 'StartSel=<mark>, StopSel=</mark>') AS snippet
+        FROM messages m
+        JOIN chats c ON c.id = m.chat_id
+        JOIN chat_members cm ON cm.chat_id = m.chat_id AND cm.user_id = $1,
+          websearch_to_tsquery('english', $3) q
+        WHERE c.ws_id = $2
+        AND c.archived_at IS NULL
+        AND m.deleted_at IS NULL
+        AND m.content_tsv @@ q
+        AND ($4::bigint IS NULL OR m.chat_id = $4)
+        AND ($5::bigint IS NULL OR m.sender_id = $5)
+        AND ($6::timestamptz IS NULL OR m.created_at >= $6)
+        AND ($7::timestamptz IS NULL OR m.created_at < $7)
+        AND (NOT $8 OR cardinality(m.files) > 0)
+        AND m.id < $9
+        ORDER BY m.id DESC
+        LIMIT $10
+        "#,
+        )
+        .bind(user_id as i64)
+        .bind(ws_id as i64)
+        .bind(q)
+        .bind(input.chat_id.map(|id| id as i64))
+        .bind(input.sender_id.map(|id| id as i64))
+        .bind(input.from)
+        .bind(input.to)
+        .bind(has_file)
+        .bind(last_id as i64)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(results)
+    }
+
+    /// React to a message, reacting twice with the same emoji is a no-op.
+    pub async fn add_reaction(
+        &self,
+        input: CreateReaction,
+        chat_id: u64,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Vec<Reaction>, AppError> {
+        if input.emoji.is_empty() || input.emoji.chars().count() > 32 {
+            return Err(AppError::UpdateMessageError(
+                "Emoji must be 1 to 32 characters".to_string(),
+            ));
+        }
+        match self.get_message(chat_id, id).await? {
+            Some(msg) if !msg.deleted => {}
+            _ => return Err(AppError::NotFound(format!("message id {id}"))),
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO message_reactions (message_id, user_id, emoji)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .bind(&input.emoji)
+        .execute(&self.pool)
+        .await?;
+
+        self.fetch_reactions(id, user_id).await
+    }
+
+    pub async fn remove_reaction(
+        &self,
+        input: CreateReaction,
+        chat_id: u64,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Vec<Reaction>, AppError> {
+        if self.get_message(chat_id, id).await?.is_none() {
+            return Err(AppError::NotFound(format!("message id {id}")));
+        }
+
+        sqlx::query(
+            r#"
+            DELETE FROM message_reactions
+            WHERE message_id = $1 AND user_id = $2 AND emoji = $3
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .bind(&input.emoji)
+        .execute(&self.pool)
+        .await?;
+
+        self.fetch_reactions(id, user_id).await
+    }
+
+    async fn fetch_reactions(&self, id: u64, user_id: u64) -> Result<Vec<Reaction>, AppError> {
+        let mut reactions = self.load_reactions(&[id as i64], user_id).await?;
+        Ok(reactions.remove(&(id as i64)).unwrap_or_default())
+    }
+
+    async fn fill_reactions(&self, messages: &mut [Message], user_id: u64) -> Result<(), AppError> {
+        let ids: Vec<i64> = messages.iter().map(|m| m.id).collect();
+        let mut reactions = self.load_reactions(&ids, user_id).await?;
+        for message in messages.iter_mut() {
+            message.reactions = reactions.remove(&message.id).unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    // files uploaded before their metadata was recorded have no attachment
+    async fn fill_attachments(&self, messages: &mut [Message]) -> Result<(), AppError> {
+        let files: Vec<ChatFile> = messages
+            .iter()
+            .flat_map(|m| &m.files)
+            .filter_map(|url| url.parse().ok())
+            .collect();
+        if files.is_empty() {
+            return Ok(());
+        }
+        let infos: HashMap<String, FileInfo> = self
+            .get_file_infos(&files)
+            .await?
+            .into_iter()
+            .map(|info| (info.url.clone(), info))
+            .collect();
+        for message in messages.iter_mut() {
+            message.attachments = message
+                .files
+                .iter()
+                .filter_map(|url| infos.get(url).cloned())
+                .collect();
+        }
+        Ok(())
+    }
+
+    // reactions aggregated per message and emoji, in the order emojis were first used
+    async fn load_reactions(
+        &self,
+        ids: &[i64],
+        user_id: u64,
+    ) -> Result<HashMap<i64, Vec<Reaction>>, AppError> {
+        let rows: Vec<ReactionRow> = sqlx::query_as(
+            r#"
+            SELECT message_id, emoji, count(*) AS count, bool_or(user_id = $2) AS reacted
+            FROM message_reactions
+            WHERE message_id = ANY($1)
+            GROUP BY message_id, emoji
+            ORDER BY message_id, min(created_at)
+            "#,
+        )
+        .bind(ids)
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut reactions: HashMap<i64, Vec<Reaction>> = HashMap::new();
+        for row in rows {
+            reactions.entry(row.message_id).or_default().push(Reaction {
+                emoji: row.emoji,
+                count: row.count,
+                reacted: row.reacted,
+            });
+        }
+        Ok(reactions)
+    }
+
+    /// Prior revisions of a message, oldest first.
+    pub async fn list_message_edits(
+        &self,
+        chat_id: u64,
+        id: u64,
+    ) -> Result<Vec<MessageEdit>, AppError> {
+        if self.get_message(chat_id, id).await?.is_none() {
+            return Err(AppError::NotFound(format!("message id {id}")));
+        }
+
+        let edits = sqlx::query_as(
+            r#"
+            SELECT id, message_id, content, files, created_at
+            FROM message_edits
+            WHERE message_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(edits)
+    }
+
+    // only the sender may change a message, and deleted ones stay deleted
+    async fn verify_message_sender(
+        &self,
+        chat_id: u64,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        let Some(message) = self.get_message(chat_id, id).await? else {
+            return Err(AppError::NotFound(format!("message id {id}")));
+        };
+        if message.sender_id != user_id as i64 {
+            return Err(AppError::PermissionDenied(format!(
+                "message {id} is not sent by user {user_id}"
+            )));
+        }
+        if message.deleted {
+            return Err(AppError::UpdateMessageError(format!(
+                "message {id} is deleted"
+            )));
+        }
+
+        Ok(message)
+    }
+}
+
+// `limit` of the search endpoint, unlike listing it never returns everything
+fn search_page_size(limit: u64) -> i64 {
+    match limit {
+        0 => SEARCH_PAGE_SIZE,
+        limit => limit.min(SEARCH_MAX_PAGE_SIZE as u64) as i64,
+    }
+}
+
+fn parse_mentions(content: &str) -> Vec<Mention> {
+    let is_handle_char = |c: char| c.is_alphanumeric() || matches!(c, '.' | '_' | '-');
+    let mut mentions = vec![];
+    let mut prev = None;
+    for (i, c) in content.char_indices() {
+        // `@` inside a word (e.g. an email address) is not a mention
+        if c == '@' && !prev.is_some_and(is_handle_char) {
+            let rest = &content[i + 1..];
+            let end = rest.find(|c| !is_handle_char(c)).unwrap_or(rest.len());
+            let token = rest[..end].trim_end_matches(['.', '-']);
+            match token {
+                "" => {}
+                "channel" => mentions.push(Mention::Channel),
+                "here" => mentions.push(Mention::Here),
+                _ => mentions.push(Mention::User(token.to_string())),
+            }
+        }
+        prev = Some(c);
+    }
+    mentions
 }
 
 #[cfg(test)]
@@ -107,6 +771,7 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec![],
+            reply_to: None,
         };
         let message = state
             .create_message(input, 1, 1)
@@ -118,16 +783,18 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec!["1".to_string()],
+            reply_to: None,
         };
 
         let err = state.create_message(input, 1, 1).await.unwrap_err();
         assert_eq!(err.to_string(), "Invalid chat file path: 1");
 
         // valid files should work
-        let url = upload_dummy_file(&state)?;
+        let url = upload_dummy_file(&state).await?;
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec![url],
+            reply_to: None,
         };
 
         let message = state
@@ -140,15 +807,57 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn list_messages_should_include_attachments() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let url = upload_dummy_file(&state).await?;
+        let input = CreateMessage {
+            content: "see attached".to_string(),
+            files: vec![url.clone()],
+            reply_to: None,
+        };
+        state.create_message(input, 1, 1).await?;
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 1,
+        };
+        let messages = state.list_messages(input, 1, 1).await?;
+        let attachments = &messages[0].attachments;
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].url, url);
+        assert_eq!(attachments[0].original_name, "test.txt");
+        assert_eq!(attachments[0].content_type, "text/plain");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_in_archived_chat_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        state.update_workspace_owner(1, 1).await?;
+        state.archive_chat(1, &user).await?;
+
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            files: vec![],
+            reply_to: None,
+        };
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "create message error: chat 1 is archived");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn list_messages_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
+        state.seed_messages().await?;
         let input = ListMessages {
             last_id: None,
             limit: 6,
         };
 
-        let messages = state.list_messages(input, 1).await?;
+        let messages = state.list_messages(input, 1, 1).await?;
         assert_eq!(messages.len(), 6);
 
         let last_id = messages.last().expect("last message should exists").id;
@@ -158,17 +867,355 @@ mod tests {
             limit: 6,
         };
 
-        let messages = state.list_messages(input, 1).await?;
+        let messages = state.list_messages(input, 1, 1).await?;
         assert_eq!(messages.len(), 4);
 
         Ok(())
     }
 
-    fn upload_dummy_file(state: &AppState) -> Result<String> {
+    #[tokio::test]
+    async fn update_message_should_keep_history() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.seed_messages().await?;
+        let input = UpdateMessage {
+            content: "Hello, rust!".to_string(),
+        };
+        let message = state.update_message(input, 1, 1, 1).await?;
+        assert_eq!(message.content, "Hello, rust!");
+        assert!(message.updated_at.is_some());
+
+        let input = UpdateMessage {
+            content: "Hello, chat!".to_string(),
+        };
+        state.update_message(input, 1, 1, 1).await?;
+
+        let edits = state.list_message_edits(1, 1).await?;
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].content, "Hello, world!");
+        assert_eq!(edits[1].content, "Hello, rust!");
+
+        // only the sender can edit
+        let input = UpdateMessage {
+            content: "hacked".to_string(),
+        };
+        let err = state.update_message(input, 1, 1, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_message_should_leave_tombstone() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.seed_messages().await?;
+        let err = state.delete_message(1, 2, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let message = state.delete_message(1, 1, 1).await?;
+        assert!(message.deleted);
+        assert!(message.content.is_empty());
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 0,
+        };
+        let messages = state.list_messages(input, 1, 1).await?;
+        assert_eq!(messages.len(), 10);
+        let tombstone = messages.last().expect("last message should exists");
+        assert_eq!(tombstone.id, 1);
+        assert!(tombstone.deleted);
+
+        // deleted message can't be edited
+        let input = UpdateMessage {
+            content: "back".to_string(),
+        };
+        let err = state.update_message(input, 1, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update message error: message 1 is deleted"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn thread_replies_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.seed_messages().await?;
+        let input = CreateMessage {
+            content: "reply".to_string(),
+            files: vec![],
+            reply_to: Some(1),
+        };
+        let reply = state.create_message(input, 1, 2).await?;
+        assert_eq!(reply.thread_root_id, Some(1));
+
+        // replying to a reply stays in the same thread
+        let input = CreateMessage {
+            content: "reply to reply".to_string(),
+            files: vec![],
+            reply_to: Some(reply.id as _),
+        };
+        let message = state.create_message(input, 1, 3).await?;
+        assert_eq!(message.reply_to, Some(reply.id));
+        assert_eq!(message.thread_root_id, Some(1));
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 1,
+        };
+        let messages = state.list_thread_messages(input, 1, 1, 1).await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, message.id);
+
+        // replies stay out of the chat timeline, roots carry the stats
+        let input = ListMessages {
+            last_id: None,
+            limit: 0,
+        };
+        let messages = state.list_messages(input, 1, 1).await?;
+        assert_eq!(messages.len(), 10);
+        let root = messages.last().expect("last message should exists");
+        assert_eq!(root.reply_count, 2);
+        assert_eq!(root.last_reply_at, Some(message.created_at));
+
+        // replying to a message in another chat should fail
+        let input = CreateMessage {
+            content: "reply".to_string(),
+            files: vec![],
+            reply_to: Some(1),
+        };
+        let err = state.create_message(input, 2, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Message 1 doesn't exist in chat 2"
+        );
+
+        // replies carry the metadata of their files
+        let url = upload_dummy_file(&state).await?;
+        let input = CreateMessage {
+            content: "reply with a file".to_string(),
+            files: vec![url.clone()],
+            reply_to: Some(1),
+        };
+        state.create_message(input, 1, 1).await?;
+        let input = ListMessages {
+            last_id: None,
+            limit: 1,
+        };
+        let messages = state.list_thread_messages(input, 1, 1, 1).await?;
+        assert_eq!(messages[0].attachments.len(), 1);
+        assert_eq!(messages[0].attachments[0].url, url);
+        assert_eq!(messages[0].attachments[0].original_name, "test.txt");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reactions_should_be_aggregated() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.seed_messages().await?;
+        let thumb = || CreateReaction {
+            emoji: "👍".to_string(),
+        };
+        state.add_reaction(thumb(), 1, 10, 1).await?;
+        state.add_reaction(thumb(), 1, 10, 1).await?;
+        state.add_reaction(thumb(), 1, 10, 2).await?;
+        let input = CreateReaction {
+            emoji: "🎉".to_string(),
+        };
+        let reactions = state.add_reaction(input, 1, 10, 2).await?;
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions[0].count, 2);
+        assert!(reactions.iter().all(|r| r.reacted));
+
+        let reactions = state.remove_reaction(thumb(), 1, 10, 1).await?;
+        assert_eq!(
+            reactions[0],
+            Reaction {
+                emoji: "👍".to_string(),
+                count: 1,
+                reacted: false,
+            }
+        );
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 1,
+        };
+        let messages = state.list_messages(input, 1, 2).await?;
+        assert_eq!(messages[0].id, 10);
+        assert_eq!(messages[0].reactions.len(), 2);
+        assert!(messages[0].reactions.iter().all(|r| r.reacted));
+
+        // reacting to a message of another chat should fail
+        let err = state.add_reaction(thumb(), 2, 10, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.seed_messages().await?;
+        let input = SearchMessages {
+            q: "hello".to_string(),
+            ..Default::default()
+        };
+        let results = state.search_messages(input, 1, 1).await?;
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].snippet, "<mark>Hello</mark>, world!");
+
+        // cursor and filters
+        let input = SearchMessages {
+            q: "hello".to_string(),
+            sender_id: Some(1),
+            last_id: Some(9),
+            limit: 1,
+            ..Default::default()
+        };
+        let results = state.search_messages(input, 1, 1).await?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].message.id, 6);
+
+        let input = SearchMessages {
+            q: "hello has:file".to_string(),
+            ..Default::default()
+        };
+        let results = state.search_messages(input, 1, 1).await?;
+        assert!(results.is_empty());
+
+        // user content is escaped, only the highlight is markup
+        let input = CreateMessage {
+            content: "<img src=x onerror=alert(1)> xss".to_string(),
+            files: vec![],
+            reply_to: None,
+        };
+        state.create_message(input, 1, 1).await?;
+        let input = SearchMessages {
+            q: "xss".to_string(),
+            ..Default::default()
+        };
+        let results = state.search_messages(input, 1, 1).await?;
+        assert_eq!(
+            results[0].snippet,
+            "&lt;img src=x onerror=alert(1)&gt; <mark>xss</mark>"
+        );
+
+        // only chats the user is a member of
+        let input = SearchMessages {
+            q: "hello".to_string(),
+            ..Default::default()
+        };
+        let results = state.search_messages(input, 2, 1).await?;
+        assert_eq!(results.len(), 4);
+        state.leave_chat(1, 2).await?;
+        let input = SearchMessages {
+            q: "hello".to_string(),
+            ..Default::default()
+        };
+        let results = state.search_messages(input, 2, 1).await?;
+        assert!(results.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn search_page_size_should_use_default_and_cap() {
+        assert_eq!(search_page_size(0), 20);
+        assert_eq!(search_page_size(5), 5);
+        assert_eq!(search_page_size(1000), 100);
+        assert_eq!(search_page_size(u64::MAX), 100);
+    }
+
+    #[test]
+    fn parse_mentions_should_work() {
+        let mentions = parse_mentions("@tchen ping @2, see tchen@acme.org or @here. @ bye @alice.");
+        assert_eq!(
+            mentions,
+            vec![
+                Mention::User("tchen".to_string()),
+                Mention::User("2".to_string()),
+                Mention::Here,
+                Mention::User("alice".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn create_message_with_mentions_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let users = state.fetch_chat_user_by_ids(1, &[2]).await?;
+        let handle = users[0].email.split('@').next().expect("email has handle");
+        let input = CreateMessage {
+            content: format!("hi @{handle} and @3, @1 is me"),
+            files: vec![],
+            reply_to: None,
+        };
+        let message = state.create_message(input, 1, 1).await?;
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 0,
+        };
+        let mentions = state.list_mentions(input.clone(), 2, 1).await?;
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0].id, message.id);
+        assert_eq!(state.list_mentions(input.clone(), 3, 1).await?.len(), 1);
+        // the sender doesn't mention themselves
+        assert!(state.list_mentions(input.clone(), 1, 1).await?.is_empty());
+
+        let input = CreateMessage {
+            content: "@channel hello".to_string(),
+            files: vec![],
+            reply_to: None,
+        };
+        state.create_message(input, 2, 1).await?;
+        let input = ListMessages {
+            last_id: None,
+            limit: 0,
+        };
+        assert_eq!(state.list_mentions(input, 2, 1).await?.len(), 2);
+
+        let input = CreateMessage {
+            content: "@here hello again".to_string(),
+            files: vec![],
+            reply_to: None,
+        };
+        state.create_message(input, 2, 1).await?;
+        let input = ListMessages {
+            last_id: None,
+            limit: 0,
+        };
+        assert_eq!(state.list_mentions(input, 2, 1).await?.len(), 3);
+
+        // unknown handles and ids are plain text
+        let input = CreateMessage {
+            content: "cc @nobody @99".to_string(),
+            files: vec![],
+            reply_to: None,
+        };
+        let message = state.create_message(input, 2, 1).await?;
+        assert_eq!(message.content, "cc @nobody @99");
+
+        // user 4 is not in chat 2
+        let input = CreateMessage {
+            content: "hi @4".to_string(),
+            files: vec![],
+            reply_to: None,
+        };
+        let err = state.create_message(input, 2, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Mentioned user @4 is not a member of chat 2"
+        );
+        Ok(())
+    }
+
+    async fn upload_dummy_file(state: &AppState) -> Result<String> {
         let file = ChatFile::new(1, "test.txt", b"hello world");
         let path = file.path(&state.config.server.base_dir);
         std::fs::create_dir_all(path.parent().expect("file path parent should exists"))?;
         std::fs::write(&path, b"hello world")?;
+        state
+            .create_file_info(&file, "test.txt", 11, "text/plain", 1)
+            .await?;
 
         Ok(file.url())
     }
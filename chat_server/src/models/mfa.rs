@@ -0,0 +1,467 @@
+use crate::{AppError, AppState};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::User;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use super::{
+    refresh_token::{generate_token, hash_token},
+    user::{hash_password, verify_password},
+};
+
+const MFA_ISSUER: &str = "Chat";
+const TOTP_STEP: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// accept the previous and next code as well, to allow for clock drift
+const TOTP_SKEW: i64 = 1;
+const MFA_CHALLENGE_TTL: i64 = 5 * 60;
+const MFA_CHALLENGE_ATTEMPTS: i32 = 5;
+const RECOVERY_CODE_COUNT: usize = 10;
+// everything but the unreserved characters of RFC 3986
+const OTPAUTH_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A new TOTP secret, MFA is enabled once a code generated from it is verified.
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct MfaEnrollment {
+    /// base32 encoded secret for manual entry
+    pub secret: String,
+    /// `otpauth://` URI to render as a QR code
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct MfaCode {
+    /// code from the authenticator app, or a recovery code
+    pub code: String,
+}
+
+/// Single use codes to sign in without the authenticator, only shown once.
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Returned by signin when the password is correct but a code is required.
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct MfaChallenge {
+    /// short-lived token to submit along with the code to /api/signin/mfa
+    pub mfa_token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct MfaSignin {
+    pub mfa_token: String,
+    /// code from the authenticator app, or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, FromRow)]
+struct UserMfa {
+    secret: String,
+    enabled_at: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    /// Start MFA enrollment with a new secret, replacing any pending one.
+    pub async fn enroll_mfa(&self, user: &User) -> Result<MfaEnrollment, AppError> {
+        if let Some(mfa) = self.find_user_mfa(user.id as _).await? {
+            if mfa.enabled_at.is_some() {
+                return Err(AppError::MfaError("mfa is already enabled".to_string()));
+            }
+        }
+
+        let mut buf = [0u8; 20];
+        OsRng.fill_bytes(&mut buf);
+        let secret = base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &buf);
+        sqlx::query(
+            r#"
+            INSERT INTO user_mfa (user_id, secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET secret = $2, last_used_step = 0, created_at = now()
+            "#,
+        )
+        .bind(user.id)
+        .bind(&secret)
+        .execute(&self.pool)
+        .await?;
+
+        // the label and issuer are free text, e.g. `+` in an email must not turn into a space
+        let issuer = utf8_percent_encode(MFA_ISSUER, OTPAUTH_ENCODE_SET);
+        let otpauth_uri = format!(
+            "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP}",
+            utf8_percent_encode(&user.email, OTPAUTH_ENCODE_SET),
+        );
+        Ok(MfaEnrollment {
+            secret,
+            otpauth_uri,
+        })
+    }
+
+    /// Finish enrollment with a code from the authenticator, returns fresh recovery codes.
+    pub async fn enable_mfa(&self, user_id: u64, code: &str) -> Result<RecoveryCodes, AppError> {
+        match self.find_user_mfa(user_id).await? {
+            Some(mfa) if mfa.enabled_at.is_some() => {
+                return Err(AppError::MfaError("mfa is already enabled".to_string()))
+            }
+            Some(mfa) => {
+                if !self.verify_totp(user_id, &mfa.secret, code).await? {
+                    return Err(AppError::MfaError("invalid code".to_string()));
+                }
+            }
+            None => return Err(AppError::MfaError("mfa enrollment not started".to_string())),
+        }
+
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| generate_recovery_code())
+            .collect();
+        let hashes = codes
+            .iter()
+            .map(|code| hash_password(&normalize_recovery_code(code)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE user_mfa SET enabled_at = now() WHERE user_id = $1")
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO mfa_recovery_codes (user_id, code_hash)
+            SELECT $1, unnest($2::text[])
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(&hashes)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(RecoveryCodes {
+            recovery_codes: codes,
+        })
+    }
+
+    /// Turn MFA off, it takes a valid code so a stolen session alone can't do it.
+    pub async fn disable_mfa(&self, user_id: u64, code: &str) -> Result<(), AppError> {
+        if !self.verify_mfa_code(user_id, code).await? {
+            return Err(AppError::MfaError("invalid code".to_string()));
+        }
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM user_mfa WHERE user_id = $1")
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Issue the token a user with MFA gets after the password is verified.
+    pub async fn create_mfa_challenge(&self, user_id: u64) -> Result<MfaChallenge, AppError> {
+        let (token, token_hash) = generate_token();
+        let expires_at = Utc::now() + Duration::seconds(MFA_CHALLENGE_TTL);
+        sqlx::query(
+            r#"
+            INSERT INTO account_tokens (user_id, purpose, token_hash, expires_at)
+            VALUES ($1, 'mfa_challenge', $2, $3)
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(token_hash)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(MfaChallenge {
+            mfa_token: token,
+            expires_at,
+        })
+    }
+
+    /// Complete the signin with the challenge token and a code.
+    ///
+    /// The challenge is dropped after a few wrong codes, the user has to
+    /// sign in with the password again.
+    pub async fn verify_mfa_challenge(&self, input: &MfaSignin) -> Result<User, AppError> {
+        let row: Option<(i64, i64)> = sqlx::query_as(
+            r#"
+            SELECT id, user_id
+            FROM account_tokens
+            WHERE token_hash = $1 AND purpose = 'mfa_challenge' AND used_at IS NULL
+                AND expires_at > now()
+            "#,
+        )
+        .bind(hash_token(&input.mfa_token))
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((id, user_id)) = row else {
+            return Err(AppError::Unauthorized(
+                "invalid or expired mfa token".to_string(),
+            ));
+        };
+
+        if !self.verify_mfa_code(user_id as _, &input.code).await? {
+            sqlx::query(
+                r#"
+                UPDATE account_tokens
+                SET attempts = attempts + 1,
+                    used_at = CASE WHEN attempts + 1 >= $2 THEN now() END
+                WHERE id = $1
+                "#,
+            )
+            .bind(id)
+            .bind(MFA_CHALLENGE_ATTEMPTS)
+            .execute(&self.pool)
+            .await?;
+            return Err(AppError::Unauthorized("invalid code".to_string()));
+        }
+
+        let ret = sqlx::query(
+            "UPDATE account_tokens SET used_at = now() WHERE id = $1 AND used_at IS NULL",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::Unauthorized(
+                "invalid or expired mfa token".to_string(),
+            ));
+        }
+
+        let user = match self.find_user_by_id(user_id).await? {
+            Some(user) => self.signin_workspace(user).await?,
+            None => None,
+        };
+        user.ok_or_else(|| AppError::Unauthorized("no active workspace".to_string()))
+    }
+
+    // a TOTP code of the enabled secret or an unused recovery code
+    async fn verify_mfa_code(&self, user_id: u64, code: &str) -> Result<bool, AppError> {
+        let Some(mfa) = self.find_user_mfa(user_id).await? else {
+            return Ok(false);
+        };
+        if mfa.enabled_at.is_none() {
+            return Ok(false);
+        }
+        let code = code.trim();
+        if code.len() == TOTP_DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit()) {
+            return self.verify_totp(user_id, &mfa.secret, code).await;
+        }
+        self.use_recovery_code(user_id, code).await
+    }
+
+    // codes can't be replayed, the step of an accepted code is remembered
+    async fn verify_totp(&self, user_id: u64, secret: &str, code: &str) -> Result<bool, AppError> {
+        let Some(step) = find_totp_step(secret, code, Utc::now().timestamp()) else {
+            return Ok(false);
+        };
+        let ret = sqlx::query(
+            "UPDATE user_mfa SET last_used_step = $2 WHERE user_id = $1 AND last_used_step < $2",
+        )
+        .bind(user_id as i64)
+        .bind(step)
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected() == 1)
+    }
+
+    async fn use_recovery_code(&self, user_id: u64, code: &str) -> Result<bool, AppError> {
+        let code = normalize_recovery_code(code);
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT id, code_hash FROM mfa_recovery_codes WHERE user_id = $1 AND used_at IS NULL",
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        for (id, code_hash) in rows {
+            if verify_password(&code, &code_hash)? {
+                let ret = sqlx::query(
+                    "UPDATE mfa_recovery_codes SET used_at = now() WHERE id = $1 AND used_at IS NULL",
+                )
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+                return Ok(ret.rows_affected() == 1);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn find_user_mfa(&self, user_id: u64) -> Result<Option<UserMfa>, AppError> {
+        let mfa = sqlx::query_as("SELECT secret, enabled_at FROM user_mfa WHERE user_id = $1")
+            .bind(user_id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(mfa)
+    }
+}
+
+// RFC 6238 code of the time step, HMAC-SHA1 with dynamic truncation as in RFC 4226
+fn totp(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    value % 10u32.pow(TOTP_DIGITS)
+}
+
+// the time step the code belongs to, if it is valid around `now`
+fn find_totp_step(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let key = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, secret)?;
+    let code: u32 = code.parse().ok()?;
+    let current = now / TOTP_STEP;
+    (current - TOTP_SKEW..=current + TOTP_SKEW).find(|step| totp(&key, *step) == code)
+}
+
+// 10 hex characters shown as `xxxxx-xxxxx`
+fn generate_recovery_code() -> String {
+    let mut buf = [0u8; 5];
+    OsRng.fill_bytes(&mut buf);
+    let code = hex::encode(buf);
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn code_at(secret: &str, step: i64) -> String {
+        let key = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, secret).unwrap();
+        format!("{:06}", totp(&key, step))
+    }
+
+    #[test]
+    fn totp_should_match_rfc6238_vectors() {
+        // SHA1 test vectors of RFC 6238 appendix B, truncated to 6 digits
+        let key = b"12345678901234567890";
+        assert_eq!(totp(key, 59 / TOTP_STEP), 287082);
+        assert_eq!(totp(key, 1111111109 / TOTP_STEP), 81804);
+        assert_eq!(totp(key, 1234567890 / TOTP_STEP), 5924);
+        assert_eq!(totp(key, 20000000000 / TOTP_STEP), 353130);
+    }
+
+    #[tokio::test]
+    async fn otpauth_uri_should_be_encoded() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut user = state.find_user_by_id(3).await?.expect("user should exist");
+        user.email = "bob+chat@acme.org".to_string();
+        let enrollment = state.enroll_mfa(&user).await?;
+        assert!(enrollment
+            .otpauth_uri
+            .starts_with("otpauth://totp/Chat:bob%2Bchat%40acme.org?secret="));
+        assert!(enrollment.otpauth_uri.contains("&issuer=Chat&"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mfa_enrollment_and_signin_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let enrollment = state.enroll_mfa(&user).await?;
+        let step = Utc::now().timestamp() / TOTP_STEP;
+        assert!(enrollment
+            .otpauth_uri
+            .starts_with("otpauth://totp/Chat:alice%40acme.org?secret="));
+
+        let ret = state.enable_mfa(2, "000000").await;
+        assert!(matches!(ret, Err(AppError::MfaError(_))));
+        let codes = state
+            .enable_mfa(2, &code_at(&enrollment.secret, step))
+            .await?;
+        assert_eq!(codes.recovery_codes.len(), RECOVERY_CODE_COUNT);
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        assert!(user.mfa_enabled);
+
+        // a code can't be used twice
+        let challenge = state.create_mfa_challenge(2).await?;
+        let input = MfaSignin {
+            mfa_token: challenge.mfa_token.clone(),
+            code: code_at(&enrollment.secret, step),
+        };
+        assert!(state.verify_mfa_challenge(&input).await.is_err());
+        let input = MfaSignin {
+            code: code_at(&enrollment.secret, step + 1),
+            ..input
+        };
+        let user = state.verify_mfa_challenge(&input).await?;
+        assert_eq!(user.ws_name, "acm");
+        assert!(state.verify_mfa_challenge(&input).await.is_err());
+
+        // recovery codes work once, with or without the dash
+        let challenge = state.create_mfa_challenge(2).await?;
+        let input = MfaSignin {
+            mfa_token: challenge.mfa_token,
+            code: codes.recovery_codes[0].replace('-', "").to_uppercase(),
+        };
+        state.verify_mfa_challenge(&input).await?;
+        assert!(!state.use_recovery_code(2, &codes.recovery_codes[0]).await?);
+
+        state.disable_mfa(2, &codes.recovery_codes[1]).await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        assert!(!user.mfa_enabled);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mfa_challenge_should_expire_after_failed_attempts() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let enrollment = state.enroll_mfa(&user).await?;
+        let step = Utc::now().timestamp() / TOTP_STEP;
+        state
+            .enable_mfa(2, &code_at(&enrollment.secret, step))
+            .await?;
+
+        let challenge = state.create_mfa_challenge(2).await?;
+        let input = MfaSignin {
+            mfa_token: challenge.mfa_token,
+            // far outside the accepted window
+            code: code_at(&enrollment.secret, step + 10),
+        };
+        for _ in 0..MFA_CHALLENGE_ATTEMPTS {
+            let ret = state.verify_mfa_challenge(&input).await;
+            assert!(matches!(ret, Err(AppError::Unauthorized(_))));
+        }
+        let input = MfaSignin {
+            code: code_at(&enrollment.secret, step + 1),
+            ..input
+        };
+        let err = state.verify_mfa_challenge(&input).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unauthorized: invalid or expired mfa token"
+        );
+        Ok(())
+    }
+}
@@ -1,20 +1,36 @@
+mod account;
 mod chat;
 mod file;
+mod invite;
 mod messages;
+mod mfa;
+mod refresh_token;
+mod signin_lockout;
+mod token_revocation;
 mod user;
 mod workspace;
 
 use serde::{Deserialize, Serialize};
 
-pub use chat::CreateChat;
-pub use messages::{CreateMessage, ListMessages};
+pub use account::{ForgotPassword, ResetPassword, VerifyEmail};
+pub use chat::{ChatSummary, CreateChat, MarkChatRead, UpdateChat, UpdateChatMember};
+pub use file::{FileSignature, SignFileUrl, SignedFileUrl, UploadLimits};
+pub use invite::{AcceptInvite, CreateInvite, Invite, InviteOutput};
+pub use messages::{
+    CreateMessage, CreateReaction, ListMessages, SearchMessages, SearchResult, UpdateMessage,
+};
+pub use mfa::{MfaChallenge, MfaCode, MfaEnrollment, MfaSignin, RecoveryCodes};
+pub use refresh_token::{RefreshToken, RefreshTokenInput};
+pub use token_revocation::{setup_revocation_listener, SignoutInput};
 pub use user::{CreateUser, SigninUser};
 use utoipa::ToSchema;
+pub use workspace::{
+    TransferWorkspace, UpdateWorkspace, UpdateWorkspaceMember, UserWorkspace, WorkspaceMember,
+};
 
-
-#[derive(Debug, Clone, Serialize,ToSchema, Deserialize)]
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
 pub struct ChatFile {
     pub ws_id: u64,
     pub ext: String, // extract ext from filename or mime type
     pub hash: String,
-}
\ No newline at end of file
+}
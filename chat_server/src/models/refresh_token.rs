@@ -0,0 +1,211 @@
+use crate::{AppError, AppState};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::User;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+/// A freshly issued refresh token, the plain token is never stored.
+#[derive(Debug, Clone)]
+pub struct RefreshToken {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct RefreshTokenInput {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, FromRow)]
+struct RefreshTokenRow {
+    id: i64,
+    user_id: i64,
+    ws_id: Option<i64>,
+    expires_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    /// Issue a refresh token starting a new token family, e.g. on signin.
+    ///
+    /// The token is scoped to the workspace, refreshing keeps the user in it.
+    pub async fn create_refresh_token(
+        &self,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<RefreshToken, AppError> {
+        let (token, token_hash) = generate_token();
+        let expires_at = self.refresh_token_expires_at();
+        sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (user_id, ws_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(ws_id as i64)
+        .bind(token_hash)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(RefreshToken { token, expires_at })
+    }
+
+    /// Exchange a refresh token for a new one of the same family.
+    ///
+    /// A refresh token can only be used once, presenting a used token again means it
+    /// leaked, so the whole family is revoked and the user has to sign in again.
+    pub async fn rotate_refresh_token(
+        &self,
+        token: &str,
+    ) -> Result<(User, RefreshToken), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let row: Option<RefreshTokenRow> = sqlx::query_as(
+            r#"
+            SELECT id, user_id, ws_id, expires_at, used_at, revoked_at
+            FROM refresh_tokens
+            WHERE token_hash = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(row) = row else {
+            return Err(AppError::Unauthorized("invalid refresh token".to_string()));
+        };
+        if row.revoked_at.is_some() {
+            return Err(AppError::Unauthorized("refresh token revoked".to_string()));
+        }
+        if row.used_at.is_some() {
+            sqlx::query(
+                r#"
+                UPDATE refresh_tokens
+                SET revoked_at = now()
+                WHERE family_id = (SELECT family_id FROM refresh_tokens WHERE id = $1)
+                AND revoked_at IS NULL
+                "#,
+            )
+            .bind(row.id)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            return Err(AppError::Unauthorized(
+                "refresh token reused, please sign in again".to_string(),
+            ));
+        }
+        if row.expires_at <= Utc::now() {
+            return Err(AppError::Unauthorized("refresh token expired".to_string()));
+        }
+
+        sqlx::query("UPDATE refresh_tokens SET used_at = now() WHERE id = $1")
+            .bind(row.id)
+            .execute(&mut *tx)
+            .await?;
+
+        let (token, token_hash) = generate_token();
+        let expires_at = self.refresh_token_expires_at();
+        sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (user_id, ws_id, family_id, token_hash, expires_at)
+            SELECT user_id, ws_id, family_id, $2, $3
+            FROM refresh_tokens
+            WHERE id = $1
+            "#,
+        )
+        .bind(row.id)
+        .bind(token_hash)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        let Some(mut user) = self.find_user_by_id(row.user_id).await? else {
+            return Err(AppError::Unauthorized("invalid refresh token".to_string()));
+        };
+        // tokens issued before workspace scoping fall back to the default workspace
+        if let Some(ws_id) = row.ws_id {
+            user.ws_id = ws_id;
+        }
+        if self
+            .get_workspace_role(user.ws_id as _, user.id as _)
+            .await
+            .is_err()
+        {
+            return Err(AppError::Unauthorized(format!(
+                "no longer a member of workspace {}",
+                user.ws_id
+            )));
+        }
+        if let Some(ws) = self.find_workspace_by_id(user.ws_id as _).await? {
+            user.ws_name = ws.name;
+        }
+
+        Ok((user, RefreshToken { token, expires_at }))
+    }
+
+    fn refresh_token_expires_at(&self) -> DateTime<Utc> {
+        Utc::now() + Duration::seconds(self.config.auth.refresh_token_ttl as i64)
+    }
+}
+
+// 32 random bytes, hex encoded, with its sha256 hash
+pub(crate) fn generate_token() -> (String, String) {
+    let mut buf = [0u8; 32];
+    OsRng.fill_bytes(&mut buf);
+    let token = hex::encode(buf);
+    let token_hash = hash_token(&token);
+    (token, token_hash)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn rotate_refresh_token_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = state.create_refresh_token(1, 1).await?;
+        let (user, new_token) = state.rotate_refresh_token(&token.token).await?;
+        assert_eq!(user.id, 1);
+        assert_eq!(user.ws_name, "acm");
+        assert_ne!(token.token, new_token.token);
+
+        state.rotate_refresh_token(&new_token.token).await?;
+        assert!(state.rotate_refresh_token("invalid").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_token_should_revoke_family() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = state.create_refresh_token(1, 1).await?;
+        let other = state.create_refresh_token(1, 1).await?;
+        let (_, new_token) = state.rotate_refresh_token(&token.token).await?;
+
+        let err = state.rotate_refresh_token(&token.token).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unauthorized: refresh token reused, please sign in again"
+        );
+        // the whole family is revoked, other families are untouched
+        let err = state
+            .rotate_refresh_token(&new_token.token)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unauthorized: refresh token revoked");
+        state.rotate_refresh_token(&other.token).await?;
+        Ok(())
+    }
+}
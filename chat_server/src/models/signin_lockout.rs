@@ -0,0 +1,152 @@
+use crate::{AppError, AppState};
+use chat_core::middlewares::RateLimited;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+// failures older than this are forgotten
+const FAILURE_WINDOW_HOURS: i64 = 24;
+
+impl AppState {
+    /// Refuse signin while the email is locked after too many failures.
+    pub async fn check_signin_lockout(&self, email: &str) -> Result<(), AppError> {
+        let locked_until: Option<Option<DateTime<Utc>>> =
+            sqlx::query_scalar("SELECT locked_until FROM signin_failures WHERE email = $1")
+                .bind(email.to_lowercase())
+                .fetch_optional(&self.pool)
+                .await?;
+        match locked_until.flatten() {
+            Some(until) if until > Utc::now() => {
+                let retry_after = (until - Utc::now()).to_std().unwrap_or_default();
+                Err(RateLimited { retry_after }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a failed signin, from the threshold on every failure locks the email
+    /// twice as long as the previous one.
+    pub async fn record_signin_failure(&self, email: &str) -> Result<(), AppError> {
+        let lockout = &self.config.rate_limit.lockout;
+        let failures: i32 = sqlx::query_scalar(
+            r#"
+            INSERT INTO signin_failures AS f (email, failures)
+            VALUES ($1, 1)
+            ON CONFLICT (email) DO UPDATE
+            SET failures = CASE
+                    WHEN f.last_failed_at < now() - make_interval(hours => $2) THEN 1
+                    ELSE f.failures + 1
+                END,
+                last_failed_at = now()
+            RETURNING failures
+            "#,
+        )
+        .bind(email.to_lowercase())
+        .bind(FAILURE_WINDOW_HOURS as i32)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if let Some(delay) = lockout_delay(failures as u32, lockout) {
+            sqlx::query("UPDATE signin_failures SET locked_until = now() + $2 WHERE email = $1")
+                .bind(email.to_lowercase())
+                .bind(delay)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn clear_signin_failures(&self, email: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM signin_failures WHERE email = $1")
+            .bind(email.to_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+fn lockout_delay(failures: u32, lockout: &crate::config::LockoutConfig) -> Option<Duration> {
+    let over = failures.checked_sub(lockout.threshold)?;
+    let delay = lockout
+        .base_delay
+        .saturating_mul(1u64.checked_shl(over).unwrap_or(u64::MAX))
+        .min(lockout.max_delay);
+    Some(Duration::from_secs(delay))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::LockoutConfig, models::SigninUser};
+    use anyhow::Result;
+    use chat_core::middlewares::{PgRateLimitStore, RateLimit, RateLimiter};
+    use std::sync::Arc;
+
+    #[test]
+    fn lockout_delay_should_double() {
+        let lockout = LockoutConfig::default();
+        assert_eq!(lockout_delay(4, &lockout), None);
+        assert_eq!(lockout_delay(5, &lockout), Some(Duration::from_secs(30)));
+        assert_eq!(lockout_delay(6, &lockout), Some(Duration::from_secs(60)));
+        assert_eq!(lockout_delay(12, &lockout), Some(Duration::from_secs(3600)));
+        assert_eq!(
+            lockout_delay(100, &lockout),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_signin_failures_should_lock_email() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let wrong = SigninUser::new("alice@acme.org", "wrong");
+        for _ in 0..5 {
+            assert!(state.verify_user(&wrong).await?.is_none());
+        }
+        // even the right password is refused while locked
+        let input = SigninUser::new("alice@acme.org", "123456");
+        let ret = state.verify_user(&input).await;
+        match ret {
+            Err(AppError::TooManyRequests(e)) => assert_eq!(e.retry_after_secs(), 30),
+            _ => panic!("expected lockout, got {:?}", ret),
+        }
+        assert!(state.check_signin_lockout("Alice@acme.org").await.is_err());
+
+        sqlx::query("UPDATE signin_failures SET locked_until = now()")
+            .execute(&state.pool)
+            .await?;
+        assert!(state.verify_user(&input).await?.is_some());
+        // a successful signin resets the count
+        assert!(state.verify_user(&wrong).await?.is_none());
+        state.check_signin_lockout("alice@acme.org").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_failure_with_long_email_should_be_recorded() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let email = format!("{}@acme.org", "a".repeat(300));
+        let input = SigninUser::new(&email, "wrong");
+        assert!(state.verify_user(&input).await?.is_none());
+        let failures: i32 =
+            sqlx::query_scalar("SELECT failures FROM signin_failures WHERE email = $1")
+                .bind(&email)
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(failures, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pg_rate_limit_store_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let store = Arc::new(PgRateLimitStore::new(state.pool.clone()));
+        let limiter = RateLimiter::new("test", RateLimit::new(2, 60), store);
+        limiter.check("a").await?;
+        limiter.check("a").await?;
+        let err = limiter.check("a").await.unwrap_err();
+        assert_eq!(err.retry_after_secs(), 30);
+        limiter.check("b").await?;
+        // keys hold unvalidated input like the signin email
+        limiter.check(&"c".repeat(300)).await?;
+        Ok(())
+    }
+}
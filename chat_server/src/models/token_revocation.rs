@@ -0,0 +1,192 @@
+use super::refresh_token::hash_token;
+use crate::{AppError, AppState};
+use chat_core::{TokenClaims, TokenRevocation};
+use chrono::{Duration, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema, Deserialize)]
+pub struct SignoutInput {
+    /// refresh token of this session, revoked along with the access token
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+impl AppState {
+    /// Revoke the access token, and the refresh token family of the session if given.
+    pub async fn signout(&self, claims: &TokenClaims, input: SignoutInput) -> Result<(), AppError> {
+        let revocation = TokenRevocation {
+            user_id: claims.user.id,
+            jti: Some(claims.jti.clone()),
+            issued_before: None,
+            expires_at: claims.expires_at,
+        };
+        self.insert_revocation(revocation).await?;
+
+        if let Some(token) = input.refresh_token {
+            self.revoke_refresh_token_family(&token, claims.user.id as _)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Revoke all access and refresh tokens of the user issued so far.
+    pub async fn signout_all(&self, user_id: u64) -> Result<(), AppError> {
+        // token issue times have milliseconds, a signin right after this one stays valid
+        let cutoff = Utc::now().trunc_subsecs(3);
+        let revocation = TokenRevocation {
+            user_id: user_id as _,
+            jti: None,
+            issued_before: Some(cutoff),
+            expires_at: cutoff + Duration::seconds(self.config.auth.access_token_ttl as _),
+        };
+        self.insert_revocation(revocation).await?;
+
+        sqlx::query(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = now()
+            WHERE user_id = $1 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(user_id as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Load revocations still in effect, e.g. on startup.
+    pub async fn load_revocations(&self) -> Result<(), AppError> {
+        let revocations: Vec<TokenRevocation> = sqlx::query_as(
+            r#"
+            SELECT user_id, jti, issued_before, expires_at
+            FROM token_revocations
+            WHERE expires_at > now()
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        for revocation in revocations {
+            self.revocations.insert(revocation);
+        }
+        Ok(())
+    }
+
+    async fn insert_revocation(&self, revocation: TokenRevocation) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO token_revocations (user_id, jti, issued_before, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(revocation.user_id)
+        .bind(&revocation.jti)
+        .bind(revocation.issued_before)
+        .bind(revocation.expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        // other instances learn about it from the token_revoked notification
+        self.revocations.insert(revocation);
+        Ok(())
+    }
+
+    async fn revoke_refresh_token_family(&self, token: &str, user_id: u64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = now()
+            WHERE family_id = (SELECT family_id FROM refresh_tokens WHERE token_hash = $1)
+            AND user_id = $2
+            AND revoked_at IS NULL
+            "#,
+        )
+        .bind(hash_token(token))
+        .bind(user_id as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Keep the revocation list in sync with revocations made by other instances.
+pub async fn setup_revocation_listener(state: AppState) -> Result<(), AppError> {
+    let mut listener = PgListener::connect(&state.config.server.db_url).await?;
+    listener.listen("token_revoked").await?;
+    state.load_revocations().await?;
+
+    tokio::spawn(async move {
+        loop {
+            // `recv` reconnects on the next call after the connection is lost,
+            // revocations notified in the meantime are picked up from the table
+            let notif = match listener.recv().await {
+                Ok(notif) => notif,
+                Err(e) => {
+                    error!("Token revocation listener failed: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    if let Err(e) = state.load_revocations().await {
+                        warn!("Failed to reload token revocations: {}", e);
+                    }
+                    continue;
+                }
+            };
+            info!("Received notification: {:?}", notif);
+            match serde_json::from_str::<TokenRevocation>(notif.payload()) {
+                Ok(revocation) => state.revocations.insert(revocation),
+                Err(e) => warn!("Failed to parse token revocation: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chat_core::middlewares::TokenVerify;
+
+    #[tokio::test]
+    async fn signout_should_revoke_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let token = state.ek.sign(user.clone())?;
+        let other = state.ek.sign(user)?;
+        let refresh = state.create_refresh_token(1, 1).await?;
+
+        let claims = state.dk.verify_claims(&token)?;
+        let input = SignoutInput {
+            refresh_token: Some(refresh.token.clone()),
+        };
+        state.signout(&claims, input).await?;
+        assert!(state.revocations.is_revoked(&claims));
+        assert!(state.verify(&token).is_err());
+        assert!(!state
+            .revocations
+            .is_revoked(&state.dk.verify_claims(&other)?));
+        assert!(state.rotate_refresh_token(&refresh.token).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signout_all_should_revoke_all_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let claims = state.dk.verify_claims(&state.ek.sign(user.clone())?)?;
+        let refresh = state.create_refresh_token(1, 1).await?;
+
+        // a token issued within the same second as the signout is revoked too
+        state.signout_all(1).await?;
+        assert!(state.revocations.is_revoked(&claims));
+        assert!(state.rotate_refresh_token(&refresh.token).await.is_err());
+
+        // signing in again right away works
+        let claims = state.dk.verify_claims(&state.ek.sign(user)?)?;
+        assert!(!state.revocations.is_revoked(&claims));
+        Ok(())
+    }
+}
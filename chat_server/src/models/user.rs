@@ -1,22 +1,25 @@
-use crate::{AppError, AppState};
+use crate::{models::invite::is_allowed_email, AppError, AppState};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
-use chat_core::{ChatUser, User};
+use chat_core::{ChatUser, User, Workspace, WorkspaceRole};
 use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
 use std::mem;
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Serialize, ToSchema,Deserialize)]
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
 pub struct CreateUser {
     pub fullname: String,
     pub email: String,
     pub workspace: String,
     pub password: String,
+    /// invite token, required to join an existing workspace unless the email domain is allowed
+    #[serde(default)]
+    pub invite: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, ToSchema,Deserialize)]
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
 pub struct SigninUser {
     pub email: String,
     pub password: String,
@@ -27,7 +30,11 @@ impl AppState {
     /// Find a user by email
     pub async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, created_at FROM users WHERE email = $1",
+            r#"
+            SELECT id, ws_id, fullname, email, user_mfa_enabled(id) AS mfa_enabled, created_at
+            FROM users
+            WHERE email = $1
+            "#,
         )
         .bind(email)
         .fetch_optional(&self.pool)
@@ -38,7 +45,11 @@ impl AppState {
     // find a user by id
     pub async fn find_user_by_id(&self, id: i64) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1",
+            r#"
+            SELECT id, ws_id, fullname, email, user_mfa_enabled(id) AS mfa_enabled, created_at
+            FROM users
+            WHERE id = $1
+            "#,
         )
         .bind(id)
         .fetch_optional(&self.pool)
@@ -46,8 +57,7 @@ impl AppState {
         Ok(user)
     }
 
-    /// Create a new user
-    // TODO: use transaction for workspace creation and user creation
+    /// Create a new user, a workspace that doesn't exist yet is created and owned by the user.
     pub async fn create_user(&self, input: &CreateUser) -> Result<User, AppError> {
         // check if email exists
         let user = self.find_user_by_email(&input.email).await?;
@@ -55,13 +65,76 @@ impl AppState {
             return Err(AppError::EmailAlreadyExists(input.email.clone()));
         }
 
-        // check if workspace exists, if not create one
-        let ws = match self.find_workspace_by_name(&input.workspace).await? {
-            Some(ws) => ws,
-            None => self.create_workspace(&input.workspace, 0).await?,
+        // joining an existing workspace requires an invite or an allowed email domain,
+        // without a role the user joins once the email is verified, see `join_allowed_workspace`.
+        // Without a workspace it's created along with the user.
+        let (ws, role, invite) = match &input.invite {
+            Some(token) => {
+                let invite = match self.find_valid_invite(token).await? {
+                    Some(invite) if invite.email.eq_ignore_ascii_case(&input.email) => invite,
+                    _ => {
+                        return Err(AppError::PermissionDenied(
+                            "invalid or expired invite".to_string(),
+                        ))
+                    }
+                };
+                let Some(ws) = self.find_workspace_by_id(invite.ws_id as _).await? else {
+                    return Err(AppError::NotFound(format!("workspace {}", invite.ws_id)));
+                };
+                (Some(ws), Some(invite.role), Some(invite.id))
+            }
+            None => match self.find_workspace_by_name(&input.workspace).await? {
+                Some(ws) if is_allowed_email(&ws, &input.email) => (Some(ws), None, None),
+                Some(ws) => {
+                    return Err(AppError::PermissionDenied(format!(
+                        "workspace {} requires an invite",
+                        ws.name
+                    )))
+                }
+                None => (None, Some(WorkspaceRole::Owner), None),
+            },
         };
 
         let password_hash = hash_password(&input.password)?;
+        let mut tx = self.pool.begin().await?;
+        if let Some(id) = invite {
+            // accept the invite only once even with concurrent signups
+            let ret = sqlx::query(
+                "UPDATE workspace_invites SET accepted_at = now() WHERE id = $1 AND accepted_at IS NULL",
+            )
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+            if ret.rows_affected() == 0 {
+                return Err(AppError::PermissionDenied(
+                    "invalid or expired invite".to_string(),
+                ));
+            }
+        }
+        // owned by the super user until the user exists, all within the transaction
+        let (ws, is_new_ws): (Workspace, bool) = match ws {
+            Some(ws) => (ws, false),
+            None => {
+                let ws = sqlx::query_as(
+                    r#"
+                    INSERT INTO workspaces (name, owner_id)
+                    VALUES ($1, 0)
+                    RETURNING id, name, owner_id, allowed_domain, created_at
+                    "#,
+                )
+                .bind(&input.workspace)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|e| match e {
+                    // a concurrent signup created it first
+                    sqlx::Error::Database(e) if e.is_unique_violation() => {
+                        AppError::WorkspaceAlreadyExists(input.workspace.clone())
+                    }
+                    e => e.into(),
+                })?;
+                (ws, true)
+            }
+        };
         let mut user: User = sqlx::query_as(
             r#"
             INSERT INTO users (ws_id, email, fullname, password_hash)
@@ -73,23 +146,43 @@ impl AppState {
         .bind(&input.email)
         .bind(&input.fullname)
         .bind(password_hash)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
-
-        user.ws_name = ws.name.clone();
-
-        if ws.owner_id == 0 {
-            self.update_workspace_owner(ws.id as _, user.id as _)
+        if let Some(role) = role {
+            sqlx::query("INSERT INTO workspace_members (ws_id, user_id, role) VALUES ($1, $2, $3)")
+                .bind(ws.id)
+                .bind(user.id)
+                .bind(role)
+                .execute(&mut *tx)
+                .await?;
+        }
+        if is_new_ws {
+            sqlx::query("UPDATE workspaces SET owner_id = $1 WHERE id = $2")
+                .bind(user.id)
+                .bind(ws.id)
+                .execute(&mut *tx)
                 .await?;
         }
+        tx.commit().await?;
+
+        user.ws_name = ws.name;
 
         Ok(user)
     }
 
-    /// Verify email and password
+    /// Verify email and password, the user signs in to their default workspace
+    /// or, if deactivated there, to another workspace they are active in.
+    ///
+    /// Repeated failures lock the email for a while, see [`LockoutConfig`](crate::config::LockoutConfig).
     pub async fn verify_user(&self, input: &SigninUser) -> Result<Option<User>, AppError> {
+        self.check_signin_lockout(&input.email).await?;
         let user: Option<User> = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, password_hash, created_at FROM users WHERE email = $1",
+            r#"
+            SELECT id, ws_id, fullname, email, password_hash, user_mfa_enabled(id) AS mfa_enabled,
+                created_at
+            FROM users
+            WHERE email = $1
+            "#,
         )
         .bind(&input.email)
         .fetch_optional(&self.pool)
@@ -99,26 +192,52 @@ impl AppState {
                 let password_hash = mem::take(&mut user.password_hash);
                 let is_valid =
                     verify_password(&input.password, &password_hash.unwrap_or_default())?;
-                if is_valid {
-                    let ws = self.find_workspace_by_id(user.ws_id as _).await?.unwrap();
-                    user.ws_name = ws.name;
-                    Ok(Some(user))
-                } else {
-                    Ok(None)
+                if !is_valid {
+                    self.record_signin_failure(&input.email).await?;
+                    return Ok(None);
                 }
+                self.clear_signin_failures(&input.email).await?;
+                self.signin_workspace(user).await
+            }
+            None => {
+                self.record_signin_failure(&input.email).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Scope the user to the workspace to sign in to, `None` if there is none left.
+    pub(crate) async fn signin_workspace(&self, mut user: User) -> Result<Option<User>, AppError> {
+        let workspaces = self.fetch_user_workspaces(user.id as _).await?;
+        let ws = workspaces
+            .iter()
+            .find(|ws| ws.workspace.id == user.ws_id)
+            .or(workspaces.first());
+        match ws {
+            Some(ws) => {
+                user.ws_id = ws.workspace.id;
+                user.ws_name = ws.workspace.name.clone();
+                Ok(Some(user))
             }
             None => Ok(None),
         }
     }
 
-    pub async fn fetch_chat_user_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
+    /// Users among `ids` who are active members of the workspace.
+    pub async fn fetch_chat_user_by_ids(
+        &self,
+        ws_id: u64,
+        ids: &[i64],
+    ) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-        SELECT id, fullname, email
-        FROM users
-        WHERE id = ANY($1)
+        SELECT u.id, u.fullname, u.email
+        FROM users u
+        JOIN workspace_members wm ON wm.user_id = u.id
+        WHERE wm.ws_id = $1 AND u.id = ANY($2) AND wm.deactivated_at IS NULL
         "#,
         )
+        .bind(ws_id as i64)
         .bind(ids)
         .fetch_all(&self.pool)
         .await?;
@@ -128,9 +247,10 @@ impl AppState {
     pub async fn fetch_chat_users(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-        SELECT id, fullname, email
-        FROM users
-        WHERE ws_id = $1
+        SELECT u.id, u.fullname, u.email
+        FROM users u
+        JOIN workspace_members wm ON wm.user_id = u.id
+        WHERE wm.ws_id = $1
         "#,
         )
         .bind(ws_id as i64)
@@ -140,7 +260,7 @@ impl AppState {
     }
 }
 
-fn hash_password(password: &str) -> Result<String, AppError> {
+pub(crate) fn hash_password(password: &str) -> Result<String, AppError> {
     let salt = SaltString::generate(&mut OsRng);
 
     // Argon2 with default params (Argon2id v19)
@@ -154,7 +274,7 @@ fn hash_password(password: &str) -> Result<String, AppError> {
     Ok(password_hash)
 }
 
-fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError> {
+pub(crate) fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError> {
     let argon2 = Argon2::default();
     let password_hash = PasswordHash::new(password_hash)?;
 
@@ -174,6 +294,7 @@ impl CreateUser {
             workspace: ws.to_string(),
             email: email.to_string(),
             password: password.to_string(),
+            invite: None,
         }
     }
 }
@@ -206,7 +327,7 @@ mod tests {
     async fn create_duplicate_user_should_fail() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
 
-        let input = CreateUser::new("acme", "Tyr Chen", "tchen@acme.org", "hunter42");
+        let input = CreateUser::new("acm", "Eli Shi", "elixy@qq.com", "hunter42");
         let ret = state.create_user(&input).await;
         match ret {
             Err(AppError::EmailAlreadyExists(email)) => {
@@ -221,7 +342,7 @@ mod tests {
     async fn create_and_verify_user_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
 
-        let input = CreateUser::new("none", "Tian Chen", "tyr@acme.org", "hunter42");
+        let input = CreateUser::new("new", "Tian Chen", "tyr@acme.org", "hunter42");
         let user = state.create_user(&input).await?;
         assert_eq!(user.email, input.email);
         assert_eq!(user.fullname, input.fullname);
@@ -240,6 +361,27 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn concurrent_signups_should_have_one_workspace_owner() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let tyr = CreateUser::new("new", "Tian Chen", "tyr@new.org", "hunter42");
+        let eve = CreateUser::new("new", "Eve", "eve@evil.org", "hunter42");
+        let (tyr, eve) = tokio::join!(state.create_user(&tyr), state.create_user(&eve));
+        let owners: Vec<_> = [tyr, eve].into_iter().filter_map(|ret| ret.ok()).collect();
+        assert_eq!(owners.len(), 1);
+        let ws = state
+            .find_workspace_by_name("new")
+            .await?
+            .expect("workspace should exist");
+        assert_eq!(ws.owner_id, owners[0].id);
+
+        // unowned workspaces are no free owner slot
+        let input = CreateUser::new("none", "Eve", "eve@evil.org", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn find_user_by_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -250,4 +392,43 @@ mod tests {
         assert_eq!(user.id, 1);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn create_user_with_allowed_domain_should_join() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("acm", "Tian Chen", "tyr@acme.org", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        sqlx::query("UPDATE workspaces SET allowed_domain = 'acme.org' WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let user = state.create_user(&input).await?;
+        assert_eq!(user.ws_id, 1);
+        assert_eq!(user.ws_name, "acm");
+
+        // anyone can type an email of the domain, it has to be verified first
+        let ret = state.get_workspace_role(1, user.id as _).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let signin = SigninUser::new(&input.email, &input.password);
+        assert!(state.verify_user(&signin).await?.is_none());
+        let ret = state.join_chat(1, &user).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        state.send_verification_email(&user).await?;
+        let mails = state.sent_mails(&input.email).await?;
+        let (_, token) = mails[0].body.split_once("token=").expect("token in mail");
+        state.verify_email(token.trim()).await?;
+        assert_eq!(
+            state.get_workspace_role(1, user.id as _).await?,
+            WorkspaceRole::Member
+        );
+        assert!(state.verify_user(&signin).await?.is_some());
+        assert!(state.join_chat(1, &user).await?.members.contains(&user.id));
+
+        let input = CreateUser::new("acm", "Eve", "eve@evil.org", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
 }
@@ -1,5 +1,53 @@
 use crate::{AppError, AppState};
-use chat_core::Workspace;
+use chat_core::{User, Workspace, WorkspaceRole};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+/// Partial update of the workspace settings, fields left out keep their current value.
+#[derive(Debug, Clone, Default, Serialize, ToSchema, Deserialize)]
+pub struct UpdateWorkspace {
+    pub name: Option<String>,
+    /// users with an email in this domain may join without an invite, empty to disable
+    pub allowed_domain: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct UpdateWorkspaceMember {
+    pub role: WorkspaceRole,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct TransferWorkspace {
+    pub user_id: u64,
+}
+
+/// A workspace the user belongs to, with their role in it.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct UserWorkspace {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub workspace: Workspace,
+    pub role: WorkspaceRole,
+    #[serde(alias = "joinedAt")]
+    pub joined_at: DateTime<Utc>,
+}
+
+/// A user as seen by workspace admins, including role and status.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct WorkspaceMember {
+    pub id: i64,
+    pub fullname: String,
+    pub email: String,
+    pub role: WorkspaceRole,
+    #[serde(alias = "deactivatedAt")]
+    pub deactivated_at: Option<DateTime<Utc>>,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
 
 impl AppState {
     pub async fn create_workspace(&self, name: &str, user_id: u64) -> Result<Workspace, AppError> {
@@ -7,7 +55,7 @@ impl AppState {
             r#"
         INSERT INTO workspaces (name, owner_id)
         VALUES ($1, $2)
-        RETURNING id, name, owner_id, created_at
+        RETURNING id, name, owner_id, allowed_domain, created_at
         "#,
         )
         .bind(name)
@@ -21,7 +69,7 @@ impl AppState {
     pub async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
-        SELECT id, name, owner_id, created_at
+        SELECT id, name, owner_id, allowed_domain, created_at
         FROM workspaces
         WHERE name = $1
         "#,
@@ -37,7 +85,7 @@ impl AppState {
     pub async fn find_workspace_by_id(&self, id: u64) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
-        SELECT id, name, owner_id, created_at
+        SELECT id, name, owner_id, allowed_domain, created_at
         FROM workspaces
         WHERE id = $1
         "#,
@@ -49,59 +97,408 @@ impl AppState {
         Ok(ws)
     }
 
+    /// Make the user the workspace owner, the previous owner becomes an admin.
     pub async fn update_workspace_owner(
         &self,
         id: u64,
         owner_id: u64,
     ) -> Result<Workspace, AppError> {
-        // update owner_id in two cases 1) owner_id = 0 2) owner's ws_id = id
+        let mut tx = self.pool.begin().await?;
+        // the new owner must be a member of the workspace
         let ws = sqlx::query_as(
             r#"
         UPDATE workspaces
         SET owner_id = $1
-        WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2
-        RETURNING id, name, owner_id, created_at
+        WHERE id = $2
+          AND EXISTS (SELECT 1 FROM workspace_members WHERE ws_id = $2 AND user_id = $1)
+        RETURNING id, name, owner_id, allowed_domain, created_at
         "#,
         )
         .bind(owner_id as i64)
         .bind(id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET role = CASE WHEN user_id = $1 THEN 'owner'::workspace_role ELSE 'admin'::workspace_role END
+            WHERE ws_id = $2 AND (user_id = $1 OR role = 'owner')
+            "#,
+        )
+        .bind(owner_id as i64)
+        .bind(id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(ws)
+    }
+
+    /// Rename the workspace or change its settings, only the owner can do this.
+    pub async fn update_workspace(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        input: UpdateWorkspace,
+    ) -> Result<Workspace, AppError> {
+        if self.get_workspace_role(ws_id, user_id).await? != WorkspaceRole::Owner {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner can update the workspace".to_string(),
+            ));
+        }
+        let Some(ws) = self.find_workspace_by_id(ws_id).await? else {
+            return Err(AppError::NotFound(format!("workspace {}", ws_id)));
+        };
+
+        let name = match input.name.map(|v| v.trim().to_string()) {
+            Some(name) if name.is_empty() => {
+                return Err(AppError::UpdateWorkspaceError(
+                    "workspace name can not be empty".to_string(),
+                ))
+            }
+            Some(name) if name != ws.name => {
+                if self.find_workspace_by_name(&name).await?.is_some() {
+                    return Err(AppError::WorkspaceAlreadyExists(name));
+                }
+                name
+            }
+            _ => ws.name,
+        };
+        let allowed_domain = match input.allowed_domain {
+            Some(v) => {
+                Some(v.trim().trim_start_matches('@').to_lowercase()).filter(|v| !v.is_empty())
+            }
+            None => ws.allowed_domain,
+        };
+        let ws = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET name = $2, allowed_domain = $3
+            WHERE id = $1
+            RETURNING id, name, owner_id, allowed_domain, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .bind(allowed_domain)
         .fetch_one(&self.pool)
         .await?;
 
         Ok(ws)
     }
+
+    /// Hand the workspace over to another active member, only the owner can do this.
+    pub async fn transfer_workspace(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        new_owner_id: u64,
+    ) -> Result<Workspace, AppError> {
+        if self.get_workspace_role(ws_id, user_id).await? != WorkspaceRole::Owner {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner can transfer the workspace".to_string(),
+            ));
+        }
+        let member = self.get_workspace_member(ws_id, new_owner_id).await?;
+        if member.deactivated_at.is_some() || member.role == WorkspaceRole::Guest {
+            return Err(AppError::UpdateWorkspaceError(format!(
+                "user {} can not own the workspace",
+                new_owner_id
+            )));
+        }
+        self.update_workspace_owner(ws_id, new_owner_id).await
+    }
+
+    /// Workspaces the user is an active member of.
+    pub async fn fetch_user_workspaces(
+        &self,
+        user_id: u64,
+    ) -> Result<Vec<UserWorkspace>, AppError> {
+        let workspaces = sqlx::query_as(
+            r#"
+            SELECT w.id, w.name, w.owner_id, w.allowed_domain, w.created_at, wm.role, wm.joined_at
+            FROM workspace_members wm
+            JOIN workspaces w ON w.id = wm.ws_id
+            WHERE wm.user_id = $1 AND wm.deactivated_at IS NULL
+            ORDER BY wm.joined_at, w.id
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(workspaces)
+    }
+
+    /// Scope the user to another workspace they are an active member of,
+    /// it also becomes the workspace they sign in to by default.
+    pub async fn switch_workspace(&self, user_id: u64, ws_id: u64) -> Result<User, AppError> {
+        self.get_workspace_role(ws_id, user_id).await?;
+        let Some(ws) = self.find_workspace_by_id(ws_id).await? else {
+            return Err(AppError::NotFound(format!("workspace {}", ws_id)));
+        };
+        let mut user: User = sqlx::query_as(
+            r#"
+            UPDATE users
+            SET ws_id = $2
+            WHERE id = $1
+            RETURNING id, ws_id, fullname, email, user_mfa_enabled(id) AS mfa_enabled,
+                created_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(ws_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+        user.ws_name = ws.name;
+
+        Ok(user)
+    }
+
+    /// Members of the workspace including deactivated users.
+    pub async fn fetch_workspace_members(
+        &self,
+        ws_id: u64,
+    ) -> Result<Vec<WorkspaceMember>, AppError> {
+        let members = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email, wm.role, wm.deactivated_at, u.created_at
+            FROM workspace_members wm
+            JOIN users u ON u.id = wm.user_id
+            WHERE wm.ws_id = $1
+            ORDER BY u.id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    pub async fn get_workspace_member(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<WorkspaceMember, AppError> {
+        let member = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email, wm.role, wm.deactivated_at, u.created_at
+            FROM workspace_members wm
+            JOIN users u ON u.id = wm.user_id
+            WHERE wm.ws_id = $1 AND wm.user_id = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        member.ok_or_else(|| AppError::NotFound(format!("user {user_id} in workspace {ws_id}")))
+    }
+
+    /// Change the role of a member, admins can only manage members and guests.
+    pub async fn update_workspace_member_role(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        member_id: u64,
+        role: WorkspaceRole,
+    ) -> Result<WorkspaceMember, AppError> {
+        if role == WorkspaceRole::Owner {
+            return Err(AppError::UpdateWorkspaceError(
+                "use the transfer endpoint to change the owner".to_string(),
+            ));
+        }
+        let member = self
+            .verify_can_manage_member(ws_id, user_id, member_id)
+            .await?;
+        if role == WorkspaceRole::Admin
+            && self.get_workspace_role(ws_id, user_id).await? != WorkspaceRole::Owner
+        {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner can grant the admin role".to_string(),
+            ));
+        }
+
+        sqlx::query("UPDATE workspace_members SET role = $3 WHERE ws_id = $1 AND user_id = $2")
+            .bind(ws_id as i64)
+            .bind(member.id)
+            .bind(role)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(WorkspaceMember { role, ..member })
+    }
+
+    /// Deactivate a member, the user can't access the workspace anymore.
+    ///
+    /// Revocation works per user, so the user is signed out of all workspaces
+    /// and has to sign in again to the ones they are still active in.
+    pub async fn deactivate_workspace_member(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        member_id: u64,
+    ) -> Result<WorkspaceMember, AppError> {
+        self.verify_can_manage_member(ws_id, user_id, member_id)
+            .await?;
+        let member = self.set_member_deactivated(ws_id, member_id, true).await?;
+        self.signout_all(member_id).await?;
+        Ok(member)
+    }
+
+    pub async fn reactivate_workspace_member(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        member_id: u64,
+    ) -> Result<WorkspaceMember, AppError> {
+        self.verify_can_manage_member(ws_id, user_id, member_id)
+            .await?;
+        self.set_member_deactivated(ws_id, member_id, false).await
+    }
+
+    /// Role of an active member, deactivated users and non-members are denied.
+    pub async fn get_workspace_role(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<WorkspaceRole, AppError> {
+        let role = sqlx::query_scalar(
+            r#"
+            SELECT role
+            FROM workspace_members
+            WHERE ws_id = $1 AND user_id = $2 AND deactivated_at IS NULL
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        role.ok_or_else(|| {
+            AppError::PermissionDenied(format!(
+                "user {user_id} is not a member of workspace {ws_id}"
+            ))
+        })
+    }
+
+    /// Whether the user belongs to the workspace, deactivated or not.
+    pub(crate) async fn is_workspace_member(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<bool, AppError> {
+        let ret: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM workspace_members WHERE ws_id = $1 AND user_id = $2)",
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(ret)
+    }
+
+    pub(crate) async fn verify_workspace_admin(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<(), AppError> {
+        match self.get_workspace_role(ws_id, user_id).await? {
+            WorkspaceRole::Owner | WorkspaceRole::Admin => Ok(()),
+            _ => Err(AppError::PermissionDenied(
+                "only workspace owners and admins can do this".to_string(),
+            )),
+        }
+    }
+
+    /// Guests only see chats they were added to, they can't browse, join or create chats.
+    pub(crate) async fn verify_not_guest(&self, ws_id: u64, user_id: u64) -> Result<(), AppError> {
+        match self.get_workspace_role(ws_id, user_id).await? {
+            WorkspaceRole::Guest => Err(AppError::PermissionDenied(
+                "guests can only access chats they are invited to".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // the owner can manage anyone else, admins only members and guests
+    async fn verify_can_manage_member(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        member_id: u64,
+    ) -> Result<WorkspaceMember, AppError> {
+        self.verify_workspace_admin(ws_id, user_id).await?;
+        if user_id == member_id {
+            return Err(AppError::PermissionDenied(
+                "can not change your own membership".to_string(),
+            ));
+        }
+        let role = self.get_workspace_role(ws_id, user_id).await?;
+        let member = self.get_workspace_member(ws_id, member_id).await?;
+        match (role, member.role) {
+            (_, WorkspaceRole::Owner) | (WorkspaceRole::Admin, WorkspaceRole::Admin) => Err(
+                AppError::PermissionDenied(format!("can not manage user {}", member_id)),
+            ),
+            _ => Ok(member),
+        }
+    }
+
+    async fn set_member_deactivated(
+        &self,
+        ws_id: u64,
+        member_id: u64,
+        deactivated: bool,
+    ) -> Result<WorkspaceMember, AppError> {
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET deactivated_at = CASE WHEN $3 THEN COALESCE(deactivated_at, now()) END
+            WHERE ws_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(member_id as i64)
+        .bind(deactivated)
+        .execute(&self.pool)
+        .await?;
+
+        self.get_workspace_member(ws_id, member_id).await
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::CreateUser;
+    use crate::models::{CreateChat, CreateUser, SigninUser};
     use anyhow::{Ok, Result};
 
     #[tokio::test]
     async fn workspace_should_create_and_set_owner() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let ws = state.create_workspace("test", 0).await.unwrap();
-
-        let input = CreateUser::new(&ws.name, "Tian Chen", "tyr@acme.org", "Hunter42");
-        let user = state.create_user(&input).await.unwrap();
-
-        assert_eq!(ws.name, "test");
-        assert_eq!(user.ws_id, ws.id);
+        let input = CreateUser::new("new", "Tian Chen", "tyr@acme.org", "Hunter42");
+        let user = state.create_user(&input).await?;
+        assert_eq!(user.ws_name, "new");
 
         let ws = state
-            .update_workspace_owner(ws.id as _, user.id as _)
-            .await
-            .unwrap();
+            .find_workspace_by_id(user.ws_id as _)
+            .await?
+            .expect("workspace should exist");
+        assert_eq!(ws.name, "new");
         assert_eq!(ws.owner_id, user.id);
+        assert_eq!(
+            state.get_workspace_role(ws.id as _, user.id as _).await?,
+            WorkspaceRole::Owner
+        );
         Ok(())
     }
 
     #[tokio::test]
     async fn workspace_should_find_by_name() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let ws = state.find_workspace_by_name("acme").await?;
-        assert_eq!(ws.unwrap().name, "acme");
+        let ws = state.find_workspace_by_name("acm").await?;
+        assert_eq!(ws.unwrap().name, "acm");
         Ok(())
     }
 
@@ -113,4 +510,163 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn update_workspace_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateWorkspace {
+            name: Some("acme corp".to_string()),
+            allowed_domain: Some("@Acme.org".to_string()),
+        };
+        let ws = state.update_workspace(1, 1, input.clone()).await?;
+        assert_eq!(ws.name, "acme corp");
+        assert_eq!(ws.allowed_domain.as_deref(), Some("acme.org"));
+
+        // fields left out keep their value, an empty domain disables self-service joins
+        let input = UpdateWorkspace {
+            name: None,
+            allowed_domain: Some("".to_string()),
+        };
+        let ws = state.update_workspace(1, 1, input).await?;
+        assert_eq!(ws.name, "acme corp");
+        assert_eq!(ws.allowed_domain, None);
+
+        let input = UpdateWorkspace {
+            name: Some("test".to_string()),
+            ..Default::default()
+        };
+        let ret = state.update_workspace(1, 1, input.clone()).await;
+        assert!(matches!(ret, Err(AppError::WorkspaceAlreadyExists(_))));
+        let ret = state.update_workspace(1, 2, input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transfer_workspace_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = state.transfer_workspace(1, 2, 3).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let ws = state.transfer_workspace(1, 1, 2).await?;
+        assert_eq!(ws.owner_id, 2);
+        assert_eq!(state.get_workspace_role(1, 2).await?, WorkspaceRole::Owner);
+        assert_eq!(state.get_workspace_role(1, 1).await?, WorkspaceRole::Admin);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_workspace_member_role_should_respect_hierarchy() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let member = state
+            .update_workspace_member_role(1, 1, 2, WorkspaceRole::Admin)
+            .await?;
+        assert_eq!(member.role, WorkspaceRole::Admin);
+
+        // admins manage members and guests, but not the owner, other admins or the admin role
+        let member = state
+            .update_workspace_member_role(1, 2, 3, WorkspaceRole::Guest)
+            .await?;
+        assert_eq!(member.role, WorkspaceRole::Guest);
+        let ret = state
+            .update_workspace_member_role(1, 2, 3, WorkspaceRole::Admin)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .update_workspace_member_role(1, 2, 1, WorkspaceRole::Member)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // members can't manage anyone
+        let ret = state
+            .update_workspace_member_role(1, 4, 5, WorkspaceRole::Guest)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .update_workspace_member_role(1, 1, 2, WorkspaceRole::Owner)
+            .await;
+        assert!(matches!(ret, Err(AppError::UpdateWorkspaceError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deactivate_workspace_member_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let signin = SigninUser::new("bob@acme.org", "123456");
+        assert!(state.verify_user(&signin).await?.is_some());
+
+        let member = state.deactivate_workspace_member(1, 1, 3).await?;
+        assert!(member.deactivated_at.is_some());
+        assert!(state.verify_user(&signin).await?.is_none());
+        let ret = state.deactivate_workspace_member(1, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let member = state.reactivate_workspace_member(1, 1, 3).await?;
+        assert!(member.deactivated_at.is_none());
+        assert!(state.verify_user(&signin).await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn guest_should_only_access_invited_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state
+            .update_workspace_member_role(1, 1, 4, WorkspaceRole::Guest)
+            .await?;
+        let guest = state.find_user_by_id(4).await?.expect("user should exist");
+
+        assert!(state.verify_not_guest(1, 4).await.is_err());
+        let ret = state.join_chat(1, &guest).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let input = CreateChat {
+            name: None,
+            members: vec![4, 5],
+            public: false,
+        };
+        let ret = state.create_chat(input, 4, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // chats the guest was added to still work
+        assert!(state.is_chat_member(1, 4, 4).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_should_switch_between_workspaces() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = state.switch_workspace(2, 2).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        sqlx::query("INSERT INTO workspace_members (ws_id, user_id, role) VALUES (2, 2, 'guest')")
+            .execute(&state.pool)
+            .await?;
+        let workspaces = state.fetch_user_workspaces(2).await?;
+        let names: Vec<_> = workspaces
+            .iter()
+            .map(|w| w.workspace.name.as_str())
+            .collect();
+        assert_eq!(names, ["acm", "test"]);
+        assert_eq!(workspaces[1].role, WorkspaceRole::Guest);
+
+        let user = state.switch_workspace(2, 2).await?;
+        assert_eq!(user.ws_id, 2);
+        assert_eq!(user.ws_name, "test");
+        // roles are per workspace
+        assert_eq!(state.get_workspace_role(1, 2).await?, WorkspaceRole::Member);
+        assert!(state.verify_not_guest(2, 2).await.is_err());
+
+        // the last workspace is used on signin
+        let signin = SigninUser::new("alice@acme.org", "123456");
+        let user = state
+            .verify_user(&signin)
+            .await?
+            .expect("user should exist");
+        assert_eq!(user.ws_id, 2);
+
+        // deactivated in one workspace, still active in the other
+        state.deactivate_workspace_member(1, 1, 2).await?;
+        assert_eq!(state.fetch_user_workspaces(2).await?.len(), 1);
+        assert!(state.switch_workspace(2, 1).await.is_err());
+        Ok(())
+    }
 }
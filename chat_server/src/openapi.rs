@@ -1,9 +1,17 @@
 use crate::handlers::*;
 use crate::{
-    AppState, CreateChat, CreateMessage, CreateUser, ErrorOutput, ListMessages, SigninUser,
+    AcceptInvite, AppState, ChatSummary, CreateChat, CreateInvite, CreateMessage, CreateReaction,
+    CreateUser, ErrorOutput, ForgotPassword, Invite, InviteOutput, ListMessages, MarkChatRead,
+    MfaChallenge, MfaCode, MfaEnrollment, MfaSignin, RecoveryCodes, RefreshTokenInput,
+    ResetPassword, SearchMessages, SearchResult, SignFileUrl, SignedFileUrl, SigninUser,
+    SignoutInput, TransferWorkspace, UpdateChat, UpdateChatMember, UpdateMessage, UpdateWorkspace,
+    UpdateWorkspaceMember, UserWorkspace, VerifyEmail, WorkspaceMember,
 };
 use axum::Router;
-use chat_core::{Chat, ChatType, ChatUser, Message, User, Workspace};
+use chat_core::{
+    Chat, ChatMember, ChatRole, ChatType, ChatUser, FileInfo, Jwk, JwkSet, Message, MessageEdit,
+    Reaction, User, Workspace, WorkspaceRole,
+};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi,
@@ -21,15 +29,57 @@ pub(crate) trait OpenApiRouter {
         paths(
             signup_handler,
             signin_handler,
+            mfa_signin_handler,
+            refresh_handler,
+            signout_handler,
+            signout_all_handler,
+            forgot_password_handler,
+            reset_password_handler,
+            verify_email_handler,
+            resend_verification_email_handler,
+            enroll_mfa_handler,
+            enable_mfa_handler,
+            disable_mfa_handler,
+            jwks_handler,
             list_chat_handler,
             create_chat_handler,
             get_chat_handler,
+            update_chat_handler,
+            delete_chat_handler,
+            restore_chat_handler,
+            discover_chat_handler,
+            join_chat_handler,
+            leave_chat_handler,
+            mark_chat_read_handler,
+            list_chat_members_handler,
+            update_chat_member_handler,
             list_message_handler,
             send_message_handler,
+            list_thread_handler,
+            update_message_handler,
+            delete_message_handler,
+            list_message_edits_handler,
+            add_reaction_handler,
+            remove_reaction_handler,
             list_chat_users_handler,
+            list_workspaces_handler,
+            switch_workspace_handler,
+            update_workspace_handler,
+            transfer_workspace_handler,
+            list_workspace_members_handler,
+            update_workspace_member_handler,
+            deactivate_workspace_member_handler,
+            reactivate_workspace_member_handler,
+            list_invites_handler,
+            create_invite_handler,
+            accept_invite_handler,
+            delete_invite_handler,
+            search_handler,
+            list_mentions_handler,
+            sign_file_handler,
         ),
         components(
-            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SigninUser, CreateUser, CreateChat, CreateMessage, ListMessages, AuthOutput, ErrorOutput),
+            schemas(User, Chat, ChatSummary, MarkChatRead, ChatType, ChatRole, ChatMember, ChatUser, Message, MessageEdit, Reaction, FileInfo, Workspace, WorkspaceRole, UserWorkspace, WorkspaceMember, UpdateWorkspace, UpdateWorkspaceMember, TransferWorkspace, CreateInvite, AcceptInvite, Invite, InviteOutput, SigninUser, CreateUser, CreateChat, UpdateChat, UpdateChatMember, CreateMessage, UpdateMessage, CreateReaction, ListMessages, SearchMessages, SearchResult, SignFileUrl, SignedFileUrl, RefreshTokenInput, SignoutInput, ForgotPassword, ResetPassword, VerifyEmail, MfaEnrollment, MfaCode, RecoveryCodes, MfaChallenge, MfaSignin, AuthOutput, SignupPending, Jwk, JwkSet, ErrorOutput),
         ),
         modifiers(&SecurityAddon),
         tags(
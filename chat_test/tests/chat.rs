@@ -1,5 +1,5 @@
 use anyhow::Result;
-use chat_core::{Chat, ChatType, Message};
+use chat_core::{Chat, ChatType, FileInfo, Message};
 use futures::StreamExt;
 use reqwest::{
     multipart::{Form, Part},
@@ -16,7 +16,7 @@ test1:
     name: user 1 create chat
     steps:
         - signin
-            email: tchen@acme.org
+            email: elixy@qq.com
             password: 123456
         - create_chat
             name: test
@@ -47,7 +47,7 @@ async fn chat_server_should_work() -> Result<()> {
     let (tdb, state) = chat_server::AppState::new_for_test().await?;
     let chat_server = ChatServer::new(state).await?;
     let db_url = tdb.url();
-    NotifyServer::new(&db_url, &chat_server.token).await?;
+    NotifyServer::new(&db_url, &chat_server).await?;
     let chat = chat_server.create_chat().await?;
     let _msg = chat_server.create_message(chat.id as u64).await?;
     sleep(Duration::from_secs(1)).await;
@@ -55,9 +55,13 @@ async fn chat_server_should_work() -> Result<()> {
 }
 
 impl NotifyServer {
-    async fn new(db_url: &str, token: &str) -> Result<Self> {
+    async fn new(db_url: &str, chat_server: &ChatServer) -> Result<Self> {
         let mut config = notify_server::AppConfig::load()?;
         config.server.db_url = db_url.to_string();
+        // verify tokens with the keys published by chat_server
+        config.auth.pk = None;
+        config.auth.jwks_url = Some(format!("http://{}/.well-known/jwks.json", chat_server.addr));
+        let token = &chat_server.token;
         let app = notify_server::get_router(config).await?;
         let listener = TcpListener::bind(WILD_ADDR).await?;
         let addr = listener.local_addr()?;
@@ -68,7 +72,7 @@ impl NotifyServer {
                 .unwrap();
         });
 
-        let mut es = EventSource::get(format!("http://{}/events?access_token={}", addr, token));
+        let mut es = EventSource::get(format!("http://{}/events?token={}", addr, token));
 
         tokio::spawn(async move {
             while let Some(event) = es.next().await {
@@ -134,7 +138,7 @@ impl ChatServer {
             .client
             .post(&format!("http://{}/api/signin", self.addr))
             .header("Content-Type", "application/json")
-            .body(r#"{"email": "tchen@acme.org","password":"123456"}"#)
+            .body(r#"{"email": "elixy@qq.com","password":"123456"}"#)
             .send()
             .await?;
 
@@ -176,7 +180,8 @@ impl ChatServer {
             .send()
             .await?;
         assert_eq!(res.status(), StatusCode::OK);
-        let ret: Vec<String> = res.json().await?;
+        let ret: Vec<FileInfo> = res.json().await?;
+        let ret: Vec<String> = ret.into_iter().map(|f| f.url).collect();
 
         let body = serde_json::to_string(&json!({
             "content": "hello",
@@ -0,0 +1,8 @@
+-- chats are archived instead of deleted since messages reference them
+-- created_by is NULL for existing chats, only the workspace owner can manage those
+ALTER TABLE chats
+  ADD COLUMN created_by bigint REFERENCES users(id),
+  ADD COLUMN archived_at timestamptz;
+
+-- create index for chats for listing non-archived chats of a workspace
+CREATE INDEX IF NOT EXISTS chats_ws_id_archived_at_index ON chats(ws_id, archived_at);
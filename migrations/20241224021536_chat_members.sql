@@ -0,0 +1,182 @@
+-- chat member role: owner, admin, member
+CREATE TYPE chat_role AS ENUM(
+  'owner',
+  'admin',
+  'member'
+);
+
+-- create chat member table
+CREATE TABLE IF NOT EXISTS chat_members(
+  chat_id bigint NOT NULL REFERENCES chats(id),
+  user_id bigint NOT NULL REFERENCES users(id),
+  role chat_role NOT NULL DEFAULT 'member',
+  joined_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_read_message_id bigint REFERENCES messages(id),
+  PRIMARY KEY (chat_id, user_id)
+);
+
+-- create index for chat_members for user_id
+CREATE INDEX IF NOT EXISTS chat_members_user_id_index ON chat_members(user_id);
+
+-- move existing members out of chats.members, the creator becomes the owner
+INSERT INTO chat_members(chat_id, user_id, role)
+SELECT
+  c.id,
+  m.user_id,
+  CASE WHEN m.user_id = c.created_by THEN
+    'owner'::chat_role
+  ELSE
+    'member'::chat_role
+  END
+FROM
+  chats c,
+  unnest(c.members) AS m(user_id);
+
+ALTER TABLE chats
+  DROP COLUMN members;
+
+-- member ids of a chat, used to keep chat json payloads compatible with chat_core::Chat
+CREATE OR REPLACE FUNCTION chat_member_ids(bigint)
+  RETURNS bigint[]
+  AS $$
+  SELECT
+    ARRAY (
+      SELECT
+        user_id
+      FROM
+        chat_members
+      WHERE
+        chat_id = $1
+      ORDER BY
+        user_id);
+$$
+LANGUAGE sql
+STABLE;
+
+-- chat row with its member ids, compatible with chat_core::Chat
+CREATE OR REPLACE FUNCTION chat_json(chats, bigint[])
+  RETURNS jsonb
+  AS $$
+  SELECT
+    to_jsonb($1) || jsonb_build_object('members', $2);
+$$
+LANGUAGE sql
+IMMUTABLE;
+
+-- chat row changes keep the current members in both old and new
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  MEMBERS bigint[];
+BEGIN
+  RAISE NOTICE 'add_to_chat: %', NEW;
+  MEMBERS := chat_member_ids(OLD.id);
+  PERFORM
+    pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', chat_json(OLD, MEMBERS), 'new', chat_json(NEW, MEMBERS))::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+-- a new chat has no members yet, it is announced once its members are added
+DROP TRIGGER add_to_chat_trigger ON chats;
+
+CREATE TRIGGER add_to_chat_trigger
+  AFTER UPDATE OR DELETE ON chats
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_chat();
+
+-- if chat members changed, notify with chat data before and after the change
+CREATE OR REPLACE FUNCTION chat_members_changed()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  REC record;
+BEGIN
+  FOR REC IN
+  SELECT
+    c AS chat,
+    chat_member_ids(c.id) AS new_members,
+    CASE WHEN TG_OP = 'INSERT' THEN
+      ARRAY (
+        SELECT
+          user_id
+        FROM
+          chat_members
+        WHERE
+          chat_id = c.id
+        EXCEPT
+        SELECT
+          user_id
+        FROM
+          changed
+        WHERE
+          chat_id = c.id
+        ORDER BY
+          user_id)
+    ELSE
+      ARRAY (
+        SELECT
+          user_id
+        FROM
+          chat_members
+        WHERE
+          chat_id = c.id
+        UNION
+        SELECT
+          user_id
+        FROM
+          changed
+        WHERE
+          chat_id = c.id
+        ORDER BY
+          user_id)
+    END AS old_members
+  FROM
+    chats c
+  WHERE
+    c.id IN (
+      SELECT
+        chat_id
+      FROM
+        changed)
+    LOOP
+      -- members added to a chat without members means the chat was just created
+      IF cardinality(REC.old_members) = 0 THEN
+        PERFORM
+          pg_notify('chat_updated', json_build_object('op', 'INSERT', 'old', NULL, 'new', chat_json(REC.chat, REC.new_members))::text);
+      ELSE
+        PERFORM
+          pg_notify('chat_updated', json_build_object('op', 'UPDATE', 'old', chat_json(REC.chat, REC.old_members), 'new', chat_json(REC.chat, REC.new_members))::text);
+      END IF;
+    END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER chat_members_added_trigger
+  AFTER INSERT ON chat_members REFERENCING NEW TABLE AS changed
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION chat_members_changed();
+
+CREATE TRIGGER chat_members_removed_trigger
+  AFTER DELETE ON chat_members REFERENCING OLD TABLE AS changed
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION chat_members_changed();
+
+-- if new message added, notify with message data
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', chat_member_ids(NEW.chat_id))::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,55 @@
+-- messages can be edited or deleted by their sender
+ALTER TABLE messages
+  ADD COLUMN updated_at timestamptz,
+  ADD COLUMN deleted_at timestamptz;
+
+-- prior revisions of edited messages
+CREATE TABLE IF NOT EXISTS message_edits(
+  id bigserial PRIMARY KEY,
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  content text NOT NULL,
+  files text[] NOT NULL DEFAULT '{}',
+  created_at timestamptz NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS message_edits_message_id_idx ON message_edits(message_id, id);
+
+-- message as sent to clients, deleted messages carry no content
+CREATE OR REPLACE FUNCTION message_json(msg messages)
+  RETURNS jsonb
+  AS $$
+  SELECT
+    to_jsonb(msg) || jsonb_build_object('deleted', msg.deleted_at IS NOT NULL);
+$$
+LANGUAGE sql;
+
+-- if new message added, notify with message data; if message edited or
+-- deleted, notify with the new message data
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', chat_member_ids(NEW.chat_id))::text);
+  ELSIF TG_OP = 'UPDATE' AND OLD.deleted_at IS NULL THEN
+    RAISE NOTICE 'add_to_message: % -> %', OLD, NEW;
+    PERFORM
+      pg_notify('chat_message_updated', json_build_object('op', CASE WHEN NEW.deleted_at IS NULL THEN
+            'UPDATE'
+          ELSE
+            'DELETE'
+          END, 'message', message_json(NEW), 'members', chat_member_ids(NEW.chat_id))::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS add_to_message_trigger ON messages;
+
+CREATE TRIGGER add_to_message_trigger
+  AFTER INSERT OR UPDATE ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_message();
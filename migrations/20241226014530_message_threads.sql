@@ -0,0 +1,8 @@
+-- messages may reply to another message, replies are grouped by their thread root
+ALTER TABLE messages
+  ADD COLUMN reply_to bigint REFERENCES messages(id),
+  ADD COLUMN thread_root_id bigint REFERENCES messages(id);
+
+CREATE INDEX IF NOT EXISTS messages_thread_root_id_idx ON messages(thread_root_id, id)
+WHERE
+  thread_root_id IS NOT NULL;
@@ -0,0 +1,45 @@
+-- emoji reactions on messages, one per user and emoji
+CREATE TABLE IF NOT EXISTS message_reactions(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  emoji varchar(32) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (message_id, user_id, emoji)
+);
+
+-- if reaction added or removed, notify chat members with the new count of the emoji
+CREATE OR REPLACE FUNCTION message_reaction_changed()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  reaction message_reactions;
+  chat bigint;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    reaction := NEW;
+  ELSE
+    reaction := OLD;
+  END IF;
+  RAISE NOTICE 'message_reaction_changed: % %', TG_OP, reaction;
+  SELECT
+    chat_id INTO chat
+  FROM
+    messages
+  WHERE
+    id = reaction.message_id;
+  PERFORM
+    pg_notify('message_reaction_changed', json_build_object('op', TG_OP, 'chat_id', chat, 'reaction', reaction, 'count',(
+          SELECT
+            count(*)
+          FROM message_reactions
+          WHERE
+            message_id = reaction.message_id AND emoji = reaction.emoji), 'members', chat_member_ids(chat))::text);
+  RETURN reaction;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER message_reaction_changed_trigger
+  AFTER INSERT OR DELETE ON message_reactions
+  FOR EACH ROW
+  EXECUTE FUNCTION message_reaction_changed();
@@ -0,0 +1,18 @@
+-- if user's read position changed, notify the user so all sessions stay in sync
+CREATE OR REPLACE FUNCTION chat_read_updated()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  RAISE NOTICE 'chat_read_updated: %', NEW;
+  PERFORM
+    pg_notify('chat_read_updated', row_to_json(NEW)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER chat_read_updated_trigger
+  AFTER UPDATE OF last_read_message_id ON chat_members
+  FOR EACH ROW
+  WHEN (OLD.last_read_message_id IS DISTINCT FROM NEW.last_read_message_id)
+  EXECUTE FUNCTION chat_read_updated();
@@ -0,0 +1,36 @@
+-- full-text search on message content
+ALTER TABLE messages
+  ADD COLUMN content_tsv tsvector GENERATED ALWAYS AS (to_tsvector('english', content)) STORED;
+
+CREATE INDEX IF NOT EXISTS messages_content_tsv_idx ON messages USING GIN(content_tsv);
+
+-- keep the search vector out of notifications, pg_notify payloads are limited to 8000 bytes
+CREATE OR REPLACE FUNCTION message_json(msg messages)
+  RETURNS jsonb
+  AS $$
+  SELECT
+    (to_jsonb(msg) - 'content_tsv') || jsonb_build_object('deleted', msg.deleted_at IS NOT NULL);
+$$
+LANGUAGE sql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', message_json(NEW), 'members', chat_member_ids(NEW.chat_id))::text);
+  ELSIF TG_OP = 'UPDATE' AND OLD.deleted_at IS NULL THEN
+    RAISE NOTICE 'add_to_message: % -> %', OLD, NEW;
+    PERFORM
+      pg_notify('chat_message_updated', json_build_object('op', CASE WHEN NEW.deleted_at IS NULL THEN
+            'UPDATE'
+          ELSE
+            'DELETE'
+          END, 'message', message_json(NEW), 'members', chat_member_ids(NEW.chat_id))::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,41 @@
+-- users mentioned in a message, @channel/@here are expanded to the chat members
+CREATE TABLE IF NOT EXISTS message_mentions(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  PRIMARY KEY (message_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS message_mentions_user_id_idx ON message_mentions(user_id, message_id DESC);
+
+-- if users mentioned, notify them only, once per message
+CREATE OR REPLACE FUNCTION message_mentioned()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  rec record;
+BEGIN
+  FOR rec IN
+  SELECT
+    message_id,
+    array_agg(user_id ORDER BY user_id) AS user_ids
+  FROM
+    mentioned
+  GROUP BY
+    message_id LOOP
+      RAISE NOTICE 'message_mentioned: % %', rec.message_id, rec.user_ids;
+      PERFORM
+        pg_notify('message_mentioned', json_build_object('message', message_json(m), 'user_ids', rec.user_ids)::text)
+      FROM
+        messages m
+      WHERE
+        m.id = rec.message_id;
+    END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER message_mentioned_trigger
+  AFTER INSERT ON message_mentions REFERENCING NEW TABLE AS mentioned
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION message_mentioned();
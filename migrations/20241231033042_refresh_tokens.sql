@@ -0,0 +1,16 @@
+-- opaque refresh tokens, only the sha256 hash is stored
+-- every rotation creates a new token in the same family, reusing a rotated token revokes the family
+CREATE TABLE IF NOT EXISTS refresh_tokens(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  family_id uuid NOT NULL DEFAULT gen_random_uuid(),
+  token_hash char(64) NOT NULL UNIQUE,
+  expires_at timestamptz NOT NULL,
+  used_at timestamptz,
+  revoked_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_family_id_idx ON refresh_tokens(family_id);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_user_id_idx ON refresh_tokens(user_id);
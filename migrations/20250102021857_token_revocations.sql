@@ -0,0 +1,30 @@
+-- revoked access tokens: a single token by jti, or all tokens of a user issued before a time
+CREATE TABLE IF NOT EXISTS token_revocations(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  jti varchar(64),
+  issued_before timestamptz,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  CHECK (jti IS NOT NULL OR issued_before IS NOT NULL)
+);
+
+CREATE INDEX IF NOT EXISTS token_revocations_expires_at_idx ON token_revocations(expires_at);
+
+-- if token revoked, notify all servers so they can update their revocation lists
+CREATE OR REPLACE FUNCTION token_revoked()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  RAISE NOTICE 'token_revoked: %', NEW;
+  PERFORM
+    pg_notify('token_revoked', row_to_json(NEW)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER token_revoked_trigger
+  AFTER INSERT ON token_revocations
+  FOR EACH ROW
+  EXECUTE FUNCTION token_revoked();
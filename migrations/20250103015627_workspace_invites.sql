@@ -0,0 +1,39 @@
+-- workspace member role: owner, admin, member, guest
+CREATE TYPE workspace_role AS ENUM(
+  'owner',
+  'admin',
+  'member',
+  'guest'
+);
+
+ALTER TABLE users
+  ADD COLUMN role workspace_role NOT NULL DEFAULT 'member';
+
+UPDATE
+  users
+SET
+  role = 'owner'
+FROM
+  workspaces w
+WHERE
+  w.owner_id = users.id
+  AND w.id = users.ws_id;
+
+-- users with an email in this domain may join without an invite
+ALTER TABLE workspaces
+  ADD COLUMN allowed_domain varchar(64);
+
+-- opaque invite tokens, only the sha256 hash is stored
+CREATE TABLE IF NOT EXISTS workspace_invites(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  email varchar(64) NOT NULL,
+  role workspace_role NOT NULL DEFAULT 'member',
+  token_hash char(64) NOT NULL UNIQUE,
+  created_by bigint NOT NULL REFERENCES users(id),
+  expires_at timestamptz NOT NULL,
+  accepted_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS workspace_invites_ws_id_idx ON workspace_invites(ws_id);
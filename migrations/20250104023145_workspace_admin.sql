@@ -0,0 +1,3 @@
+-- deactivated users can't sign in, their data stays in place
+ALTER TABLE users
+  ADD COLUMN deactivated_at timestamptz;
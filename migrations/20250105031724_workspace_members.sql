@@ -0,0 +1,31 @@
+-- users may belong to several workspaces, users.ws_id is the workspace they sign in to by default
+CREATE TABLE IF NOT EXISTS workspace_members(
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  user_id bigint NOT NULL REFERENCES users(id),
+  role workspace_role NOT NULL DEFAULT 'member',
+  -- deactivated members can't access the workspace, their data stays in place
+  deactivated_at timestamptz,
+  joined_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (ws_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS workspace_members_user_id_idx ON workspace_members(user_id);
+
+-- role and status are per workspace now
+INSERT INTO workspace_members(ws_id, user_id, role, deactivated_at, joined_at)
+SELECT
+  ws_id,
+  id,
+  role,
+  deactivated_at,
+  COALESCE(created_at, now())
+FROM
+  users;
+
+ALTER TABLE users
+  DROP COLUMN role,
+  DROP COLUMN deactivated_at;
+
+-- tokens renewed with a refresh token stay in the workspace they were issued for
+ALTER TABLE refresh_tokens
+  ADD COLUMN ws_id bigint REFERENCES workspaces(id);
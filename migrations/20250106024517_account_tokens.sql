@@ -0,0 +1,20 @@
+-- single use tokens sent by mail, only the sha256 hash is stored
+CREATE TYPE account_token_purpose AS ENUM(
+  'password_reset',
+  'email_verification'
+);
+
+CREATE TABLE IF NOT EXISTS account_tokens(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  purpose account_token_purpose NOT NULL,
+  token_hash char(64) NOT NULL UNIQUE,
+  expires_at timestamptz NOT NULL,
+  used_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS account_tokens_user_id_idx ON account_tokens(user_id, purpose);
+
+ALTER TABLE users
+  ADD COLUMN email_verified_at timestamptz;
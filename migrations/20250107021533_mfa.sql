@@ -0,0 +1,43 @@
+-- TOTP second factor, enabled once the first code is verified
+CREATE TABLE IF NOT EXISTS user_mfa(
+  user_id bigint PRIMARY KEY REFERENCES users(id),
+  -- base32 encoded, it has to be readable to compute codes
+  secret varchar(64) NOT NULL,
+  -- codes are single use, a code of this time step or older is rejected
+  last_used_step bigint NOT NULL DEFAULT 0,
+  enabled_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE OR REPLACE FUNCTION user_mfa_enabled(bigint)
+  RETURNS boolean
+  AS $$
+  SELECT
+    EXISTS (
+      SELECT
+        1
+      FROM
+        user_mfa
+      WHERE
+        user_id = $1
+        AND enabled_at IS NOT NULL);
+$$
+LANGUAGE sql
+STABLE;
+
+-- argon2 hashed, each code works once
+CREATE TABLE IF NOT EXISTS mfa_recovery_codes(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  code_hash text NOT NULL,
+  used_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS mfa_recovery_codes_user_id_idx ON mfa_recovery_codes(user_id);
+
+-- signin with a password only yields an mfa challenge token until the code is submitted
+ALTER TYPE account_token_purpose ADD VALUE IF NOT EXISTS 'mfa_challenge';
+
+ALTER TABLE account_tokens
+  ADD COLUMN attempts int NOT NULL DEFAULT 0;
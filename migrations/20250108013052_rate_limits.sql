@@ -0,0 +1,14 @@
+-- token buckets shared by all chat_server instances
+CREATE TABLE IF NOT EXISTS rate_limit_buckets(
+  key varchar(256) PRIMARY KEY,
+  tokens double precision NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT clock_timestamp()
+);
+
+-- failed signins per email, signin is locked with exponential backoff after too many
+CREATE TABLE IF NOT EXISTS signin_failures(
+  email varchar(64) PRIMARY KEY,
+  failures int NOT NULL DEFAULT 0,
+  locked_until timestamptz,
+  last_failed_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
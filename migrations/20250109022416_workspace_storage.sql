@@ -0,0 +1,6 @@
+-- bytes of uploaded files stored per workspace, checked against the quota on upload
+CREATE TABLE IF NOT EXISTS workspace_storage(
+  ws_id bigint PRIMARY KEY REFERENCES workspaces(id),
+  bytes_used bigint NOT NULL DEFAULT 0,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
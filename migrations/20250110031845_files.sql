@@ -0,0 +1,12 @@
+-- metadata of uploaded files, the content is stored under base_dir by hash
+CREATE TABLE IF NOT EXISTS files(
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  hash varchar(64) NOT NULL,
+  ext text NOT NULL,
+  original_name text NOT NULL,
+  size bigint NOT NULL,
+  content_type text NOT NULL,
+  uploader_id bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (ws_id, hash, ext)
+);
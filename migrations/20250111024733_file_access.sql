@@ -0,0 +1,18 @@
+-- everyone who uploaded a file, the same content is stored only once
+CREATE TABLE IF NOT EXISTS file_uploaders(
+  ws_id bigint NOT NULL,
+  hash varchar(64) NOT NULL,
+  ext text NOT NULL,
+  user_id bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (ws_id, hash, ext, user_id),
+  FOREIGN KEY (ws_id, hash, ext) REFERENCES files(ws_id, hash, ext)
+);
+
+INSERT INTO file_uploaders(ws_id, hash, ext, user_id, created_at)
+SELECT ws_id, hash, ext, uploader_id, created_at
+FROM files
+ON CONFLICT DO NOTHING;
+
+-- find the messages a file is attached to, downloads are allowed to readers of those chats
+CREATE INDEX IF NOT EXISTS messages_files_idx ON messages USING gin(files);
@@ -0,0 +1,7 @@
+-- emails of failed signins aren't validated, any length must be recorded
+ALTER TABLE signin_failures
+  ALTER COLUMN email TYPE text;
+
+-- bucket keys embed the signin email as well
+ALTER TABLE rate_limit_buckets
+  ALTER COLUMN key TYPE text;
@@ -1,4 +1,5 @@
 use anyhow::{bail, Result};
+use chat_core::JwtAlgorithm;
 use serde::{Deserialize, Serialize};
 use std::{env, fs::File};
 
@@ -10,7 +11,22 @@ pub struct AppConfig {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthConfig {
-    pub pk: String,
+    /// algorithm of `pk`, one of Ed25519, ES256, ES384, RS256
+    #[serde(default)]
+    pub algorithm: JwtAlgorithm,
+    /// public key to verify tokens with, not needed when `jwks_url` is set
+    #[serde(default)]
+    pub pk: Option<String>,
+    /// fetch verification keys from chat_server, e.g. http://localhost:6688/.well-known/jwks.json
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// how often to refetch the keys in seconds, so rotated keys get picked up
+    #[serde(default = "default_jwks_refresh_interval")]
+    pub jwks_refresh_interval: u64,
+}
+
+fn default_jwks_refresh_interval() -> u64 {
+    60
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -21,7 +37,6 @@ pub struct ServerConfig {
 
 impl AppConfig {
     pub fn load() -> Result<Self> {
-     
         let ret = match (
             File::open("notify.yml"),
             File::open("/etc/config/notify.yml"),
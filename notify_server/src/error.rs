@@ -16,6 +16,9 @@ pub enum AppError {
 
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
+
+    #[error("token revoked")]
+    TokenRevoked,
 }
 
 impl ErrorOutput {
@@ -30,6 +33,7 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response<axum::body::Body> {
         let status = match &self {
             Self::JwtError(_) => StatusCode::FORBIDDEN,
+            Self::TokenRevoked => StatusCode::UNAUTHORIZED,
             Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
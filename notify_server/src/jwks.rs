@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chat_core::{DecodingKey, JwkSet};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// Load verification keys from chat_server's JWKS endpoint and keep them fresh.
+pub(crate) async fn setup_jwks_refresh(state: AppState) -> Result<()> {
+    let Some(url) = state.config.auth.jwks_url.clone() else {
+        return Ok(());
+    };
+    let client = reqwest::Client::new();
+    // fail fast at startup, later refresh errors keep the last known keys
+    refresh_keys(&state, &client, &url).await?;
+
+    let interval = Duration::from_secs(state.config.auth.jwks_refresh_interval.max(1));
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = refresh_keys(&state, &client, &url).await {
+                warn!("refresh jwks from {} failed: {:?}", url, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn refresh_keys(state: &AppState, client: &reqwest::Client, url: &str) -> Result<()> {
+    let jwks: JwkSet = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .context("fetch jwks failed")?
+        .json()
+        .await
+        .context("parse jwks failed")?;
+    let dk = DecodingKey::from_jwks(&jwks)?;
+    info!("loaded {} verification keys from {}", jwks.keys.len(), url);
+    *state.dk.write().expect("jwks lock poisoned") = dk;
+    Ok(())
+}
@@ -1,5 +1,6 @@
 mod config;
 mod error;
+mod jwks;
 mod notif;
 mod sse;
 
@@ -12,11 +13,14 @@ use axum::{
 };
 use chat_core::{
     middlewares::{verify_token, TokenVerify},
-    DecodingKey, User,
+    DecodingKey, RevocationList, TokenClaims,
 };
 use dashmap::DashMap;
 use sse::sse_handler;
-use std::{ops::Deref, sync::Arc};
+use std::{
+    ops::Deref,
+    sync::{Arc, RwLock},
+};
 use tokio::sync::broadcast;
 use tower_http::cors::{self, CorsLayer};
 
@@ -32,13 +36,15 @@ pub struct AppState(Arc<AppStateInner>);
 pub struct AppStateInner {
     pub config: AppConfig,
     users: UserMap,
-    dk: DecodingKey,
+    dk: RwLock<DecodingKey>,
+    revocations: RevocationList,
 }
 
 const INDEX_HTML: &str = include_str!("../index.html");
 
 pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
     let state = AppState::new(config);
+    jwks::setup_jwks_refresh(state.clone()).await?;
     notif::setup_pg_listener(state.clone()).await?;
 
     let cors = CorsLayer::new()
@@ -70,8 +76,16 @@ async fn index_handler() -> impl IntoResponse {
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
-        Ok(self.dk.verify(token)?)
+    fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error> {
+        let claims = self
+            .dk
+            .read()
+            .expect("jwks lock poisoned")
+            .verify_claims(token)?;
+        if self.revocations.is_revoked(&claims) {
+            return Err(AppError::TokenRevoked);
+        }
+        Ok(claims)
     }
 }
 
@@ -85,8 +99,19 @@ impl Deref for AppState {
 
 impl AppState {
     pub fn new(config: AppConfig) -> Self {
-        let dk = DecodingKey::load(&config.auth.pk).expect("Failed to load public key");
+        // keys from `jwks_url` are loaded in get_router
+        let dk = match &config.auth.pk {
+            Some(pk) => DecodingKey::load(config.auth.algorithm, pk, None)
+                .expect("Failed to load public key"),
+            None => DecodingKey::default(),
+        };
         let users = Arc::new(DashMap::new());
-        Self(Arc::new(AppStateInner { config, dk, users }))
+        let revocations = RevocationList::default();
+        Self(Arc::new(AppStateInner {
+            config,
+            dk: RwLock::new(dk),
+            users,
+            revocations,
+        }))
     }
 }
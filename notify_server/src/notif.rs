@@ -1,19 +1,43 @@
 use std::{collections::HashSet, sync::Arc};
 
 use crate::AppState;
-use chat_core::{Chat, Message};
-use futures::StreamExt;
+use chat_core::{Chat, ChatMember, Message, TokenRevocation};
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgListener;
-use tracing::{info, warn};
+use sqlx::{
+    postgres::{PgConnection, PgListener},
+    Connection,
+};
+use tracing::{error, info, warn};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "event")]
 pub enum AppEvent {
     NewChat(Chat),
     AddToChat(Chat),
+    UpdateChat(Chat),
     RemoveFromChat(Chat),
     NewMessage(Message),
+    NewThreadReply(Message),
+    MessageUpdated(Message),
+    MessageDeleted(Message),
+    ReactionChanged(ReactionChanged),
+    ChatRead(ChatMember),
+    Mentioned(Message),
+    // internal, closes the streams opened with a revoked token
+    TokenRevoked(TokenRevocation),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionChanged {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub user_id: i64,
+    pub emoji: String,
+    // true if the reaction was added, false if removed
+    pub added: bool,
+    // number of users reacting with this emoji after the change
+    pub count: i64,
 }
 
 #[derive(Debug)]
@@ -38,79 +62,324 @@ struct ChatMessageCreated {
     members: Vec<i64>,
 }
 
+// pg_notify('chat_message_updated', json_build_object('op', ..., 'message', message_json(NEW), 'members', ...)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessageUpdated {
+    op: String,
+    message: Message,
+    members: Vec<i64>,
+}
+
+// pg_notify('message_reaction_changed', json_build_object('op', TG_OP, 'chat_id', chat, 'reaction', reaction, 'count', ..., 'members', ...)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct MessageReactionChanged {
+    op: String,
+    chat_id: i64,
+    reaction: MessageReaction,
+    count: i64,
+    members: Vec<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MessageReaction {
+    message_id: i64,
+    user_id: i64,
+    emoji: String,
+}
+
+// pg_notify('message_mentioned', json_build_object('message', message_json(m), 'user_ids', ...)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct MessageMentioned {
+    message: Message,
+    user_ids: Vec<i64>,
+}
+
 pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
     let mut listener = PgListener::connect(&state.config.server.db_url).await?;
     listener.listen("chat_updated").await?;
     listener.listen("chat_message_created").await?;
-
-    let mut stream = listener.into_stream();
+    listener.listen("chat_message_updated").await?;
+    listener.listen("message_reaction_changed").await?;
+    listener.listen("chat_read_updated").await?;
+    listener.listen("message_mentioned").await?;
+    listener.listen("token_revoked").await?;
+    load_revocations(&state).await?;
 
     tokio::spawn(async move {
-        while let Some(Ok(notif)) = stream.next().await {
+        loop {
+            // `recv` reconnects on the next call after the connection is lost,
+            // revocations notified in the meantime are picked up from the table
+            let notif = match listener.recv().await {
+                Ok(notif) => notif,
+                Err(e) => {
+                    error!("Notification listener failed: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    if let Err(e) = load_revocations(&state).await {
+                        warn!("Failed to reload token revocations: {}", e);
+                    }
+                    continue;
+                }
+            };
             info!("Received notification: {:?}", notif);
-            let notification = Notification::load(notif.channel(), notif.payload())?;
+            // a bad payload must not stop the listener
+            let notifications = match Notification::load(notif.channel(), notif.payload()) {
+                Ok(notifications) => notifications,
+                Err(e) => {
+                    warn!("Failed to load notification {:?}: {}", notif, e);
+                    continue;
+                }
+            };
             let users = &state.users;
-            for user_id in notification.user_ids {
-                if let Some(tx) = users.get(&user_id) {
-                    info!("Sending notification to user {}", user_id);
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("Failed to send notification to user {}: {}", user_id, e);
+            for notification in notifications {
+                if let AppEvent::TokenRevoked(revocation) = notification.event.as_ref() {
+                    state.revocations.insert(revocation.clone());
+                }
+                for user_id in notification.user_ids {
+                    if let Some(tx) = users.get(&user_id) {
+                        info!("Sending notification to user {}", user_id);
+                        if let Err(e) = tx.send(notification.event.clone()) {
+                            warn!("Failed to send notification to user {}: {}", user_id, e);
+                        }
                     }
                 }
             }
         }
-        Ok::<_, anyhow::Error>(())
     });
 
     Ok(())
 }
 
+// revocations made before we started listening
+async fn load_revocations(state: &AppState) -> anyhow::Result<()> {
+    let mut conn = PgConnection::connect(&state.config.server.db_url).await?;
+    let revocations: Vec<TokenRevocation> = sqlx::query_as(
+        r#"
+        SELECT user_id, jti, issued_before, expires_at
+        FROM token_revocations
+        WHERE expires_at > now()
+        "#,
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    for revocation in revocations {
+        state.revocations.insert(revocation);
+    }
+    Ok(())
+}
+
 impl Notification {
-    fn load(r#type: &str, payload: &str) -> anyhow::Result<Self> {
+    fn new(user_ids: HashSet<u64>, event: AppEvent) -> Self {
+        Self {
+            user_ids,
+            event: Arc::new(event),
+        }
+    }
+
+    fn load(r#type: &str, payload: &str) -> anyhow::Result<Vec<Self>> {
         match r#type {
             "chat_updated" => {
                 let payload: ChatUpdated = serde_json::from_str(payload)?;
                 info!("ChatUpdated: {:?}", payload);
-                let user_ids =
-                    get_affected_chat_user_ids(payload.old.as_ref(), payload.new.as_ref());
-                let event = match payload.op.as_str() {
-                    "INSERT" => AppEvent::NewChat(payload.new.expect("new should exist")),
-                    "UPDATE" => AppEvent::AddToChat(payload.new.expect("new should exist")),
-                    "DELETE" => AppEvent::RemoveFromChat(payload.old.expect("old should exist")),
+                let ret = match (payload.op.as_str(), payload.old, payload.new) {
+                    ("INSERT", _, Some(new)) => {
+                        vec![Self::new(get_chat_user_ids(&new), AppEvent::NewChat(new))]
+                    }
+                    ("UPDATE", Some(old), Some(new)) => get_chat_update_notifications(old, new),
+                    ("DELETE", Some(old), _) => {
+                        vec![Self::new(
+                            get_chat_user_ids(&old),
+                            AppEvent::RemoveFromChat(old),
+                        )]
+                    }
                     _ => return Err(anyhow::anyhow!("Invalid operation")),
                 };
-                Ok(Self {
-                    user_ids,
-                    event: Arc::new(event),
-                })
+                Ok(ret)
             }
             "chat_message_created" => {
                 let payload: ChatMessageCreated = serde_json::from_str(payload)?;
                 let user_ids = payload.members.iter().map(|v| *v as u64).collect();
-                Ok(Self {
+                // thread replies only bump the thread badge of the root message
+                let event = match payload.message.thread_root_id {
+                    Some(_) => AppEvent::NewThreadReply(payload.message),
+                    None => AppEvent::NewMessage(payload.message),
+                };
+                Ok(vec![Self::new(user_ids, event)])
+            }
+            "chat_message_updated" => {
+                let payload: ChatMessageUpdated = serde_json::from_str(payload)?;
+                let user_ids = payload.members.iter().map(|v| *v as u64).collect();
+                let event = match payload.op.as_str() {
+                    "UPDATE" => AppEvent::MessageUpdated(payload.message),
+                    "DELETE" => AppEvent::MessageDeleted(payload.message),
+                    _ => return Err(anyhow::anyhow!("Invalid operation")),
+                };
+                Ok(vec![Self::new(user_ids, event)])
+            }
+            "message_reaction_changed" => {
+                let payload: MessageReactionChanged = serde_json::from_str(payload)?;
+                let user_ids = payload.members.iter().map(|v| *v as u64).collect();
+                let event = ReactionChanged {
+                    chat_id: payload.chat_id,
+                    message_id: payload.reaction.message_id,
+                    user_id: payload.reaction.user_id,
+                    emoji: payload.reaction.emoji,
+                    added: payload.op == "INSERT",
+                    count: payload.count,
+                };
+                Ok(vec![Self::new(user_ids, AppEvent::ReactionChanged(event))])
+            }
+            "chat_read_updated" => {
+                // pg_notify('chat_read_updated', row_to_json(NEW)::text);
+                let member: ChatMember = serde_json::from_str(payload)?;
+                let user_ids = HashSet::from([member.user_id as u64]);
+                Ok(vec![Self::new(user_ids, AppEvent::ChatRead(member))])
+            }
+            "message_mentioned" => {
+                let payload: MessageMentioned = serde_json::from_str(payload)?;
+                let user_ids = payload.user_ids.iter().map(|v| *v as u64).collect();
+                Ok(vec![Self::new(
+                    user_ids,
+                    AppEvent::Mentioned(payload.message),
+                )])
+            }
+            "token_revoked" => {
+                // pg_notify('token_revoked', row_to_json(NEW)::text);
+                let revocation: TokenRevocation = serde_json::from_str(payload)?;
+                let user_ids = HashSet::from([revocation.user_id as u64]);
+                Ok(vec![Self::new(
                     user_ids,
-                    event: Arc::new(AppEvent::NewMessage(payload.message)),
-                })
+                    AppEvent::TokenRevoked(revocation),
+                )])
             }
             _ => Err(anyhow::anyhow!("Invalid notification type")),
         }
     }
 }
 
-fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet<u64> {
-    match (old, new) {
-        (Some(old), Some(new)) => {
-            // diff old/new members, if identical, no need to notify, otherwise notify the union of both
-            let old_user_ids: HashSet<_> = old.members.iter().map(|v| *v as u64).collect();
-            let new_user_ids: HashSet<_> = new.members.iter().map(|v| *v as u64).collect();
-            if old_user_ids == new_user_ids {
-                HashSet::new()
-            } else {
-                old_user_ids.union(&new_user_ids).copied().collect()
-            }
+fn get_chat_user_ids(chat: &Chat) -> HashSet<u64> {
+    chat.members.iter().map(|v| *v as u64).collect()
+}
+
+// users joining the chat get AddToChat, users leaving get RemoveFromChat,
+// and the remaining members get UpdateChat if anything else changed
+fn get_chat_update_notifications(old: Chat, new: Chat) -> Vec<Notification> {
+    // archiving removes the chat for everyone, restoring brings it back
+    match (old.archived_at, new.archived_at) {
+        (None, Some(_)) => {
+            return vec![Notification::new(
+                get_chat_user_ids(&old),
+                AppEvent::RemoveFromChat(new),
+            )]
+        }
+        (Some(_), None) => {
+            return vec![Notification::new(
+                get_chat_user_ids(&new),
+                AppEvent::AddToChat(new),
+            )]
         }
-        (Some(old), None) => old.members.iter().map(|v| *v as u64).collect(),
-        (None, Some(new)) => new.members.iter().map(|v| *v as u64).collect(),
-        _ => HashSet::new(),
+        _ => {}
+    }
+
+    let old_user_ids = get_chat_user_ids(&old);
+    let new_user_ids = get_chat_user_ids(&new);
+    let added: HashSet<_> = new_user_ids.difference(&old_user_ids).copied().collect();
+    let removed: HashSet<_> = old_user_ids.difference(&new_user_ids).copied().collect();
+    let kept: HashSet<_> = old_user_ids.intersection(&new_user_ids).copied().collect();
+
+    let mut ret = vec![];
+    if !added.is_empty() {
+        ret.push(Notification::new(added, AppEvent::AddToChat(new.clone())));
+    }
+    if !removed.is_empty() {
+        ret.push(Notification::new(
+            removed,
+            AppEvent::RemoveFromChat(new.clone()),
+        ));
+    }
+    if !kept.is_empty() && old != new {
+        ret.push(Notification::new(kept, AppEvent::UpdateChat(new)));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message() -> serde_json::Value {
+        json!({
+            "id": 1,
+            "chat_id": 1,
+            "sender_id": 1,
+            "content": "hi @2",
+            "files": [],
+            "created_at": "2025-01-01T00:00:00Z",
+        })
+    }
+
+    fn user_ids(notification: &Notification) -> Vec<u64> {
+        let mut ids: Vec<_> = notification.user_ids.iter().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn load_should_reject_bad_payload() {
+        assert!(Notification::load("chat_message_created", "{").is_err());
+        assert!(Notification::load("message_mentioned", "{}").is_err());
+        assert!(Notification::load("unknown", "{}").is_err());
+    }
+
+    #[test]
+    fn thread_reply_should_go_to_chat_members() -> anyhow::Result<()> {
+        let mut message = message();
+        message["thread_root_id"] = json!(1);
+        let payload = json!({ "message": message, "members": [1, 2, 3] });
+        let ret = Notification::load("chat_message_created", &payload.to_string())?;
+        assert_eq!(ret.len(), 1);
+        assert_eq!(user_ids(&ret[0]), [1, 2, 3]);
+        assert!(matches!(ret[0].event.as_ref(), AppEvent::NewThreadReply(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn mention_should_go_to_mentioned_users_only() -> anyhow::Result<()> {
+        let payload = json!({ "message": message(), "user_ids": [2] });
+        let ret = Notification::load("message_mentioned", &payload.to_string())?;
+        assert_eq!(ret.len(), 1);
+        assert_eq!(user_ids(&ret[0]), [2]);
+        assert!(matches!(ret[0].event.as_ref(), AppEvent::Mentioned(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn chat_read_should_go_to_reader_only() -> anyhow::Result<()> {
+        let payload = json!({
+            "chat_id": 1,
+            "user_id": 3,
+            "role": "member",
+            "joined_at": "2025-01-01T00:00:00Z",
+            "last_read_message_id": 1,
+        });
+        let ret = Notification::load("chat_read_updated", &payload.to_string())?;
+        assert_eq!(user_ids(&ret[0]), [3]);
+        assert!(matches!(ret[0].event.as_ref(), AppEvent::ChatRead(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn token_revoked_should_go_to_revoked_user() -> anyhow::Result<()> {
+        let payload = json!({
+            "user_id": 4,
+            "jti": null,
+            "issued_before": "2025-01-01T00:00:00Z",
+            "expires_at": "2025-01-01T00:15:00Z",
+        });
+        let ret = Notification::load("token_revoked", &payload.to_string())?;
+        assert_eq!(user_ids(&ret[0]), [4]);
+        assert!(matches!(ret[0].event.as_ref(), AppEvent::TokenRevoked(_)));
+        Ok(())
     }
 }
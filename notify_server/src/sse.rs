@@ -4,7 +4,7 @@ use axum::{
     response::{sse::Event, Sse},
     Extension,
 };
-use chat_core::User;
+use chat_core::TokenClaims;
 use futures::Stream;
 use std::{convert::Infallible, time::Duration};
 use tokio::sync::broadcast;
@@ -14,10 +14,10 @@ use tracing::{debug, info};
 const CHANNEL_CAPACITY: usize = 256;
 
 pub(crate) async fn sse_handler(
-    Extension(user): Extension<User>,
+    Extension(claims): Extension<TokenClaims>,
     State(state): State<AppState>,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let user_id = user.id as u64;
+    let user_id = claims.user.id as u64;
     let users = &state.users;
 
     let rx = if let Some(tx) = users.get(&user_id) {
@@ -29,17 +29,33 @@ pub(crate) async fn sse_handler(
     };
     info!("User {} subscribed", user_id);
 
-    let stream = BroadcastStream::new(rx).filter_map(|v| v.ok()).map(|v| {
-        let name = match v.as_ref() {
-            AppEvent::NewChat(_) => "NewChat",
-            AppEvent::AddToChat(_) => "AddToChat",
-            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
-            AppEvent::NewMessage(_) => "NewMessage",
-        };
-        let v = serde_json::to_string(&v).expect("Failed to serialize event");
-        debug!("Sending event {}: {:?}", name, v);
-        Ok(Event::default().data(v).event(name))
-    });
+    let revocations = state.revocations.clone();
+    let stream = BroadcastStream::new(rx)
+        .filter_map(|v| v.ok())
+        // end the stream once the token it was opened with gets revoked
+        .take_while(move |v| {
+            !(matches!(v.as_ref(), AppEvent::TokenRevoked(_)) && revocations.is_revoked(&claims))
+        })
+        .filter(|v| !matches!(v.as_ref(), AppEvent::TokenRevoked(_)))
+        .map(|v| {
+            let name = match v.as_ref() {
+                AppEvent::NewChat(_) => "NewChat",
+                AppEvent::AddToChat(_) => "AddToChat",
+                AppEvent::UpdateChat(_) => "UpdateChat",
+                AppEvent::RemoveFromChat(_) => "RemoveFromChat",
+                AppEvent::NewMessage(_) => "NewMessage",
+                AppEvent::NewThreadReply(_) => "NewThreadReply",
+                AppEvent::MessageUpdated(_) => "MessageUpdated",
+                AppEvent::MessageDeleted(_) => "MessageDeleted",
+                AppEvent::ReactionChanged(_) => "ReactionChanged",
+                AppEvent::ChatRead(_) => "ChatRead",
+                AppEvent::Mentioned(_) => "Mentioned",
+                AppEvent::TokenRevoked(_) => "TokenRevoked",
+            };
+            let v = serde_json::to_string(&v).expect("Failed to serialize event");
+            debug!("Sending event {}: {:?}", name, v);
+            Ok(Event::default().data(v).event(name))
+        });
 
     Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()